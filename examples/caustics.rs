@@ -0,0 +1,126 @@
+extern crate ard;
+
+use std::env;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ard::camera::*;
+use ard::color::*;
+use ard::filter::*;
+use ard::light::*;
+use ard::material::*;
+use ard::math::*;
+use ard::photon::*;
+use ard::sampler::*;
+use ard::shapes::*;
+use ard::tile::*;
+use ard::trace::*;
+
+fn main() {
+    // Pass the number of final gather rays, e.g. 32, to trade render time for smoother indirect light.
+    let final_gather_rays = env::args()
+        .nth(1)
+        .map(|arg| arg.parse().expect("Usage: caustics [final gather rays]"))
+        .unwrap_or(0);
+
+    let lamp = SphereLight {
+        center: Vector3::new(-4.5, 2.5, -1.0),
+        radius: 0.3,
+        radiance: Color::white() * 150.0,
+    };
+
+    let config = RendererConfig {
+        image_width: 640,
+        image_height: 480,
+        pixel_size: 0.01,
+        pixel_sampler: UnitSquareSampler::jittered_sampler(4),
+        adaptive_sampling: None,
+        filter: Arc::new(BoxFilter::default()),
+        passes: 1,
+        budget: None,
+        max_trace_depth: 16,
+        ambient_color: Color::default(),
+        atmosphere: None,
+        lights: vec![Arc::new(lamp)],
+        integrator: Arc::new(PhotonMapper::new(&PhotonMapperConfig {
+            global_photons: 200_000,
+            caustic_photons: 400_000,
+            gather_count: 200,
+            max_gather_distance: 0.3,
+            final_gather_rays,
+        })),
+        spectral: false,
+        seed: 0,
+        statistics: false,
+        checkpoint: None,
+        progress: None,
+        crop: None,
+        tiles: TileConfig::default(),
+        num_render_threads: None,
+    };
+
+    let mut renderer = Renderer::new(&config);
+
+    let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+        &Vector3::new(0.0, 3.0, 6.0),
+        &Vector3::new(0.0, 0.5, 0.0),
+        &Vector3::new(0.0, 1.0, 0.0),
+        4.0,
+    ));
+
+    let objects: Arc<Vec<Arc<dyn Hitable>>> = Arc::new(vec![
+        Arc::new(Sphere {
+            center: Vector3::new(-0.8, 0.8, 0.0),
+            radius: 0.8,
+            material: Arc::new(Dielectric::new(1.5, &Color::white())),
+        }),
+        Arc::new(Sphere {
+            center: Vector3::new(1.2, 0.8, -0.5),
+            radius: 0.8,
+            material: Arc::new(Metal::new(
+                &Color {
+                    r: 0.95,
+                    g: 0.8,
+                    b: 0.5,
+                    a: 1.0,
+                },
+                0.0,
+            )),
+        }),
+        Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &(Color::white() * 0.7),
+            )),
+        }),
+        Arc::new(Plane {
+            point: Vector3::new(0.0, 0.0, -3.0),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &Color {
+                    r: 0.6,
+                    g: 0.3,
+                    b: 0.3,
+                    a: 1.0,
+                },
+            )),
+        }),
+    ]);
+
+    let start_time = Instant::now();
+
+    renderer
+        .render(&camera, &objects)
+        .expect("Cannot write checkpoint");
+
+    let elapsed = start_time.elapsed().as_secs();
+
+    println!("Image rendered in {0} seconds", elapsed);
+
+    renderer
+        .write_to_file("caustics.bmp")
+        .expect("Cannot write bitmap");
+}
@@ -0,0 +1,112 @@
+extern crate ard;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ard::camera::*;
+use ard::color::*;
+use ard::filter::*;
+use ard::integrator::*;
+use ard::sky::*;
+use ard::material::*;
+use ard::math::*;
+use ard::sampler::*;
+use ard::shapes::*;
+use ard::tile::*;
+use ard::trace::*;
+
+fn main() {
+    // Late afternoon sun in a clear sky, scaled to keep the sky around unit radiance.
+    let sky = SkyLight::new(0.2 * PI, 0.3 * PI, 2.5, 0.08);
+    let sun = sky.sun();
+
+    let config = RendererConfig {
+        image_width: 640,
+        image_height: 480,
+        pixel_size: 0.01,
+        pixel_sampler: UnitSquareSampler::jittered_sampler(8),
+        adaptive_sampling: None,
+        filter: Arc::new(BoxFilter::default()),
+        passes: 1,
+        budget: None,
+        max_trace_depth: 64,
+        ambient_color: Color::default(),
+        atmosphere: None,
+        lights: vec![Arc::new(sky), Arc::new(sun)],
+        integrator: Arc::new(PathTracer::new()),
+        spectral: false,
+        seed: 0,
+        statistics: false,
+        checkpoint: None,
+        progress: None,
+        crop: None,
+        tiles: TileConfig::default(),
+        num_render_threads: None,
+    };
+
+    let mut renderer = Renderer::new(&config);
+
+    let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+        &Vector3::new(0.0, 2.0, 4.5),
+        &Vector3::new(0.0, 1.0, 0.0),
+        &Vector3::new(0.0, 1.0, 0.0),
+        4.0,
+    ));
+
+    let objects: Arc<Vec<Arc<dyn Hitable>>> = Arc::new(vec![
+        Arc::new(Sphere {
+            center: Vector3::new(-1.2, 1.0, 0.0),
+            radius: 1.0,
+            material: Arc::new(Metal::new(
+                &Color {
+                    r: 0.9,
+                    g: 0.9,
+                    b: 0.9,
+                    a: 1.0,
+                },
+                0.05,
+            )),
+        }),
+        Arc::new(Sphere {
+            center: Vector3::new(1.2, 1.0, 0.0),
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &Color {
+                    r: 0.8,
+                    g: 0.3,
+                    b: 0.2,
+                    a: 1.0,
+                },
+            )),
+        }),
+        Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &Color {
+                    r: 0.5,
+                    g: 0.5,
+                    b: 0.5,
+                    a: 1.0,
+                },
+            )),
+        }),
+    ]);
+
+    let start_time = Instant::now();
+
+    renderer
+        .render(&camera, &objects)
+        .expect("Cannot write checkpoint");
+
+    let elapsed = start_time.elapsed().as_secs();
+
+    println!("Image rendered in {0} seconds", elapsed);
+
+    renderer
+        .write_to_file("daylight.bmp")
+        .expect("Cannot write bitmap");
+}
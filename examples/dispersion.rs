@@ -0,0 +1,118 @@
+extern crate ard;
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ard::bdpt::*;
+use ard::camera::*;
+use ard::color::*;
+use ard::filter::*;
+use ard::light::*;
+use ard::material::*;
+use ard::math::*;
+use ard::sampler::*;
+use ard::shapes::*;
+use ard::tile::*;
+use ard::trace::*;
+use ard::RenderBuffer;
+
+fn main() {
+    // A small lamp low beside a ball of flint glass focuses a caustic onto the floor, which the strong
+    // dispersion of the glass spreads into a rainbow when rendering spectrally.
+    let lamp = SphereLight {
+        center: Vector3::new(-4.5, 2.0, -1.0),
+        radius: 0.2,
+        radiance: Color::white() * 400.0,
+    };
+
+    let config = RendererConfig {
+        image_width: 640,
+        image_height: 480,
+        pixel_size: 0.01,
+        pixel_sampler: UnitSquareSampler::jittered_sampler(4),
+        adaptive_sampling: None,
+        filter: Arc::new(BoxFilter::default()),
+        passes: 4,
+        budget: None,
+        max_trace_depth: 16,
+        ambient_color: Color::default(),
+        atmosphere: None,
+        lights: vec![Arc::new(lamp)],
+        integrator: Arc::new(BidirectionalPathTracer::new()),
+        spectral: true,
+        seed: 0,
+        statistics: false,
+        checkpoint: Some(CheckpointConfig {
+            path: "dispersion.checkpoint".into(),
+            interval: Duration::from_secs(60),
+        }),
+        progress: None,
+        crop: None,
+        tiles: TileConfig::default(),
+        num_render_threads: None,
+    };
+
+    let mut renderer = Renderer::new(&config);
+
+    let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+        &Vector3::new(0.0, 3.0, 5.0),
+        &Vector3::new(0.0, 0.5, 0.0),
+        &Vector3::new(0.0, 1.0, 0.0),
+        4.0,
+    ));
+
+    let objects: Arc<Vec<Arc<dyn Hitable>>> = Arc::new(vec![
+        Arc::new(Sphere {
+            center: Vector3::new(-0.8, 0.8, 0.0),
+            radius: 0.8,
+            material: Arc::new(Dielectric::with_refractive_index(
+                &RefractiveIndex::sf11(),
+                &Color::white(),
+            )),
+        }),
+        Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &(Color::white() * 0.7),
+            )),
+        }),
+        Arc::new(Plane {
+            point: Vector3::new(0.0, 0.0, -3.0),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &(Color::white() * 0.5),
+            )),
+        }),
+    ]);
+
+    let start_time = Instant::now();
+
+    // Every pass adds 16 samples per pixel; the image is written after each one so it can be watched.
+    let on_pass = |pass: u32, image: &RenderBuffer| {
+        println!(
+            "Pass {0} rendered after {1} seconds",
+            pass + 1,
+            start_time.elapsed().as_secs()
+        );
+        image
+            .write_to_file("dispersion.bmp")
+            .expect("Cannot write bitmap");
+        true
+    };
+    // An interrupted render continues from its last checkpoint.
+    let checkpoint = Path::new("dispersion.checkpoint");
+    if checkpoint.exists() {
+        renderer.resume_progressive(&camera, &objects, checkpoint, on_pass)
+    } else {
+        renderer.render_progressive(&camera, &objects, on_pass)
+    }
+    .expect("Cannot read or write checkpoint");
+
+    let elapsed = start_time.elapsed().as_secs();
+
+    println!("Image rendered in {0} seconds", elapsed);
+}
@@ -0,0 +1,118 @@
+extern crate ard;
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use ard::bdpt::*;
+use ard::camera::*;
+use ard::color::*;
+use ard::filter::*;
+use ard::light::*;
+use ard::material::*;
+use ard::math::*;
+use ard::sampler::*;
+use ard::shapes::*;
+use ard::tile::*;
+use ard::trace::*;
+
+fn main() {
+    // A lamp in the room behind a wall only reaches the camera through the narrow gap under the wall,
+    // which is hard to find for paths traced from the camera alone.
+    let lamp = SphereLight {
+        center: Vector3::new(0.0, 0.5, -1.5),
+        radius: 0.3,
+        radiance: Color {
+            r: 60.0,
+            g: 50.0,
+            b: 35.0,
+            a: 1.0,
+        },
+    };
+
+    let config = RendererConfig {
+        image_width: 640,
+        image_height: 480,
+        pixel_size: 0.01,
+        pixel_sampler: UnitSquareSampler::jittered_sampler(4),
+        adaptive_sampling: Some(AdaptiveSamplingConfig {
+            max_samples_per_pixel: 64,
+            ..AdaptiveSamplingConfig::default()
+        }),
+        filter: Arc::new(MitchellFilter::default()),
+        passes: 1,
+        budget: None,
+        max_trace_depth: 64,
+        ambient_color: Color::default(),
+        atmosphere: None,
+        lights: vec![Arc::new(lamp)],
+        integrator: Arc::new(BidirectionalPathTracer::new()),
+        spectral: false,
+        seed: 0,
+        statistics: false,
+        checkpoint: None,
+        progress: None,
+        crop: None,
+        tiles: TileConfig::default(),
+        num_render_threads: None,
+    };
+
+    let mut renderer = Renderer::new(&config);
+
+    let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+        &Vector3::new(0.0, 1.6, 4.0),
+        &Vector3::new(0.0, 0.4, 0.0),
+        &Vector3::new(0.0, 1.0, 0.0),
+        4.0,
+    ));
+
+    let diffuse = |r: f64, g: f64, b: f64| -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(
+            &HemiSphereSampler::jittered_sampler(8, 1.0),
+            &Color { r, g, b, a: 1.0 },
+        ))
+    };
+
+    let objects: Arc<Vec<Arc<dyn Hitable>>> = Arc::new(vec![
+        Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: diffuse(0.6, 0.55, 0.5),
+        }),
+        Arc::new(Plane {
+            point: Vector3::new(0.0, 3.0, 0.0),
+            normal: Vector3::new(0.0, -1.0, 0.0),
+            material: diffuse(0.7, 0.7, 0.7),
+        }),
+        Arc::new(Plane {
+            point: Vector3::new(0.0, 0.0, -3.0),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            material: diffuse(0.7, 0.7, 0.7),
+        }),
+        // The wall with the door ends just above the floor.
+        Arc::new(Cube::new(
+            Vector3::new(0.0, 1.625, 0.0),
+            Vector3::new(40.0, 2.75, 0.2),
+            Vector3::zero(),
+            diffuse(0.5, 0.6, 0.7),
+        )),
+    ]);
+
+    let start_time = Instant::now();
+
+    renderer
+        .render(&camera, &objects)
+        .expect("Cannot write checkpoint");
+
+    let elapsed = start_time.elapsed().as_secs();
+
+    println!("Image rendered in {0} seconds", elapsed);
+
+    renderer
+        .write_to_file("light_under_door.bmp")
+        .expect("Cannot write bitmap");
+    if let Some(sample_counts) = renderer.sample_counts() {
+        sample_counts
+            .write_to_file("light_under_door_samples.bmp")
+            .expect("Cannot write bitmap");
+    }
+}
@@ -5,10 +5,13 @@ use std::time::Instant;
 
 use ard::camera::*;
 use ard::color::*;
+use ard::filter::*;
+use ard::integrator::*;
 use ard::material::*;
 use ard::math::*;
 use ard::sampler::*;
 use ard::shapes::*;
+use ard::tile::*;
 use ard::trace::*;
 
 fn main() {
@@ -17,13 +20,27 @@ fn main() {
         image_height: 480,
         pixel_size: 0.01,
         pixel_sampler: UnitSquareSampler::regular_sampler(8),
-        max_trace_depth: 10,
+        adaptive_sampling: None,
+        filter: Arc::new(BoxFilter::default()),
+        passes: 1,
+        budget: None,
+        max_trace_depth: 64,
         ambient_color: Color {
             r: 0.6,
             g: 0.8,
             b: 1.0,
             a: 1.0,
         },
+        atmosphere: None,
+        lights: Vec::new(),
+        integrator: Arc::new(PathTracer::new()),
+        spectral: false,
+        seed: 0,
+        statistics: false,
+        checkpoint: None,
+        progress: None,
+        crop: None,
+        tiles: TileConfig::default(),
         num_render_threads: None,
     };
 
@@ -41,7 +58,6 @@ fn main() {
             center: Vector3::new(2.0, 1.0, 0.0),
             radius: 1.0,
             material: Arc::new(Metal::new(
-                &UnitSphereSampler::standard_sampler(),
                 &Color {
                     r: 0.8,
                     g: 0.8,
@@ -55,7 +71,6 @@ fn main() {
             center: Vector3::new(-2.0, 1.0, 0.0),
             radius: 1.0,
             material: Arc::new(Metal::new(
-                &UnitSphereSampler::standard_sampler(),
                 &Color {
                     r: 0.8,
                     g: 0.6,
@@ -95,7 +110,9 @@ fn main() {
 
     let start_time = Instant::now();
 
-    renderer.render(&camera, &objects);
+    renderer
+        .render(&camera, &objects)
+        .expect("Cannot write checkpoint");
 
     let elapsed = start_time.elapsed().as_secs();
 
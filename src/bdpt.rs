@@ -0,0 +1,960 @@
+use rand::{Rng, RngCore};
+use std::io::Result;
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::film::Film;
+use crate::integrator::Integrator;
+use crate::io::{InputStream, OutputStream};
+use crate::light::Light;
+use crate::math::{Ray3, Vector2, Vector3};
+use crate::scene::{is_black, Scene};
+use crate::serialize::{write_tagged, Deserialize, Serialize};
+use crate::shapes::Intersection;
+use crate::spectrum::{Radiance, SampledSpectrum};
+use crate::TraceContext;
+
+/// Bidirectional path tracing. For every camera ray a path is also traced from a randomly chosen area light,
+/// and every prefix of the light path is connected to every prefix of the camera path. The connections are
+/// weighted by multiple importance sampling with the power heuristic, so each strategy contributes where
+/// it is good at finding light, e.g. light paths for caustics and for lights hidden behind small openings.
+/// Light path vertices connected directly to the camera are splatted to the pixel they project to.
+///
+/// Lights at infinity have no surface to start light paths from and are only reached from the camera side,
+/// by light sampling and by rays leaving the scene. Participating media attenuate every segment of the
+/// subpaths and their connections but do not scatter light, so scattering media look darker than with
+/// path tracing.
+#[derive(Clone, Debug)]
+pub struct BidirectionalPathTracer {
+    max_depth: u32,
+}
+
+impl Default for BidirectionalPathTracer {
+    fn default() -> BidirectionalPathTracer {
+        BidirectionalPathTracer::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+/// A vertex of a camera or light subpath. The densities are with respect to surface area: `pdf_fwd` for
+/// sampling the vertex in the direction the subpath was traced, `pdf_rev` for sampling it from the other end.
+#[derive(Clone)]
+struct Vertex<S> {
+    kind: VertexKind,
+    point: Vector3,
+    normal: Vector3,
+    intersection: Option<Intersection>,
+    light: Option<Arc<dyn Light>>,
+    /// Radiance emitted towards the previous vertex by a light hit by a camera path.
+    emitted: S,
+    beta: S,
+    delta: bool,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl<S: Radiance> Vertex<S> {
+    fn camera(point: Vector3, beta: S) -> Vertex<S> {
+        Vertex {
+            kind: VertexKind::Camera,
+            point,
+            normal: Vector3::zero(),
+            intersection: None,
+            light: None,
+            emitted: S::default(),
+            beta,
+            delta: false,
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(
+        point: Vector3,
+        normal: Vector3,
+        light: &Arc<dyn Light>,
+        beta: S,
+        pdf_fwd: f64,
+    ) -> Vertex<S> {
+        Vertex {
+            kind: VertexKind::Light,
+            point,
+            normal,
+            intersection: None,
+            light: Some(Arc::clone(light)),
+            emitted: S::default(),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(intersection: Intersection, beta: S) -> Vertex<S> {
+        Vertex {
+            kind: VertexKind::Surface,
+            point: intersection.point,
+            normal: intersection.normal,
+            intersection: Some(intersection),
+            light: None,
+            emitted: S::default(),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn is_connectible(&self) -> bool {
+        !self.delta
+    }
+
+    /// Evaluates the scattering function, without the cosine term, for light leaving in `direction`.
+    fn f(&self, direction: &Vector3, trace_context: &TraceContext) -> S {
+        let f = match self.kind {
+            VertexKind::Camera => Color::white(),
+            VertexKind::Light => {
+                if self.normal.dot(direction) > 0.0 {
+                    Color::white()
+                } else {
+                    Color::black()
+                }
+            }
+            VertexKind::Surface => {
+                let intersection = self.intersection.as_ref().unwrap();
+                let cos_theta = self.normal.dot(direction).abs();
+                if cos_theta <= 1e-9 {
+                    return S::default();
+                }
+                intersection
+                    .material
+                    .evaluate(&intersection.ray, intersection, direction)
+                    * (1.0 / cos_theta)
+            }
+        };
+        S::uplift(&f, trace_context)
+    }
+
+    /// Converts a density with respect to solid angle at this vertex to one with respect to area at `next`.
+    fn convert_density(&self, pdf: f64, next: &Vertex<S>) -> f64 {
+        let d = next.point - self.point;
+        let distance_squared = d.length_squared();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+        let cos_theta = if next.kind == VertexKind::Camera {
+            1.0
+        } else {
+            next.normal.dot(&d).abs() / distance_squared.sqrt()
+        };
+        pdf * cos_theta / distance_squared
+    }
+
+    /// Returns the density with respect to area with which `next` is sampled from this vertex,
+    /// arriving from `prev`.
+    fn pdf(&self, film: &Film, prev: Option<&Vertex<S>>, next: &Vertex<S>) -> f64 {
+        let direction = (next.point - self.point).normalized();
+        let pdf = match self.kind {
+            VertexKind::Camera => film
+                .camera()
+                .project(&next.point)
+                .map_or(0.0, |projection| projection.film_density / film.area()),
+            VertexKind::Light => {
+                let light = self.light.as_ref().unwrap();
+                light.pdf_emission(&self.point, &self.normal, &direction).1
+            }
+            VertexKind::Surface => {
+                let intersection = self.intersection.as_ref().unwrap();
+                let incoming = prev.map_or(intersection.ray, |prev| {
+                    Ray3::new(prev.point, (self.point - prev.point).normalized())
+                });
+                intersection
+                    .material
+                    .pdf(&incoming, intersection, &direction)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+
+    /// Returns the density with respect to area with which a light path starts at this light vertex.
+    fn pdf_light_origin(&self, num_lights: usize) -> f64 {
+        let light = self.light.as_ref().unwrap();
+        light
+            .pdf_emission(&self.point, &self.normal, &Vector3::zero())
+            .0
+            / num_lights as f64
+    }
+
+    /// The vertex moved slightly off its surface towards `target`, as origin of a shadow ray.
+    fn offset_towards(&self, target: &Vector3) -> Vector3 {
+        if self.kind == VertexKind::Camera {
+            return self.point;
+        }
+        let side = if self.normal.dot(&(*target - self.point)) >= 0.0 {
+            1.0
+        } else {
+            -1.0
+        };
+        self.point + self.normal * (0.01 * side)
+    }
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        self.trace(scene, film, &mut trace_context.clone(), ray, rng)
+    }
+
+    fn spectral_radiance(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        trace_context: &mut TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledSpectrum> {
+        Some(self.trace(scene, film, trace_context, ray, rng))
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "bdpt", self)
+    }
+}
+
+impl Serialize for BidirectionalPathTracer {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.max_depth.write_to(out)
+    }
+}
+
+impl Deserialize for BidirectionalPathTracer {
+    fn read_from(input: &mut InputStream) -> Result<BidirectionalPathTracer> {
+        let max_depth = u32::read_from(input)?;
+        Ok(BidirectionalPathTracer::with_max_depth(max_depth))
+    }
+}
+
+impl BidirectionalPathTracer {
+    pub fn new() -> BidirectionalPathTracer {
+        BidirectionalPathTracer { max_depth: 8 }
+    }
+
+    /// Limits the number of scattering events of the combined paths.
+    pub fn with_max_depth(max_depth: u32) -> BidirectionalPathTracer {
+        BidirectionalPathTracer { max_depth }
+    }
+
+    /// Traces a camera and a light subpath carrying RGB colors or, in spectral mode, spectra at the
+    /// wavelengths of `trace_context`, and connects them.
+    fn trace<S: Radiance>(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        trace_context: &mut TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> S {
+        let max_depth = self.max_depth.min(scene.max_trace_depth) as usize;
+        let (area_lights, infinite_lights): (Vec<_>, Vec<_>) = scene
+            .lights
+            .iter()
+            .cloned()
+            .partition(|light| !light.is_infinite());
+
+        // Cameras that cannot project points, like the orthographic camera, cannot be connected to.
+        let camera_projection = film.camera().project(&ray.point_at(1.0));
+        let camera_pdf =
+            camera_projection.map_or(1.0, |projection| projection.film_density / film.area());
+
+        let white = S::uplift(&Color::white(), trace_context);
+        let mut radiance = S::default();
+        let mut camera_path = vec![Vertex::camera(ray.origin, white)];
+        self.random_walk(
+            scene,
+            *ray,
+            white,
+            camera_pdf,
+            Some((&infinite_lights, &mut radiance)),
+            max_depth + 2,
+            trace_context,
+            rng,
+            &mut camera_path,
+        );
+
+        let mut light_path = Vec::new();
+        if !area_lights.is_empty() {
+            let index =
+                ((rng.gen::<f64>() * area_lights.len() as f64) as usize).min(area_lights.len() - 1);
+            let light = &area_lights[index];
+            let pdf_choice = 1.0 / area_lights.len() as f64;
+            let u_position = Vector2::new(rng.gen(), rng.gen());
+            let u_direction = Vector2::new(rng.gen(), rng.gen());
+            if let Some(emission) = light.sample_emission(u_position, u_direction) {
+                let pdf_origin = emission.pdf_position * pdf_choice;
+                if pdf_origin > 0.0 && emission.pdf_direction > 0.0 && !is_black(&emission.radiance)
+                {
+                    let vertex = Vertex::light(
+                        emission.ray.origin,
+                        emission.normal,
+                        light,
+                        S::uplift(&emission.radiance, trace_context) * (1.0 / pdf_origin),
+                        pdf_origin,
+                    );
+                    let beta = vertex.beta
+                        * (emission.normal.dot(&emission.ray.direction).abs()
+                            / emission.pdf_direction);
+                    light_path.push(vertex);
+                    let ray = Ray3::new(
+                        emission.ray.origin + emission.normal * 0.01,
+                        emission.ray.direction,
+                    );
+                    self.random_walk(
+                        scene,
+                        ray,
+                        beta,
+                        emission.pdf_direction,
+                        None,
+                        max_depth + 1,
+                        trace_context,
+                        rng,
+                        &mut light_path,
+                    );
+                }
+            }
+        }
+
+        let camera_connectible = camera_projection.is_some();
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = s + t;
+                if depth < 2 || depth - 2 > max_depth || (s == 0 && t == 1) {
+                    continue;
+                }
+                if t == 1 && !camera_connectible {
+                    continue;
+                }
+
+                let mut sampled = None;
+                let mut raster = None;
+                let contribution = self.connect(
+                    scene,
+                    film,
+                    &light_path,
+                    &camera_path,
+                    s,
+                    t,
+                    &area_lights,
+                    &mut sampled,
+                    &mut raster,
+                    trace_context,
+                    rng,
+                );
+                if contribution.is_black() {
+                    continue;
+                }
+
+                let weight = mis_weight(
+                    film,
+                    &light_path,
+                    &camera_path,
+                    sampled.as_ref(),
+                    s,
+                    t,
+                    area_lights.len(),
+                    camera_connectible,
+                );
+                if t == 1 {
+                    if let Some((x, y)) = raster {
+                        // Both subpaths are traced, so the wavelengths are final.
+                        film.splat(x, y, &(contribution * weight).to_rgb(trace_context));
+                    }
+                } else {
+                    radiance += contribution * weight;
+                }
+            }
+        }
+
+        radiance
+    }
+
+    /// Extends `path` by tracing `ray` through the scene until `max_vertices` is reached or the path ends.
+    /// Camera paths pass the lights at infinity and add the light arriving from them to the radiance.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<S: Radiance>(
+        &self,
+        scene: &Scene,
+        mut ray: Ray3,
+        mut beta: S,
+        mut pdf_dir: f64,
+        mut camera: Option<(&[Arc<dyn Light>], &mut S)>,
+        max_vertices: usize,
+        trace_context: &mut TraceContext,
+        rng: &mut dyn RngCore,
+        path: &mut Vec<Vertex<S>>,
+    ) {
+        let mut scatter_pdf: Option<f64> = None;
+
+        while path.len() < max_vertices {
+            let have_hit = scene.intersect(&ray);
+            let t_surface = have_hit.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+            let light_hit = scene
+                .intersect_light(&ray)
+                .filter(|hit| hit.0.t < t_surface);
+            let t_hit = light_hit.as_ref().map_or(t_surface, |hit| hit.0.t);
+            let transmittance = scene.media_transmittance(&ray, t_hit, rng);
+            beta = beta * S::uplift(&transmittance, trace_context);
+
+            // Lights are opaque; only camera paths record the light they hit.
+            if let Some((hit, light)) = light_hit {
+                if camera.is_some() {
+                    let mut vertex =
+                        Vertex::light(ray.point_at(hit.t), hit.normal, light, beta, 0.0);
+                    vertex.emitted = S::uplift(&hit.radiance, trace_context);
+                    vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_dir, &vertex);
+                    path.push(vertex);
+                }
+                break;
+            }
+
+            let intersection = match have_hit {
+                Some(intersection) => intersection,
+                None => {
+                    if let Some((_, radiance)) = camera.as_mut() {
+                        **radiance +=
+                            beta * S::uplift(&scene.background(&ray, scatter_pdf), trace_context);
+                    }
+                    break;
+                }
+            };
+
+            let mut vertex = Vertex::surface(intersection, beta);
+            vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_dir, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let n = path.len();
+            let intersection = path[n - 1].intersection.clone().unwrap();
+            let material = &intersection.material;
+            let mut scattered = Ray3::default();
+            let mut attenuation = Color::black();
+            if !material.scatter(
+                trace_context,
+                &ray,
+                &intersection,
+                rng,
+                &mut attenuation,
+                &mut scattered,
+            ) {
+                break;
+            }
+            trace_context.dimension += 1;
+
+            let pdf = material.pdf(&ray, &intersection, &scattered.direction);
+            if pdf > 0.0 {
+                if let Some((infinite_lights, radiance)) = camera.as_mut() {
+                    if !infinite_lights.is_empty() {
+                        let light_origin = intersection.point + intersection.normal * 0.01;
+                        let trace_context = &*trace_context;
+                        let direct = scene.direct_lighting_from(
+                            infinite_lights,
+                            &light_origin,
+                            trace_context,
+                            rng,
+                            true,
+                            &|direction| {
+                                (
+                                    S::uplift(
+                                        &material.evaluate(&ray, &intersection, direction),
+                                        trace_context,
+                                    ),
+                                    material.pdf(&ray, &intersection, direction),
+                                )
+                            },
+                        );
+                        **radiance += beta * direct;
+                    }
+                }
+
+                let reverse = material.pdf(
+                    &Ray3::new(scattered.origin, -scattered.direction),
+                    &intersection,
+                    &-ray.direction,
+                );
+                path[n - 2].pdf_rev = path[n - 1].convert_density(reverse, &path[n - 2]);
+                pdf_dir = pdf;
+                scatter_pdf = Some(pdf);
+            } else {
+                path[n - 1].delta = true;
+                path[n - 2].pdf_rev = 0.0;
+                pdf_dir = 0.0;
+                scatter_pdf = None;
+            }
+
+            beta = beta
+                * S::uplift_with(&attenuation, trace_context, &|wavelength| {
+                    material.spectral_attenuation(&ray, &intersection, &attenuation, wavelength)
+                });
+            if beta.is_black() {
+                break;
+            }
+            ray = scattered;
+        }
+    }
+
+    /// Returns the unweighted contribution of the path made of the first `s` light and the first `t` camera
+    /// vertices. Strategies that sample a new vertex at the end of a subpath store it in `sampled`, and
+    /// connections to the camera store the pixel they contribute to in `raster`.
+    #[allow(clippy::too_many_arguments)]
+    fn connect<S: Radiance>(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        light_path: &[Vertex<S>],
+        camera_path: &[Vertex<S>],
+        s: usize,
+        t: usize,
+        area_lights: &[Arc<dyn Light>],
+        sampled: &mut Option<Vertex<S>>,
+        raster: &mut Option<(u32, u32)>,
+        trace_context: &TraceContext,
+        rng: &mut dyn RngCore,
+    ) -> S {
+        if s == 0 {
+            let pt = &camera_path[t - 1];
+            if pt.kind != VertexKind::Light {
+                return S::default();
+            }
+            return pt.beta * pt.emitted;
+        }
+
+        if t == 1 {
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible() {
+                return S::default();
+            }
+            let (x, y, projection) = match film.project(&qs.point) {
+                Some(projection) => projection,
+                None => return S::default(),
+            };
+
+            let origin = qs.offset_towards(&projection.origin);
+            let d = projection.origin - origin;
+            let distance = d.length();
+            let direction = d / distance;
+            let f = qs.f(&direction, trace_context);
+            if f.is_black() {
+                return S::default();
+            }
+
+            let visibility = scene.transmittance(&Ray3::new(origin, direction), distance, rng);
+            let importance = projection.film_density / film.area();
+            let cos_theta = qs.normal.dot(&direction).abs();
+            *sampled = Some(Vertex::camera(projection.origin, S::default()));
+            *raster = Some((x, y));
+            return qs.beta
+                * f
+                * S::uplift(&visibility, trace_context)
+                * (importance * cos_theta / (distance * distance));
+        }
+
+        if s == 1 {
+            let pt = &camera_path[t - 1];
+            if pt.kind != VertexKind::Surface || !pt.is_connectible() {
+                return S::default();
+            }
+
+            let index =
+                ((rng.gen::<f64>() * area_lights.len() as f64) as usize).min(area_lights.len() - 1);
+            let light = &area_lights[index];
+            let pdf_choice = 1.0 / area_lights.len() as f64;
+            let u = Vector2::new(rng.gen(), rng.gen());
+            let origin = pt.point + pt.normal * 0.01;
+            let sample = match light.sample(&origin, u) {
+                Some(sample) if sample.pdf > 0.0 => sample,
+                _ => return S::default(),
+            };
+
+            let intersection = pt.intersection.as_ref().unwrap();
+            let f =
+                intersection
+                    .material
+                    .evaluate(&intersection.ray, intersection, &sample.direction);
+            if is_black(&f) || is_black(&sample.radiance) {
+                return S::default();
+            }
+
+            let visibility =
+                scene.transmittance(&Ray3::new(origin, sample.direction), sample.distance, rng);
+            let point = origin + sample.direction * sample.distance;
+            let emitted = S::uplift(&sample.radiance, trace_context);
+            let mut vertex = Vertex::light(
+                point,
+                sample.normal,
+                light,
+                emitted * (1.0 / (sample.pdf * pdf_choice)),
+                0.0,
+            );
+            vertex.pdf_fwd = vertex.pdf_light_origin(area_lights.len());
+            *sampled = Some(vertex);
+            return pt.beta
+                * S::uplift(&f, trace_context)
+                * emitted
+                * S::uplift(&visibility, trace_context)
+                * (1.0 / (sample.pdf * pdf_choice));
+        }
+
+        let qs = &light_path[s - 1];
+        let pt = &camera_path[t - 1];
+        if pt.kind != VertexKind::Surface || !qs.is_connectible() || !pt.is_connectible() {
+            return S::default();
+        }
+
+        let origin = qs.offset_towards(&pt.point);
+        let target = pt.offset_towards(&qs.point);
+        let d = target - origin;
+        let distance = d.length();
+        let direction = d / distance;
+        let f =
+            qs.beta * qs.f(&direction, trace_context) * pt.f(&-direction, trace_context) * pt.beta;
+        if f.is_black() {
+            return S::default();
+        }
+
+        let visibility = scene.transmittance(&Ray3::new(origin, direction), distance, rng);
+        let g = qs.normal.dot(&direction).abs() * pt.normal.dot(&direction).abs()
+            / (distance * distance);
+        f * S::uplift(&visibility, trace_context) * g
+    }
+}
+
+/// Returns the power heuristic weight of the strategy with `s` light and `t` camera vertices.
+/// The densities of the vertices next to the connection depend on the strategy and are recomputed, the
+/// others were recorded while tracing the subpaths.
+#[allow(clippy::too_many_arguments)]
+fn mis_weight<S: Radiance>(
+    film: &Film,
+    light_path: &[Vertex<S>],
+    camera_path: &[Vertex<S>],
+    sampled: Option<&Vertex<S>>,
+    s: usize,
+    t: usize,
+    num_lights: usize,
+    camera_connectible: bool,
+) -> f64 {
+    if s + t == 2 {
+        return 1.0;
+    }
+
+    let mut lights: Vec<(f64, f64, bool)> = light_path[..s]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+    let mut cameras: Vec<(f64, f64, bool)> = camera_path[..t]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+
+    let qs = if s == 0 {
+        None
+    } else if s == 1 && t > 1 {
+        sampled
+    } else {
+        Some(&light_path[s - 1])
+    };
+    let pt = if t == 1 {
+        sampled.unwrap()
+    } else {
+        &camera_path[t - 1]
+    };
+    let qs_minus = if s > 1 {
+        Some(&light_path[s - 2])
+    } else {
+        None
+    };
+    let pt_minus = if t > 1 {
+        Some(&camera_path[t - 2])
+    } else {
+        None
+    };
+
+    if s == 1 && t > 1 {
+        lights[0] = (qs.unwrap().pdf_fwd, 0.0, false);
+    }
+
+    // The vertices of the connection are never specular, otherwise the strategy would not contribute.
+    if s > 0 {
+        lights[s - 1].2 = false;
+    }
+    cameras[t - 1].2 = false;
+
+    cameras[t - 1].1 = match qs {
+        Some(qs) => qs.pdf(film, qs_minus, pt),
+        None => pt.pdf_light_origin(num_lights),
+    };
+    if let Some(pt_minus) = pt_minus {
+        cameras[t - 2].1 = pt.pdf(film, qs, pt_minus);
+    }
+    if let Some(qs) = qs {
+        lights[s - 1].1 = pt.pdf(film, pt_minus, qs);
+        if let Some(qs_minus) = qs_minus {
+            lights[s - 2].1 = qs.pdf(film, Some(pt), qs_minus);
+        }
+    }
+
+    // Light sampling picks the light vertex with a density that depends on the vertex it is connected from,
+    // while light paths start with the emission density. The ratios below assume the emission density
+    // everywhere and the strategy with a single light vertex is corrected by `light_sampling`.
+    let light_vertex = match qs {
+        Some(_) if s > 1 => &light_path[0],
+        Some(qs) => qs,
+        None => pt,
+    };
+    let light_neighbour = if s > 1 {
+        Some(&light_path[1])
+    } else if s == 1 {
+        Some(pt)
+    } else {
+        pt_minus
+    };
+    let light_sampling = light_neighbour
+        .filter(|v| v.kind == VertexKind::Surface)
+        .map_or(1.0, |v| light_sampling_pdf(light_vertex, v, num_lights))
+        / light_vertex.pdf_light_origin(num_lights);
+
+    let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+    let correction = |k: usize| if k == 1 { light_sampling } else { 1.0 };
+    let mut sum = 0.0;
+
+    let mut ratio = 1.0;
+    for i in (1..t).rev() {
+        ratio *= remap(cameras[i].1) / remap(cameras[i].0);
+        if !cameras[i].2 && !cameras[i - 1].2 && (i > 1 || camera_connectible) {
+            sum += (ratio * correction(s + t - i)).powi(2);
+        }
+    }
+
+    ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(lights[i].1) / remap(lights[i].0);
+        let previous_delta = i > 0 && lights[i - 1].2;
+        if !lights[i].2 && !previous_delta {
+            sum += (ratio * correction(i)).powi(2);
+        }
+    }
+
+    if s == 1 && t > 1 {
+        sum /= light_sampling * light_sampling;
+    }
+
+    1.0 / (1.0 + sum)
+}
+
+/// Returns the density with respect to area with which light sampling from `from` picks the light vertex.
+fn light_sampling_pdf<S: Radiance>(
+    light_vertex: &Vertex<S>,
+    from: &Vertex<S>,
+    num_lights: usize,
+) -> f64 {
+    let light = light_vertex.light.as_ref().unwrap();
+    let origin = from.point + from.normal * 0.01;
+    let d = light_vertex.point - origin;
+    let distance_squared = d.length_squared();
+    let direction = d.normalized();
+    light.pdf(&origin, &direction) * light_vertex.normal.dot(&direction).abs()
+        / (distance_squared * num_lights as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Camera, OrthographicCamera, PinholeCamera};
+    use crate::filter::BoxFilter;
+    use crate::integrator::PathTracer;
+    use crate::light::SphereLight;
+    use crate::material::Lambertian;
+    use crate::medium::{HomogeneousMedium, Medium};
+    use crate::sampler::{HemiSphereSampler, UnitSquareSampler};
+    use crate::shapes::{Hitable, Plane, Sphere};
+    use crate::tile::TileConfig;
+    use crate::trace::{Renderer, RendererConfig};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn plane_under_sphere_light(atmosphere: Option<Arc<dyn Medium>>) -> Scene {
+        let objects: Vec<Arc<dyn Hitable>> = vec![Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &(Color::white() * 0.5),
+            )),
+        })];
+        Scene {
+            objects: Arc::new(objects),
+            lights: vec![Arc::new(SphereLight {
+                center: Vector3::new(0.0, 3.0, 0.0),
+                radius: 0.5,
+                radiance: Color::white() * 10.0,
+            })],
+            atmosphere,
+            ambient_color: Color::black(),
+            max_trace_depth: 8,
+        }
+    }
+
+    /// The mean radiance of a ray hitting the plane at the origin.
+    fn mean_radiance(integrator: &dyn Integrator, scene: &Scene) -> f64 {
+        // Without camera connections every strategy is accounted for in the returned radiance.
+        let camera: Arc<dyn Camera> = Arc::new(OrthographicCamera::new(
+            &Vector3::new(2.0, 2.0, 0.0),
+            &Vector3::zero(),
+            &Vector3::new(0.0, 1.0, 0.0),
+        ));
+        let film = Film::new(4, 4, 0.1, &camera);
+        let ray = Ray3::new(
+            Vector3::new(2.0, 2.0, 0.0),
+            Vector3::new(-1.0, -1.0, 0.0).normalized(),
+        );
+        let context = TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        };
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 2000;
+        (0..n)
+            .map(|_| {
+                integrator
+                    .radiance(scene, &film, &context, &ray, &mut rng)
+                    .g
+            })
+            .sum::<f64>()
+            / n as f64
+    }
+
+    #[test]
+    fn diffuse_plane_under_sphere_light() {
+        let mean = mean_radiance(
+            &BidirectionalPathTracer::new(),
+            &plane_under_sphere_light(None),
+        );
+
+        // A sphere light of radiance L subtending sin(alpha) = r / d lights a diffuse surface of albedo rho
+        // facing it to rho * L * (r / d)^2.
+        let expected = 0.5 * 10.0 * 0.25 / 9.0;
+        assert!(
+            (mean - expected).abs() < 0.02 * expected,
+            "{} vs {}",
+            mean,
+            expected
+        );
+    }
+
+    #[test]
+    fn absorbing_media_attenuate_like_path_tracing() {
+        let absorber = HomogeneousMedium::new(&(Color::white() * 0.2), &Color::black(), 0.0);
+        let scene = plane_under_sphere_light(Some(Arc::new(absorber)));
+        let mean = mean_radiance(&BidirectionalPathTracer::new(), &scene);
+        let expected = mean_radiance(&PathTracer::new(), &scene);
+        assert!(
+            (mean - expected).abs() < 0.1 * expected,
+            "{} vs {}",
+            mean,
+            expected
+        );
+    }
+
+    #[test]
+    fn pinhole_renders_match_path_tracing() {
+        // A pinhole camera can be hit by light paths, so part of the light reaches the film through splats.
+        let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+            &Vector3::new(0.0, 1.5, 4.0),
+            &Vector3::zero(),
+            &Vector3::new(0.0, 1.0, 0.0),
+            2.0,
+        ));
+        let material = Arc::new(Lambertian::new(
+            &HemiSphereSampler::jittered_sampler(4, 1.0),
+            &(Color::white() * 0.5),
+        ));
+        let objects: Arc<Vec<Arc<dyn Hitable>>> = Arc::new(vec![
+            Arc::new(Sphere {
+                center: Vector3::new(0.0, 0.5, 0.0),
+                radius: 0.5,
+                material: material.clone(),
+            }),
+            Arc::new(Plane {
+                point: Vector3::zero(),
+                normal: Vector3::new(0.0, 1.0, 0.0),
+                material,
+            }),
+        ]);
+        let mean = |integrator: Arc<dyn Integrator>| {
+            let mut renderer = Renderer::new(&RendererConfig {
+                image_width: 16,
+                image_height: 12,
+                pixel_size: 0.1,
+                pixel_sampler: UnitSquareSampler::jittered_sampler(4),
+                adaptive_sampling: None,
+                filter: Arc::new(BoxFilter::default()),
+                passes: 16,
+                budget: None,
+                max_trace_depth: 4,
+                ambient_color: Color::black(),
+                atmosphere: None,
+                lights: vec![Arc::new(SphereLight {
+                    center: Vector3::new(1.0, 2.0, 0.0),
+                    radius: 0.3,
+                    radiance: Color::white() * 20.0,
+                })],
+                integrator,
+                spectral: false,
+                seed: 7,
+                statistics: false,
+                checkpoint: None,
+                progress: None,
+                crop: None,
+                tiles: TileConfig::default(),
+                num_render_threads: Some(4),
+            });
+            let mut mean = 0.0;
+            renderer
+                .render_progressive(&camera, &objects, |_, image| {
+                    mean = 0.0;
+                    for y in 0..image.height() {
+                        for x in 0..image.width() {
+                            mean += image.get_pixel(x, y).g / (16.0 * 12.0);
+                        }
+                    }
+                    true
+                })
+                .unwrap();
+            mean
+        };
+
+        let bdpt = mean(Arc::new(BidirectionalPathTracer::new()));
+        let path_tracer = mean(Arc::new(PathTracer::new()));
+        assert!(
+            (bdpt - path_tracer).abs() < 0.02 * path_tracer,
+            "{} vs {}",
+            bdpt,
+            path_tracer
+        );
+    }
+}
@@ -1,29 +1,108 @@
 extern crate ard;
 
+use std::env;
+use std::io::{self, Write};
+use std::net::TcpListener;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 
+use ard::bdpt::*;
 use ard::camera::*;
 use ard::color::*;
+use ard::filter::*;
+use ard::integrator::*;
 use ard::material::*;
 use ard::math::*;
+use ard::mlt::*;
+use ard::photon::*;
+use ard::progress::*;
 use ard::sampler::*;
 use ard::shapes::*;
+use ard::tile::*;
+use ard::trace::distributed::*;
 use ard::trace::*;
 
+fn integrator_from_name(name: &str) -> Option<Arc<dyn Integrator>> {
+    match name {
+        "path" => Some(Arc::new(PathTracer::new())),
+        "bdpt" => Some(Arc::new(BidirectionalPathTracer::new())),
+        "photons" => Some(Arc::new(PhotonMapper::new(&PhotonMapperConfig::default()))),
+        "mlt" => Some(Arc::new(MetropolisIntegrator::new(&MetropolisConfig::default()))),
+        "whitted" => Some(Arc::new(WhittedIntegrator::new())),
+        "ao" => Some(Arc::new(AmbientOcclusionIntegrator::new(1.0, 4))),
+        "direct" => Some(Arc::new(DirectLightingIntegrator::new())),
+        "normals" => Some(Arc::new(DebugIntegrator::new(DebugChannel::Normals))),
+        "depth" => Some(Arc::new(DebugIntegrator::new(DebugChannel::Depth {
+            max_distance: 10.0,
+        }))),
+        "uvs" => Some(Arc::new(DebugIntegrator::new(DebugChannel::Uvs))),
+        "materials" => Some(Arc::new(DebugIntegrator::new(DebugChannel::MaterialIds))),
+        _ => None,
+    }
+}
+
 fn main() {
+    let usage = "Usage: raytracer [listen ADDRESS] [path|bdpt|photons|mlt|whitted|ao|direct|normals|depth|uvs|materials] [seed] [seconds]\n       raytracer worker ADDRESS [threads]";
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("worker") {
+        let address = args.get(1).expect(usage);
+        let threads = args.get(2).map(|threads| threads.parse().expect(usage));
+        run_worker(address.as_str(), threads).expect("Render worker failed");
+        return;
+    }
+    // The tiles are traced by the workers connecting to the address.
+    let listener = if args.first().map(String::as_str) == Some("listen") {
+        let address = args.get(1).expect(usage);
+        let listener = TcpListener::bind(address.as_str()).expect("Cannot listen for workers");
+        args.drain(..2);
+        Some(listener)
+    } else {
+        None
+    };
+
+    let integrator_name = args.first().cloned().unwrap_or_else(|| "path".to_string());
+    let integrator = integrator_from_name(&integrator_name).expect(usage);
+    let seed = args.get(1).map_or(0, |seed| seed.parse().expect(usage));
+    // With a time limit, passes are added until it runs out.
+    let budget = args.get(2).map(|seconds| RenderBudget {
+        time: Some(Duration::from_secs(seconds.parse().expect(usage))),
+        target_error: None,
+    });
+
     let config = RendererConfig {
         image_width: 1024,
         image_height: 768,
         pixel_size: 0.006,
         pixel_sampler: UnitSquareSampler::regular_sampler(8),
-        max_trace_depth: 8,
+        adaptive_sampling: None,
+        filter: Arc::new(BoxFilter::default()),
+        passes: 1,
+        budget,
+        max_trace_depth: 64,
         ambient_color: Color {
             r: 0.6,
             g: 0.8,
             b: 1.0,
             a: 1.0,
         },
+        atmosphere: None,
+        lights: Vec::new(),
+        integrator,
+        spectral: false,
+        seed,
+        statistics: true,
+        checkpoint: None,
+        progress: Some(Arc::new(|progress: &Progress| {
+            print!(
+                "\r{0:5.1}% rendered, {1} camera rays, {2} seconds left ",
+                100.0 * progress.fraction(),
+                progress.camera_rays,
+                progress.eta.as_secs()
+            );
+            io::stdout().flush().expect("Cannot write progress");
+        })),
+        crop: None,
+        tiles: TileConfig::default(),
         num_render_threads: None,
     };
 
@@ -43,7 +122,6 @@ fn main() {
             Vector3::new(2.0, 2.0, 2.0),
             Vector3::zero(),
             Arc::new(Metal::new(
-                &UnitSphereSampler::random_sampler(64),
                 &Color {
                     r: 0.8,
                     g: 0.8,
@@ -57,7 +135,6 @@ fn main() {
             center: Vector3::new(-2.05, 1.0, 0.0),
             radius: 1.0,
             material: Arc::new(Metal::new(
-                &UnitSphereSampler::random_sampler(64),
                 &Color {
                     r: 0.8,
                     g: 0.6,
@@ -98,7 +175,6 @@ fn main() {
             radius: 100.0,
             //material: Rc::new(Lambertian::new(&HemiSphereSampler::jittered_sampler(8, 100.0), &Color { r: 0.8, g: 1.0, b: 0.0, a: 1.0 })),
             material: Arc::new(Metal::new(
-                &UnitSphereSampler::random_sampler(64),
                 &Color {
                     r: 0.8,
                     g: 0.8,
@@ -110,13 +186,25 @@ fn main() {
         }),
     ]);
 
-    let start_time = Instant::now();
-
-    renderer.render(&camera, &objects);
-
-    let elapsed = start_time.elapsed().as_secs();
+    let summary = match listener {
+        Some(listener) => renderer
+            .render_distributed(&camera, &objects, &listener, |_, _| true)
+            .expect("Cannot render with workers"),
+        None => renderer
+            .render(&camera, &objects)
+            .expect("Cannot write checkpoint"),
+    };
 
-    println!("Image rendered in {0} seconds", elapsed);
+    println!();
+    println!(
+        "Image rendered in {0} seconds with {1} samples per pixel",
+        summary.elapsed.as_secs(),
+        summary.samples_per_pixel
+    );
+    if let Some(statistics) = renderer.statistics() {
+        println!();
+        print!("{}", statistics);
+    }
 
     renderer
         .write_to_file("image.bmp")
@@ -12,7 +12,7 @@ fn render_unit_square_sampler(render_buffer: &mut RenderBuffer, offset_x: u32, o
 }
 
 fn render_hemi_sphere_sampler(render_buffer: &mut RenderBuffer, offset_x: u32, offset_y: u32, dim: f64, sampler: &HemiSphereSampler) {
-    for &vec in sampler.samples[0].iter() {
+    for &vec in sampler.pattern.samples[0].iter() {
         let x = (dim * (vec.x + 1.0) * 0.5) as u32;
         let y = (dim * (vec.y + 1.0) * 0.5) as u32;
         let z = (dim * (vec.z + 1.0) * 0.5) as u32;
@@ -80,5 +80,23 @@ fn main() {
         render_hemi_sphere_sampler(&mut render_buffer, box_size * x, box_size * 1, box_dim, &HemiSphereSampler::jittered_sampler(8, e));
     }
 
+    // Patterns with a sample count that is not a square, side by side.
+    let num_samples = 50;
+    let patterns = [
+        UnitSquareSampler::multi_jittered_sampler(num_samples),
+        UnitSquareSampler::n_rooks_sampler(num_samples),
+        UnitSquareSampler::poisson_disk_sampler(num_samples),
+        UnitSquareSampler::random_sampler(num_samples),
+        UnitSquareSampler::halton_sampler(num_samples),
+        UnitSquareSampler::hammersley_sampler(num_samples),
+        UnitSquareSampler::sobol_sampler(num_samples),
+        UnitSquareSampler::zero_two_sampler(num_samples),
+    ];
+    for (i, pattern) in patterns.iter().enumerate() {
+        let x = i as u32 % num_boxes;
+        let y = 2 + i as u32 / num_boxes;
+        render_unit_square_sampler(&mut render_buffer, box_size * x, box_size * y, box_dim, pattern);
+    }
+
     render_buffer.write_to_file("samplers.bmp").expect("Cannot write bitmap");
 }
\ No newline at end of file
@@ -1,9 +1,34 @@
+use std::io::Result;
 
+use crate::io::{InputStream, OutputStream};
 use crate::math::*;
+use crate::serialize::{unsupported, write_tagged, Deserialize, Serialize};
 
 pub trait Camera : Send + Sync {
 
     fn generate_ray(&self, dx: f64, dy: f64) -> Ray3;
+
+    /// Maps `point` back onto the film, the inverse of `generate_ray`. Returns `None` for points behind
+    /// the camera and for cameras that cannot be connected to arbitrary points.
+    fn project(&self, _point: &Vector3) -> Option<CameraProjection> {
+        None
+    }
+
+    /// Writes the type and the fields of the camera, e.g. to ship the scene to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("camera"))
+    }
+}
+
+/// Where a point in the scene appears on the film of a camera.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraProjection {
+    /// The film coordinates, as passed to `generate_ray`.
+    pub film_position: Vector2,
+    /// The origin of the camera ray through the point.
+    pub origin: Vector3,
+    /// The film area per solid angle of camera rays around the direction to the point.
+    pub film_density: f64,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -22,6 +47,36 @@ impl Camera for OrthographicCamera {
             direction: self.direction,
         }
     }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "orthographic", self)
+    }
+}
+
+impl Serialize for OrthographicCamera {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [
+            self.eye,
+            self.lookat,
+            self.direction,
+            self.uvw.0,
+            self.uvw.1,
+            self.uvw.2,
+        ]
+        .write_to(out)
+    }
+}
+
+impl Deserialize for OrthographicCamera {
+    fn read_from(input: &mut InputStream) -> Result<OrthographicCamera> {
+        let [eye, lookat, direction, u, v, w] = <[Vector3; 6]>::read_from(input)?;
+        Ok(OrthographicCamera {
+            eye,
+            lookat,
+            direction,
+            uvw: (u, v, w),
+        })
+    }
 }
 
 impl OrthographicCamera {
@@ -51,6 +106,46 @@ impl Camera for PinholeCamera {
             direction: (self.uvw.0 * dx + self.uvw.1 * dy - self.uvw.2 * self.distance).normalized(),
         }
     }
+
+    fn project(&self, point: &Vector3) -> Option<CameraProjection> {
+        let q = *point - self.eye;
+        let depth = -q.dot(&self.uvw.2);
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let cos_theta = depth / q.length();
+        Some(CameraProjection {
+            film_position: Vector2::new(q.dot(&self.uvw.0), q.dot(&self.uvw.1)) * (self.distance / depth),
+            origin: self.eye,
+            film_density: self.distance * self.distance / (cos_theta * cos_theta * cos_theta),
+        })
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "pinhole", self)
+    }
+}
+
+impl Serialize for PinholeCamera {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.eye.write_to(out)?;
+        self.distance.write_to(out)?;
+        [self.uvw.0, self.uvw.1, self.uvw.2].write_to(out)
+    }
+}
+
+impl Deserialize for PinholeCamera {
+    fn read_from(input: &mut InputStream) -> Result<PinholeCamera> {
+        let eye = Vector3::read_from(input)?;
+        let distance = f64::read_from(input)?;
+        let [u, v, w] = <[Vector3; 3]>::read_from(input)?;
+        Ok(PinholeCamera {
+            eye,
+            distance,
+            uvw: (u, v, w),
+        })
+    }
 }
 
 impl PinholeCamera {
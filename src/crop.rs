@@ -0,0 +1,165 @@
+//! Rendering of a part of the image, e.g. to re-render an area after changing a material.
+
+use std::io::Result;
+use std::path::PathBuf;
+
+use crate::io::{InputStream, OutputStream};
+use crate::serialize::{invalid_data, write_tagged, Deserialize, Serialize};
+use crate::tile::Tile;
+
+/// A rectangle of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CropWindow {
+    /// A rectangle of pixels, with `(x, y)` its top left pixel.
+    Pixels {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// The top left and bottom right corner as fractions of the image width and height, from 0 to 1. The
+    /// window covers every pixel it overlaps.
+    Normalized {
+        x_min: f64,
+        y_min: f64,
+        x_max: f64,
+        y_max: f64,
+    },
+}
+
+impl CropWindow {
+    /// The pixels of the window in an image of the given size. The window must overlap the image.
+    pub fn pixels(&self, image_width: u32, image_height: u32) -> Tile {
+        let (x, y, x_end, y_end) = match *self {
+            CropWindow::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => (x, y, x.saturating_add(width), y.saturating_add(height)),
+            CropWindow::Normalized {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => {
+                let to_pixel = |t: f64, size: u32| (t.clamp(0.0, 1.0) * size as f64) as u32;
+                let to_pixel_end =
+                    |t: f64, size: u32| (t.clamp(0.0, 1.0) * size as f64).ceil() as u32;
+                (
+                    to_pixel(x_min, image_width),
+                    to_pixel(y_min, image_height),
+                    to_pixel_end(x_max, image_width),
+                    to_pixel_end(y_max, image_height),
+                )
+            }
+        };
+        let x_end = x_end.min(image_width);
+        let y_end = y_end.min(image_height);
+        expect_lt!(x, x_end);
+        expect_lt!(y, y_end);
+        Tile {
+            x,
+            y,
+            width: x_end - x,
+            height: y_end - y,
+        }
+    }
+}
+
+impl Serialize for CropWindow {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        match *self {
+            CropWindow::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => write_tagged(out, "pixels", &[x, y, width, height]),
+            CropWindow::Normalized {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => write_tagged(out, "normalized", &[x_min, y_min, x_max, y_max]),
+        }
+    }
+}
+
+impl Deserialize for CropWindow {
+    fn read_from(input: &mut InputStream) -> Result<CropWindow> {
+        match String::read_from(input)?.as_str() {
+            "pixels" => {
+                let [x, y, width, height] = <[u32; 4]>::read_from(input)?;
+                Ok(CropWindow::Pixels {
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+            }
+            "normalized" => {
+                let [x_min, y_min, x_max, y_max] = <[f64; 4]>::read_from(input)?;
+                Ok(CropWindow::Normalized {
+                    x_min,
+                    y_min,
+                    x_max,
+                    y_max,
+                })
+            }
+            _ => Err(invalid_data("Unknown crop window")),
+        }
+    }
+}
+
+/// What becomes of the pixels outside the crop window.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CropOutput {
+    /// The image of the renderer is just the window.
+    Cropped,
+    /// The window is pasted into the full-size image read from the file, e.g. an earlier render.
+    Composite(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct CropConfig {
+    pub window: CropWindow,
+    pub output: CropOutput,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_are_clipped_to_the_image() {
+        let window = CropWindow::Pixels {
+            x: 10,
+            y: 20,
+            width: 100,
+            height: 5,
+        };
+        let expected = Tile {
+            x: 10,
+            y: 20,
+            width: 54,
+            height: 5,
+        };
+        assert_eq!(expected, window.pixels(64, 48));
+
+        // Normalized windows cover the pixels they touch.
+        let window = CropWindow::Normalized {
+            x_min: 0.25,
+            y_min: 0.1,
+            x_max: 0.5,
+            y_max: 1.5,
+        };
+        let expected = Tile {
+            x: 16,
+            y: 4,
+            width: 16,
+            height: 44,
+        };
+        assert_eq!(expected, window.pixels(64, 48));
+    }
+}
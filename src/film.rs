@@ -0,0 +1,285 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::camera::{Camera, CameraProjection};
+use crate::color::Color;
+use crate::math::{Vector2, Vector3};
+use crate::RenderBuffer;
+
+/// Number of fractional bits of the fixed point sums of splats, which leaves every sum room for 2^39.
+const SPLAT_FRACTION_BITS: i32 = 24;
+/// Largest magnitude of a single splat, so that no splat overflows a sum on its own.
+const SPLAT_MAX: f64 = 1e9;
+
+/// The film of the camera. Besides the image geometry it collects contributions that integrators splat
+/// to arbitrary pixels, e.g. from light paths connected to the camera. Splats are added atomically, so
+/// all render threads can share one film. They are summed in fixed point, because integer sums do not
+/// depend on the order in which the threads add to them. Splats that are not finite are dropped.
+/// Camera samples may splat, so the renderer averages their splats over the camera samples traced so far.
+/// Unscaled splats, e.g. those of preprocessing, are added to the image as they are.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_size: f64,
+    /// Seed of the render, from which integrators derive the random numbers they draw outside camera paths.
+    pub seed: u64,
+    /// Number of threads integrators may use when they preprocess the scene.
+    pub num_threads: u32,
+    camera: Arc<dyn Camera>,
+    splats: Vec<AtomicU64>,
+    unscaled_splats: Vec<AtomicU64>,
+    /// Whether anything was splatted since the splats were last taken.
+    splatted: AtomicBool,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, pixel_size: f64, camera: &Arc<dyn Camera>) -> Film {
+        Film {
+            width,
+            height,
+            pixel_size,
+            seed: 0,
+            num_threads: 1,
+            camera: Arc::clone(camera),
+            splats: fixed_point_sums(width, height),
+            unscaled_splats: fixed_point_sums(width, height),
+            splatted: AtomicBool::new(false),
+        }
+    }
+
+    /// A film with the same geometry, camera and seed, but without splats.
+    pub fn cleared(&self) -> Film {
+        let mut film = Film::new(self.width, self.height, self.pixel_size, &self.camera);
+        film.seed = self.seed;
+        film.num_threads = self.num_threads;
+        film
+    }
+
+    pub fn camera(&self) -> &Arc<dyn Camera> {
+        &self.camera
+    }
+
+    /// The area of the whole film.
+    pub fn area(&self) -> f64 {
+        self.width as f64 * self.height as f64 * self.pixel_size * self.pixel_size
+    }
+
+    /// Projects `point` onto the film and returns the pixel it falls into together with the projection.
+    pub fn project(&self, point: &Vector3) -> Option<(u32, u32, CameraProjection)> {
+        let projection = self.camera.project(point)?;
+        let (x, y) = self.raster_position(projection.film_position)?;
+        Some((x, y, projection))
+    }
+
+    /// Returns the pixel containing the film coordinates `film_position`, using the same pixel layout as the
+    /// renderer when it generates camera rays.
+    pub fn raster_position(&self, film_position: Vector2) -> Option<(u32, u32)> {
+        let x = (film_position.x / self.pixel_size + 0.5 * self.width as f64 + 0.5).floor();
+        let y = (-film_position.y / self.pixel_size + 0.5 * self.height as f64 + 0.5).floor();
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            None
+        } else {
+            Some((x as u32, y as u32))
+        }
+    }
+
+    /// Splats a contribution of a camera sample.
+    pub fn splat(&self, x: u32, y: u32, color: &Color) {
+        if self.add_splat(&self.splats, x, y, color) {
+            self.splatted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Splats a contribution that the renderer adds to the image as it is, e.g. one of a Markov chain.
+    pub fn splat_unscaled(&self, x: u32, y: u32, color: &Color) {
+        self.add_splat(&self.unscaled_splats, x, y, color);
+    }
+
+    /// Adds `color` to the sums of pixel (`x`, `y`) and returns whether they changed.
+    fn add_splat(&self, sums: &[AtomicU64], x: u32, y: u32, color: &Color) -> bool {
+        expect_lt!(x, self.width);
+        expect_lt!(y, self.height);
+        let index = 3 * (y as usize * self.width as usize + x as usize);
+        let mut added = false;
+        for (offset, value) in [color.r, color.g, color.b].iter().enumerate() {
+            if *value == 0.0 || !value.is_finite() {
+                continue;
+            }
+            let value = value.clamp(-SPLAT_MAX, SPLAT_MAX);
+            let fixed = (value * 2f64.powi(SPLAT_FRACTION_BITS)).round() as i64;
+            sums[index + offset].fetch_add(fixed as u64, Ordering::Relaxed);
+            added = true;
+        }
+        added
+    }
+
+    /// The sum of the splats of camera samples to pixel (`x`, `y`).
+    pub fn get_splat(&self, x: u32, y: u32) -> Color {
+        self.get_sum(&self.splats, x, y)
+    }
+
+    /// The sum of the unscaled splats to pixel (`x`, `y`).
+    pub fn get_unscaled_splat(&self, x: u32, y: u32) -> Color {
+        self.get_sum(&self.unscaled_splats, x, y)
+    }
+
+    fn get_sum(&self, sums: &[AtomicU64], x: u32, y: u32) -> Color {
+        let index = 3 * (y as usize * self.width as usize + x as usize);
+        let channel = |offset: usize| {
+            sums[index + offset].load(Ordering::Relaxed) as i64 as f64
+                * 2f64.powi(-SPLAT_FRACTION_BITS)
+        };
+        Color {
+            r: channel(0),
+            g: channel(1),
+            b: channel(2),
+            a: 1.0,
+        }
+    }
+
+    /// The fixed point sums of all splats, those of camera samples first, e.g. to save them in a checkpoint.
+    pub fn splat_sums(&self) -> Vec<u64> {
+        self.splats
+            .iter()
+            .chain(&self.unscaled_splats)
+            .map(|sum| sum.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Replaces the sums of all splats by those returned by `splat_sums`.
+    pub fn set_splat_sums(&self, sums: &[u64]) {
+        expect_eq!(sums.len(), self.splats.len() + self.unscaled_splats.len());
+        for (splat, &sum) in self.splats.iter().chain(&self.unscaled_splats).zip(sums) {
+            splat.store(sum, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes the splats of camera samples, returning the index and fixed point sum of every sum that is not zero. Together
+    /// with `add_splat_sum` this moves the splats of one film to another.
+    pub fn take_splats(&self) -> Vec<(u32, u64)> {
+        if !self.splatted.swap(false, Ordering::Relaxed) {
+            return Vec::new();
+        }
+        self.splats
+            .iter()
+            .enumerate()
+            .map(|(index, sum)| (index as u32, sum.swap(0, Ordering::Relaxed)))
+            .filter(|&(_, sum)| sum != 0)
+            .collect()
+    }
+
+    /// Adds a fixed point sum returned by `take_splats`.
+    pub fn add_splat_sum(&self, index: u32, sum: u64) {
+        expect_lt!(index as usize, self.splats.len());
+        self.splats[index as usize].fetch_add(sum, Ordering::Relaxed);
+        self.splatted.store(true, Ordering::Relaxed);
+    }
+
+    /// Adds the splats of camera samples, multiplied by `scale`, and the unscaled splats to the pixels of
+    /// `buffer`.
+    pub fn add_splats_to(&self, buffer: &mut RenderBuffer, scale: f64) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let splat = self.get_splat(x, y) * scale + self.get_unscaled_splat(x, y);
+                if splat.r == 0.0 && splat.g == 0.0 && splat.b == 0.0 {
+                    continue;
+                }
+                let mut color = buffer.get_pixel(x, y);
+                color.r += splat.r;
+                color.g += splat.g;
+                color.b += splat.b;
+                buffer.set_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+fn fixed_point_sums(width: u32, height: u32) -> Vec<AtomicU64> {
+    (0..3 * width as usize * height as usize)
+        .map(|_| AtomicU64::new(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::PinholeCamera;
+    use std::thread;
+
+    fn film() -> Film {
+        let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+            &Vector3::new(0.0, 0.0, 5.0),
+            &Vector3::zero(),
+            &Vector3::new(0.0, 1.0, 0.0),
+            2.0,
+        ));
+        Film::new(8, 6, 0.1, &camera)
+    }
+
+    #[test]
+    fn projection_inverts_camera_rays() {
+        let film = film();
+        let ray = film.camera().generate_ray(0.23, -0.12);
+        let (x, y, projection) = film.project(&ray.point_at(3.0)).unwrap();
+        assert_close!(0.23, projection.film_position.x);
+        assert_close!(-0.12, projection.film_position.y);
+        assert_eq!((6, 4), (x, y));
+    }
+
+    #[test]
+    fn concurrent_splats_add_up() {
+        let film = Arc::new(film());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let film = Arc::clone(&film);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        film.splat(2, 3, &(Color::white() * 0.5));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_close!(2000.0, film.get_splat(2, 3).g);
+    }
+
+    #[test]
+    fn large_splats_do_not_overflow() {
+        let film = film();
+        for _ in 0..10_000 {
+            film.splat(1, 1, &(Color::white() * 1e6));
+        }
+        film.splat(1, 1, &(Color::white() * f64::NAN));
+        film.splat(1, 1, &(Color::white() * f64::INFINITY));
+        assert_close!(1e10, film.get_splat(1, 1).r);
+    }
+
+    #[test]
+    fn taken_splats_move_to_another_film() {
+        let film = film();
+        assert!(film.take_splats().is_empty());
+        film.splat(2, 3, &(Color::white() * 0.5));
+        film.splat(
+            7,
+            5,
+            &Color {
+                r: 0.0,
+                g: -0.25,
+                b: 0.0,
+                a: 1.0,
+            },
+        );
+
+        let other = film.cleared();
+        let splats = film.take_splats();
+        assert_eq!(4, splats.len());
+        for (index, sum) in splats {
+            other.add_splat_sum(index, sum);
+        }
+        assert_close!(0.5, other.get_splat(2, 3).b);
+        assert_close!(-0.25, other.get_splat(7, 5).g);
+        assert_eq!(0.0, film.get_splat(2, 3).b);
+    }
+}
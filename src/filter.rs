@@ -0,0 +1,342 @@
+//! Reconstruction filters, which weight the contribution of a pixel sample to the pixels around it.
+//! All filters are separable and centred on the pixel, with offsets measured in pixels.
+
+use std::f64::consts::PI;
+use std::io::Result;
+
+use crate::io::{InputStream, OutputStream};
+use crate::serialize::{unsupported, write_tagged, Deserialize, Serialize};
+
+pub trait Filter: Send + Sync {
+    /// Samples farther than the radius from the centre of a pixel along either axis do not contribute to it.
+    fn radius(&self) -> f64;
+
+    /// The weight of a sample at offset `(x, y)` from the centre of a pixel. May be negative.
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+
+    /// Writes the type and the parameters of the filter, e.g. to ship the render to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("filter"))
+    }
+}
+
+/// Weights all samples within the radius equally. With a radius of half a pixel every sample contributes to
+/// exactly one pixel, whose color becomes the plain average of its samples.
+#[derive(Clone, Debug)]
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+impl Default for BoxFilter {
+    fn default() -> BoxFilter {
+        BoxFilter { radius: 0.5 }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        // Half-open, so that samples on the border of two pixels count for one of them only.
+        let inside = |t: f64| t >= -self.radius && t < self.radius;
+        if inside(x) && inside(y) {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "box", self)
+    }
+}
+
+impl Serialize for BoxFilter {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.radius.write_to(out)
+    }
+}
+
+impl Deserialize for BoxFilter {
+    fn read_from(input: &mut InputStream) -> Result<BoxFilter> {
+        Ok(BoxFilter {
+            radius: f64::read_from(input)?,
+        })
+    }
+}
+
+/// Weights samples linearly falling off from the centre of the pixel to the radius.
+#[derive(Clone, Debug)]
+pub struct TentFilter {
+    pub radius: f64,
+}
+
+impl Default for TentFilter {
+    fn default() -> TentFilter {
+        TentFilter { radius: 1.0 }
+    }
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "tent", self)
+    }
+}
+
+impl Serialize for TentFilter {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.radius.write_to(out)
+    }
+}
+
+impl Deserialize for TentFilter {
+    fn read_from(input: &mut InputStream) -> Result<TentFilter> {
+        Ok(TentFilter {
+            radius: f64::read_from(input)?,
+        })
+    }
+}
+
+/// A Gaussian `exp(-alpha * x^2)`, shifted down to reach zero at the radius.
+#[derive(Clone, Debug)]
+pub struct GaussianFilter {
+    pub radius: f64,
+    /// Falloff of the Gaussian. Larger values give sharper images.
+    pub alpha: f64,
+}
+
+impl Default for GaussianFilter {
+    fn default() -> GaussianFilter {
+        GaussianFilter {
+            radius: 1.5,
+            alpha: 2.0,
+        }
+    }
+}
+
+impl GaussianFilter {
+    fn evaluate_1d(&self, t: f64) -> f64 {
+        let edge = (-self.alpha * self.radius * self.radius).exp();
+        ((-self.alpha * t * t).exp() - edge).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "gaussian", self)
+    }
+}
+
+impl Serialize for GaussianFilter {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.radius, self.alpha].write_to(out)
+    }
+}
+
+impl Deserialize for GaussianFilter {
+    fn read_from(input: &mut InputStream) -> Result<GaussianFilter> {
+        let [radius, alpha] = <[f64; 2]>::read_from(input)?;
+        Ok(GaussianFilter { radius, alpha })
+    }
+}
+
+/// The cubic filters of Mitchell and Netravali, stretched over the radius. Filters with `b + 2 * c = 1`
+/// reproduce flat images exactly; the recommended `b = c = 1/3` trades blurring against ringing.
+#[derive(Clone, Debug)]
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl Default for MitchellFilter {
+    fn default() -> MitchellFilter {
+        MitchellFilter {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        }
+    }
+}
+
+impl MitchellFilter {
+    fn evaluate_1d(&self, t: f64) -> f64 {
+        let (b, c) = (self.b, self.c);
+        let t = (2.0 * t / self.radius).abs();
+        let value = if t < 1.0 {
+            (12.0 - 9.0 * b - 6.0 * c) * t * t * t
+                + (-18.0 + 12.0 * b + 6.0 * c) * t * t
+                + (6.0 - 2.0 * b)
+        } else if t < 2.0 {
+            (-b - 6.0 * c) * t * t * t
+                + (6.0 * b + 30.0 * c) * t * t
+                + (-12.0 * b - 48.0 * c) * t
+                + (8.0 * b + 24.0 * c)
+        } else {
+            0.0
+        };
+        value / 6.0
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "mitchell", self)
+    }
+}
+
+impl Serialize for MitchellFilter {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.radius, self.b, self.c].write_to(out)
+    }
+}
+
+impl Deserialize for MitchellFilter {
+    fn read_from(input: &mut InputStream) -> Result<MitchellFilter> {
+        let [radius, b, c] = <[f64; 3]>::read_from(input)?;
+        Ok(MitchellFilter { radius, b, c })
+    }
+}
+
+/// A sinc windowed by a wider sinc that vanishes at the radius, which keeps images sharp at the price of
+/// some ringing around edges.
+#[derive(Clone, Debug)]
+pub struct LanczosFilter {
+    pub radius: f64,
+}
+
+impl Default for LanczosFilter {
+    fn default() -> LanczosFilter {
+        LanczosFilter { radius: 3.0 }
+    }
+}
+
+impl LanczosFilter {
+    fn evaluate_1d(&self, t: f64) -> f64 {
+        if t.abs() >= self.radius {
+            0.0
+        } else {
+            sinc(t) * sinc(t / self.radius)
+        }
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "lanczos", self)
+    }
+}
+
+impl Serialize for LanczosFilter {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.radius.write_to(out)
+    }
+}
+
+impl Deserialize for LanczosFilter {
+    fn read_from(input: &mut InputStream) -> Result<LanczosFilter> {
+        Ok(LanczosFilter {
+            radius: f64::read_from(input)?,
+        })
+    }
+}
+
+fn sinc(t: f64) -> f64 {
+    if t.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * t).sin() / (PI * t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The weights a sample at offset `(x, y)` from the centre of a pixel gives that pixel and its neighbours.
+    fn weights(filter: &dyn Filter, x: f64, y: f64) -> Vec<f64> {
+        let reach = filter.radius().ceil() as i32 + 1;
+        let mut weights = Vec::new();
+        for j in -reach..=reach {
+            for i in -reach..=reach {
+                weights.push(filter.evaluate(x - i as f64, y - j as f64));
+            }
+        }
+        weights
+    }
+
+    #[test]
+    fn filters_peak_at_the_centre_and_vanish_at_their_radius() {
+        let filters: Vec<Box<dyn Filter>> = vec![
+            Box::new(BoxFilter::default()),
+            Box::new(TentFilter::default()),
+            Box::new(GaussianFilter::default()),
+            Box::new(MitchellFilter::default()),
+            Box::new(LanczosFilter::default()),
+        ];
+        for filter in filters {
+            let r = filter.radius();
+            let centre = filter.evaluate(0.0, 0.0);
+            assert!(centre > 0.0);
+            for t in [0.1, 0.3, 0.45, 0.7 * r, 0.99 * r] {
+                assert!(filter.evaluate(t, 0.0) <= centre);
+                assert_eq!(filter.evaluate(t, 0.0), filter.evaluate(-t, 0.0));
+                assert_eq!(filter.evaluate(t, 0.0), filter.evaluate(0.0, t));
+            }
+            assert!(filter.evaluate(r, 0.0).abs() < 1e-12);
+            assert_eq!(filter.evaluate(r + 0.1, 0.0), 0.0);
+            assert_eq!(filter.evaluate(0.0, -r - 0.1), 0.0);
+        }
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        // Wherever a sample falls, the weights of interpolating filters over the pixel grid have the same sum.
+        let filters: Vec<Box<dyn Filter>> = vec![
+            Box::new(BoxFilter::default()),
+            Box::new(TentFilter::default()),
+            Box::new(MitchellFilter::default()),
+        ];
+        for filter in filters {
+            let total: f64 = weights(filter.as_ref(), 0.0, 0.0).iter().sum();
+            for (x, y) in [(0.1, 0.2), (0.25, -0.4), (-0.3, 0.05)] {
+                let sum: f64 = weights(filter.as_ref(), x, y).iter().sum();
+                assert!((sum - total).abs() < 1e-9, "{} vs {}", sum, total);
+            }
+        }
+    }
+}
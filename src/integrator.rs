@@ -0,0 +1,691 @@
+use rand::{Rng, RngCore};
+use std::io::{Result, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::film::Film;
+use crate::io::{InputStream, OutputStream};
+use crate::material::Material;
+use crate::math::{Ray3, Vector2};
+use crate::scene::{power_heuristic, Scene};
+use crate::serialize::{invalid_data, unsupported, write_tagged, Deserialize, Serialize};
+use crate::shapes::Intersection;
+use crate::spectrum::{Radiance, SampledSpectrum};
+use crate::stats;
+use crate::warp::{cosine_hemisphere, to_world};
+use crate::TraceContext;
+
+/// A rendering algorithm that computes the radiance arriving at the camera along a ray.
+/// Integrators may additionally splat contributions to arbitrary pixels of the `film`.
+pub trait Integrator: Send + Sync {
+    /// Called once per render before any radiance is computed, e.g. to trace photons.
+    fn preprocess(&self, _scene: &Scene, _film: &Film) {}
+
+    fn radiance(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color;
+
+    /// The radiance at the wavelengths of `trace_context` in spectral mode. Integrators terminate the secondary
+    /// wavelengths of the context if the path depended on the hero wavelength. Integrators returning `None`
+    /// compute RGB radiance, also in spectral mode.
+    fn spectral_radiance(
+        &self,
+        _scene: &Scene,
+        _film: &Film,
+        _trace_context: &mut TraceContext,
+        _ray: &Ray3,
+        _rng: &mut dyn RngCore,
+    ) -> Option<SampledSpectrum> {
+        None
+    }
+
+    /// Whether all light reaches the film through splats. The radiance of camera rays then tells nothing
+    /// about the noise of the image.
+    fn splats_only(&self) -> bool {
+        false
+    }
+
+    /// Writes the type and the parameters of the integrator, e.g. to ship the render to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("integrator"))
+    }
+}
+
+/// Path tracing with light sampling and multiple importance sampling at every scattering event.
+/// Paths are extended iteratively while tracking their throughput. After `russian_roulette_depth` bounces
+/// they are terminated randomly with a probability based on the remaining throughput, so that dark paths
+/// end early without biasing the image. The maximum trace depth of the scene only acts as a safety bound.
+#[derive(Clone, Debug)]
+pub struct PathTracer {
+    russian_roulette_depth: u32,
+}
+
+impl Default for PathTracer {
+    fn default() -> PathTracer {
+        PathTracer::new()
+    }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        self.trace(scene, &mut trace_context.clone(), ray, rng)
+    }
+
+    fn spectral_radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        trace_context: &mut TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Option<SampledSpectrum> {
+        Some(self.trace(scene, trace_context, ray, rng))
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "path", self)
+    }
+}
+
+impl Serialize for PathTracer {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.russian_roulette_depth.write_to(out)
+    }
+}
+
+impl Deserialize for PathTracer {
+    fn read_from(input: &mut InputStream) -> Result<PathTracer> {
+        let depth = u32::read_from(input)?;
+        Ok(PathTracer::with_russian_roulette_depth(depth))
+    }
+}
+
+impl PathTracer {
+    pub fn new() -> PathTracer {
+        PathTracer {
+            russian_roulette_depth: 3,
+        }
+    }
+
+    /// Sets the number of bounces after which paths may be terminated by Russian roulette.
+    pub fn with_russian_roulette_depth(russian_roulette_depth: u32) -> PathTracer {
+        PathTracer {
+            russian_roulette_depth,
+        }
+    }
+
+    /// Traces a path carrying RGB colors or, in spectral mode, spectra at the wavelengths of `trace_context`.
+    fn trace<S: Radiance>(
+        &self,
+        scene: &Scene,
+        trace_context: &mut TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> S {
+        let mut radiance = S::default();
+        let mut throughput = S::uplift(&Color::white(), trace_context);
+        let mut ray = *ray;
+        let mut scatter_pdf: Option<f64> = None;
+
+        for depth in 0..scene.max_trace_depth {
+            let have_hit = scene.intersect(&ray);
+            let t_surface = have_hit.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+            let light_hit = scene.intersect_light(&ray).filter(|hit| hit.0.t < t_surface);
+            let t_hit = light_hit.as_ref().map_or(t_surface, |hit| hit.0.t);
+            let mut transmission = Color::white();
+            let medium_event = scene.sample_media(&ray, t_hit, rng, &mut transmission);
+            throughput = throughput * S::uplift(&transmission, trace_context);
+
+            if let Some((t, weight, medium)) = medium_event {
+                throughput = throughput * S::uplift(&weight, trace_context);
+
+                let point = ray.point_at(t);
+                let phase = medium.phase_function();
+                let incoming = ray.direction;
+                let white = S::uplift(&Color::white(), trace_context);
+                let direct =
+                    scene.direct_lighting(&point, trace_context, rng, true, &|direction| {
+                        let p = phase.evaluate(incoming.dot(direction));
+                        (white * p, p)
+                    });
+                radiance += throughput * direct;
+
+                let direction = phase.sample(&incoming, rng);
+                scatter_pdf = Some(phase.evaluate(incoming.dot(&direction)));
+                ray = Ray3::new(point, direction);
+            } else if let Some((hit, light)) = light_hit {
+                let weight = scatter_pdf.map_or(1.0, |pdf| {
+                    power_heuristic(pdf, light.pdf(&ray.origin, &ray.direction))
+                });
+                radiance += throughput * S::uplift(&hit.radiance, trace_context) * weight;
+                break;
+            } else if let Some(intersection) = have_hit {
+                let material = &intersection.material;
+                let light_origin = intersection.point + intersection.normal * 0.01;
+                let direct =
+                    scene.direct_lighting(&light_origin, trace_context, rng, true, &|direction| {
+                        (
+                            S::uplift(
+                                &material.evaluate(&ray, &intersection, direction),
+                                trace_context,
+                            ),
+                            material.pdf(&ray, &intersection, direction),
+                        )
+                    });
+                radiance += throughput * direct;
+
+                let mut scattered = Ray3::default();
+                let mut attenuation = Color::black();
+                if !material.scatter(
+                    trace_context,
+                    &ray,
+                    &intersection,
+                    rng,
+                    &mut attenuation,
+                    &mut scattered,
+                ) {
+                    break;
+                }
+                trace_context.dimension += 1;
+                throughput = throughput
+                    * S::uplift_with(&attenuation, trace_context, &|wavelength| {
+                        material.spectral_attenuation(&ray, &intersection, &attenuation, wavelength)
+                    });
+
+                let pdf = material.pdf(&ray, &intersection, &scattered.direction);
+                scatter_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+                ray = scattered;
+            } else {
+                radiance +=
+                    throughput * S::uplift(&scene.background(&ray, scatter_pdf), trace_context);
+                break;
+            }
+
+            if depth + 1 >= self.russian_roulette_depth {
+                let survival = throughput.max_value().min(0.95);
+                if survival <= 0.0 || rng.gen::<f64>() >= survival {
+                    break;
+                }
+                throughput = throughput * (1.0 / survival);
+            }
+        }
+
+        radiance
+    }
+}
+
+/// Whitted-style ray tracing: direct light at diffuse surfaces plus an ambient term, and recursion
+/// only along the discrete directions of mirror-like materials. Participating media are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct WhittedIntegrator {}
+
+impl Integrator for WhittedIntegrator {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        self.trace_ray(scene, trace_context, ray, 0, rng)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        "whitted".write_to(out)
+    }
+}
+
+impl WhittedIntegrator {
+    pub fn new() -> WhittedIntegrator {
+        WhittedIntegrator {}
+    }
+
+    fn trace_ray(
+        &self,
+        scene: &Scene,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        depth: u32,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let intersection = scene.intersect(ray);
+        if let Some(emitted) = visible_light(scene, ray, &intersection) {
+            return emitted;
+        }
+        let intersection = match intersection {
+            Some(intersection) => intersection,
+            None => return scene.background(ray, None),
+        };
+
+        let material = &intersection.material;
+        let mut scattered = Ray3::default();
+        let mut attenuation = Color::black();
+        if !material.scatter(
+            &mut trace_context.clone(),
+            ray,
+            &intersection,
+            rng,
+            &mut attenuation,
+            &mut scattered,
+        ) {
+            return attenuation;
+        }
+
+        if material.pdf(ray, &intersection, &scattered.direction) > 0.0 {
+            let light_origin = intersection.point + intersection.normal * 0.01;
+            let direct =
+                scene.direct_lighting(&light_origin, trace_context, rng, false, &|direction| {
+                    (material.evaluate(ray, &intersection, direction), 0.0)
+                });
+            direct + scene.ambient_color * attenuation
+        } else if depth < scene.max_trace_depth {
+            self.trace_ray(scene, &trace_context.next_dimension(), &scattered, depth + 1, rng) * attenuation
+        } else {
+            Color::black()
+        }
+    }
+}
+
+/// Ambient occlusion: the fraction of the hemisphere above the first hit point that is not blocked
+/// within `max_distance`.
+#[derive(Clone, Debug)]
+pub struct AmbientOcclusionIntegrator {
+    max_distance: f64,
+    num_samples: u32,
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        _: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let intersection = match scene.intersect(ray) {
+            Some(intersection) => intersection,
+            None => return Color::white(),
+        };
+
+        let w = if intersection.normal.dot(&ray.direction) > 0.0 {
+            -intersection.normal
+        } else {
+            intersection.normal
+        };
+        let origin = intersection.point + w * 0.01;
+
+        let mut unoccluded = 0;
+        for _ in 0..self.num_samples {
+            // Cosine weighted directions, so every unblocked direction counts the same.
+            let direction = to_world(&cosine_hemisphere(Vector2::new(rng.gen(), rng.gen())), &w);
+            let occlusion_ray = Ray3::new(origin, direction);
+            stats::count_shadow_ray();
+            let blocked = scene
+                .objects
+                .iter()
+                .any(|o| o.intersect(&occlusion_ray).is_some_and(|hit| hit.t < self.max_distance));
+            if !blocked {
+                unoccluded += 1;
+            }
+        }
+
+        Color::white() * (unoccluded as f64 / self.num_samples as f64)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "ambient_occlusion", self)
+    }
+}
+
+impl Serialize for AmbientOcclusionIntegrator {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.max_distance.write_to(out)?;
+        self.num_samples.write_to(out)
+    }
+}
+
+impl Deserialize for AmbientOcclusionIntegrator {
+    fn read_from(input: &mut InputStream) -> Result<AmbientOcclusionIntegrator> {
+        let max_distance = f64::read_from(input)?;
+        let num_samples = u32::read_from(input)?;
+        if num_samples == 0 {
+            return Err(invalid_data("Invalid number of samples"));
+        }
+        Ok(AmbientOcclusionIntegrator::new(max_distance, num_samples))
+    }
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new(max_distance: f64, num_samples: u32) -> AmbientOcclusionIntegrator {
+        expect_neq!(num_samples, 0);
+        AmbientOcclusionIntegrator {
+            max_distance,
+            num_samples,
+        }
+    }
+}
+
+/// Direct lighting only: light arriving at the first hit point straight from the lights, without any
+/// interreflections. Rays that leave the scene return the background.
+#[derive(Clone, Debug, Default)]
+pub struct DirectLightingIntegrator {}
+
+impl Integrator for DirectLightingIntegrator {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let intersection = scene.intersect(ray);
+        if let Some(emitted) = visible_light(scene, ray, &intersection) {
+            return emitted;
+        }
+        let intersection = match intersection {
+            Some(intersection) => intersection,
+            None => return scene.background(ray, None),
+        };
+
+        let material = &intersection.material;
+        let light_origin = intersection.point + intersection.normal * 0.01;
+        scene.direct_lighting(&light_origin, trace_context, rng, false, &|direction| {
+            (material.evaluate(ray, &intersection, direction), 0.0)
+        })
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        "direct".write_to(out)
+    }
+}
+
+impl DirectLightingIntegrator {
+    pub fn new() -> DirectLightingIntegrator {
+        DirectLightingIntegrator {}
+    }
+}
+
+/// Returns the radiance of the light surface hit by `ray` if it is in front of `intersection`.
+fn visible_light(scene: &Scene, ray: &Ray3, intersection: &Option<Intersection>) -> Option<Color> {
+    let t_surface = intersection.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+    scene
+        .intersect_light(ray)
+        .filter(|hit| hit.0.t < t_surface)
+        .map(|hit| hit.0.radiance)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugChannel {
+    /// Absolute value of the surface normal as color.
+    Normals,
+    /// Distance to the first hit, black at the camera and white at `max_distance` and beyond.
+    Depth { max_distance: f64 },
+    /// Surface coordinates in the red and green channel, wrapped to [0, 1).
+    Uvs,
+    /// A distinct color per material, derived from its parameters so that it is the same in every run.
+    MaterialIds,
+}
+
+/// Visualises a property of the first surface hit by each camera ray. Misses are black.
+#[derive(Clone, Debug)]
+pub struct DebugIntegrator {
+    channel: DebugChannel,
+}
+
+impl Integrator for DebugIntegrator {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        _: &TraceContext,
+        ray: &Ray3,
+        _: &mut dyn RngCore,
+    ) -> Color {
+        let intersection = match scene.intersect(ray) {
+            Some(intersection) => intersection,
+            None => return Color::black(),
+        };
+
+        match self.channel {
+            DebugChannel::Normals => Color {
+                r: intersection.normal.x.abs(),
+                g: intersection.normal.y.abs(),
+                b: intersection.normal.z.abs(),
+                a: 1.0,
+            },
+            DebugChannel::Depth { max_distance } => {
+                let distance = intersection.t * ray.direction.length();
+                Color::white() * (distance / max_distance).min(1.0)
+            }
+            DebugChannel::Uvs => Color {
+                r: intersection.uv.x - intersection.uv.x.floor(),
+                g: intersection.uv.y - intersection.uv.y.floor(),
+                b: 0.0,
+                a: 1.0,
+            },
+            DebugChannel::MaterialIds => {
+                // Scramble the id so that similar materials get clearly different colors.
+                let hash =
+                    material_id(intersection.material.as_ref()).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                Color {
+                    r: ((hash >> 40) & 0xff) as f64 / 255.0,
+                    g: ((hash >> 48) & 0xff) as f64 / 255.0,
+                    b: ((hash >> 56) & 0xff) as f64 / 255.0,
+                    a: 1.0,
+                }
+            }
+        }
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "debug", self)
+    }
+}
+
+impl Serialize for DebugIntegrator {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        match self.channel {
+            DebugChannel::Normals => "normals".write_to(out),
+            DebugChannel::Depth { max_distance } => write_tagged(out, "depth", &max_distance),
+            DebugChannel::Uvs => "uvs".write_to(out),
+            DebugChannel::MaterialIds => "material_ids".write_to(out),
+        }
+    }
+}
+
+impl Deserialize for DebugIntegrator {
+    fn read_from(input: &mut InputStream) -> Result<DebugIntegrator> {
+        let channel = match String::read_from(input)?.as_str() {
+            "normals" => DebugChannel::Normals,
+            "depth" => DebugChannel::Depth {
+                max_distance: f64::read_from(input)?,
+            },
+            "uvs" => DebugChannel::Uvs,
+            "material_ids" => DebugChannel::MaterialIds,
+            _ => return Err(invalid_data("Unknown debug channel")),
+        };
+        Ok(DebugIntegrator::new(channel))
+    }
+}
+
+impl DebugIntegrator {
+    pub fn new(channel: DebugChannel) -> DebugIntegrator {
+        DebugIntegrator { channel }
+    }
+}
+
+/// An id of `material` that is the same in every run and on every render worker: the FNV-1a hash of its
+/// serialization. Materials that cannot be serialized share one id.
+fn material_id(material: &dyn Material) -> u64 {
+    let hash = Arc::new(AtomicU64::new(0xcbf2_9ce4_8422_2325));
+    let mut out = OutputStream::from_writer(HashWriter(Arc::clone(&hash)));
+    match material.serialize(&mut out).and_then(|_| out.flush()) {
+        Ok(()) => hash.load(Ordering::Relaxed),
+        Err(_) => 0,
+    }
+}
+
+/// Hashes the bytes written to it with FNV-1a.
+struct HashWriter(Arc<AtomicU64>);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut hash = self.0.load(Ordering::Relaxed);
+        for byte in buf {
+            hash = (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        self.0.store(hash, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Camera, PinholeCamera};
+    use crate::material::{ComplexRefractiveIndex, Lambertian, Metal, NullMaterial};
+    use crate::math::Vector3;
+    use crate::sampler::HemiSphereSampler;
+    use crate::shapes::{Hitable, Plane, Sphere};
+    use crate::spectrum::SampledWavelengths;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn scene(objects: Vec<Arc<dyn Hitable>>) -> Scene {
+        Scene {
+            objects: Arc::new(objects),
+            lights: Vec::new(),
+            atmosphere: None,
+            ambient_color: Color::white(),
+            max_trace_depth: 4,
+        }
+    }
+
+    fn film() -> Film {
+        let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+            &Vector3::new(0.0, 1.0, 0.0),
+            &Vector3::zero(),
+            &Vector3::new(0.0, 0.0, 1.0),
+            1.0,
+        ));
+        Film::new(4, 4, 0.1, &camera)
+    }
+
+    fn context() -> TraceContext {
+        TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        }
+    }
+
+    #[test]
+    fn ambient_occlusion_of_open_plane_is_one() {
+        let scene = scene(vec![Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(NullMaterial::new()),
+        })]);
+        let ray = Ray3::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let ao = AmbientOcclusionIntegrator::new(10.0, 16);
+        let c = ao.radiance(&scene, &film(), &context(), &ray, &mut StdRng::seed_from_u64(1));
+        assert_close!(1.0, c.r);
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        let scene = scene(vec![Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::regular_sampler(1, 1.0),
+                &(Color::white() * 0.5),
+            )),
+        })]);
+        let ray = Ray3::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let path_tracer = PathTracer::with_russian_roulette_depth(0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 4000;
+        let mean = (0..n)
+            .map(|_| path_tracer.radiance(&scene, &film(), &context(), &ray, &mut rng).g)
+            .sum::<f64>()
+            / n as f64;
+        assert!((mean - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn spectral_paths_carry_the_reflectance_at_every_wavelength() {
+        let scene = scene(vec![Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Metal::conductor(&ComplexRefractiveIndex::gold(), 0.0)),
+        })]);
+        let ray = Ray3::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let path_tracer = PathTracer::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 1000;
+        let mut sum = Color::black();
+        for i in 0..n {
+            let mut context = TraceContext {
+                wavelengths: Some(SampledWavelengths::sample((i as f64 + 0.5) / n as f64)),
+                ..context()
+            };
+            let radiance = path_tracer
+                .spectral_radiance(&scene, &film(), &mut context, &ray, &mut rng)
+                .unwrap();
+            sum += context.wavelengths.unwrap().to_rgb(&radiance);
+        }
+        let spectral = sum * (1.0 / n as f64);
+        let rgb = path_tracer.radiance(&scene, &film(), &context(), &ray, &mut rng);
+
+        // Gold reflects red and absorbs blue, in both modes.
+        assert!(spectral.r > spectral.g && spectral.g > spectral.b);
+        assert!((spectral.r - rgb.r).abs() < 0.15);
+        assert!((spectral.b - rgb.b).abs() < 0.15);
+    }
+
+    #[test]
+    fn material_ids_depend_on_the_parameters_only() {
+        let sampler = HemiSphereSampler::regular_sampler(1, 1.0);
+        let grey = || Lambertian::new(&sampler, &(Color::white() * 0.5));
+        assert_eq!(material_id(&grey()), material_id(&grey()));
+        assert_ne!(
+            material_id(&grey()),
+            material_id(&Lambertian::new(&sampler, &Color::white()))
+        );
+    }
+
+    #[test]
+    fn debug_normals_of_sphere_facing_camera() {
+        let scene = scene(vec![Arc::new(Sphere {
+            center: Vector3::zero(),
+            radius: 1.0,
+            material: Arc::new(NullMaterial::new()),
+        })]);
+        let ray = Ray3::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        let debug = DebugIntegrator::new(DebugChannel::Normals);
+        let c = debug.radiance(&scene, &film(), &context(), &ray, &mut StdRng::seed_from_u64(1));
+        assert_close!(Vector3::new(0.0, 0.0, 1.0), Vector3::new(c.r, c.g, c.b));
+    }
+}
@@ -1,12 +1,17 @@
 use std::convert::AsRef;
 use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
 use std::io::Result;
 use std::io::Write;
 use std::path::Path;
 
 pub struct OutputStream {
-    writer: Box<dyn Write>,
+    writer: Box<dyn Write + Send>,
 }
 
 impl OutputStream {
@@ -18,6 +23,13 @@ impl OutputStream {
         })
     }
 
+    /// Buffers the writes to `writer`, e.g. a network connection.
+    pub fn from_writer<W: Write + Send + 'static>(writer: W) -> OutputStream {
+        OutputStream {
+            writer: Box::new(BufWriter::new(writer)),
+        }
+    }
+
     pub fn write(&mut self, array: &[u8]) -> Result<()> {
         self.writer.write_all(array)
     }
@@ -35,4 +47,109 @@ impl OutputStream {
             ((value >> 24) & 0xff) as u8,
         ])
     }
+
+    pub fn write_u64_le(&mut self, value: u64) -> Result<()> {
+        self.writer.write_all(&value.to_le_bytes())
+    }
+
+    pub fn write_f64_le(&mut self, value: f64) -> Result<()> {
+        self.writer.write_all(&value.to_le_bytes())
+    }
+
+    /// Writes all buffered data to the file. Dropping the stream flushes too, but ignores errors.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub struct InputStream {
+    reader: Box<dyn BufRead + Send>,
+}
+
+impl InputStream {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<InputStream> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        Ok(InputStream {
+            reader: Box::new(reader),
+        })
+    }
+
+    /// Buffers the reads from `reader`, e.g. a network connection.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> InputStream {
+        InputStream {
+            reader: Box::new(BufReader::new(reader)),
+        }
+    }
+
+    pub fn read(&mut self, array: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        let mut buffer = [0u8; 1];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    pub fn read_u16_le(&mut self) -> Result<u16> {
+        let mut buffer = [0u8; 2];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(u16::from_le_bytes(buffer))
+    }
+
+    pub fn read_u32_le(&mut self) -> Result<u32> {
+        let mut buffer = [0u8; 4];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(u32::from_le_bytes(buffer))
+    }
+
+    pub fn read_u64_le(&mut self) -> Result<u64> {
+        let mut buffer = [0u8; 8];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    pub fn read_f64_le(&mut self) -> Result<f64> {
+        let mut buffer = [0u8; 8];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(f64::from_le_bytes(buffer))
+    }
+
+    pub fn read_f32(&mut self, little_endian: bool) -> Result<f32> {
+        let mut buffer = [0u8; 4];
+        self.reader.read_exact(&mut buffer)?;
+        if little_endian {
+            Ok(f32::from_le_bytes(buffer))
+        } else {
+            Ok(f32::from_be_bytes(buffer))
+        }
+    }
+
+    /// Reads a line of text without the trailing newline.
+    pub fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of file"));
+        }
+        while line.ends_with('\n') || line.ends_with('\r') {
+            line.pop();
+        }
+        Ok(line)
+    }
+
+    /// Reads whitespace-separated tokens up to and including the next single whitespace character.
+    pub fn read_token(&mut self) -> Result<String> {
+        let mut token = String::new();
+        loop {
+            let c = self.read_u8()? as char;
+            if c.is_whitespace() {
+                if token.is_empty() {
+                    continue;
+                }
+                return Ok(token);
+            }
+            token.push(c);
+        }
+    }
 }
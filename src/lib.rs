@@ -7,32 +7,70 @@ extern crate rand;
 #[macro_use]
 mod macros;
 
+pub mod bdpt;
 pub mod camera;
 pub mod color;
+pub mod crop;
+pub mod film;
+pub mod filter;
+pub mod integrator;
 pub mod io;
+pub mod light;
 pub mod material;
 pub mod math;
+pub mod medium;
+pub mod mlt;
+pub mod photon;
+pub mod progress;
 pub mod sampler;
+pub mod scene;
+pub mod serialize;
 pub mod shapes;
+pub mod sky;
+pub mod spectrum;
+pub mod stats;
+pub mod tile;
 pub mod trace;
+pub mod warp;
+
+use std::io::{Error, ErrorKind};
 
 use self::color::Color;
-use self::io::OutputStream;
+use self::io::{InputStream, OutputStream};
+use self::spectrum::SampledWavelengths;
 
 #[derive(Clone, Debug)]
 pub struct TraceContext {
     pub set_index: usize,
     pub sample_index: usize,
+    /// The dimension of the path the samples are drawn for. Camera samples take dimension 0 and every
+    /// scattering event along the path the next one, so that bounces do not reuse the same samples.
+    pub dimension: usize,
+    /// The wavelengths traced by the path in spectral mode.
+    pub wavelengths: Option<SampledWavelengths>,
+}
+
+impl TraceContext {
+    /// The context of the next scattering event along the same path.
+    pub fn next_dimension(&self) -> TraceContext {
+        TraceContext {
+            dimension: self.dimension + 1,
+            ..self.clone()
+        }
+    }
 }
 
 /// A 2-dimensional pixel buffer.
 /// The x coordinate goes from 0 to width (exclusive), from left to right.
 /// The y coordinate goes from 0 to height (exclusive), from top to bottom.
+/// Every pixel holds a weighted sum of samples and the sum of their weights, and its color is their quotient.
+/// Pixels that are set directly have a weight of one.
 #[derive(Clone, Debug)]
 pub struct RenderBuffer {
     width: u32,
     height: u32,
     pixels: Vec<Color>,
+    weights: Vec<f64>,
 }
 
 impl RenderBuffer {
@@ -55,15 +93,69 @@ impl RenderBuffer {
             width: width,
             height: height,
             pixels: pixels,
+            weights: vec![0.0; (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Color {
+        expect_lt!(x, self.width);
+        expect_lt!(y, self.height);
+
+        let pos = (y * self.width + x) as usize;
+        let weight = self.weights[pos];
+        if weight == 0.0 {
+            self.pixels[pos]
+        } else {
+            self.pixels[pos] * (1.0 / weight)
         }
     }
 
+    /// Returns the weighted sum of the samples of a pixel.
+    pub fn get_weighted_sum(&self, x: u32, y: u32) -> Color {
+        expect_lt!(x, self.width);
+        expect_lt!(y, self.height);
+
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Returns the sum of the weights of the samples of a pixel.
+    pub fn get_weight(&self, x: u32, y: u32) -> f64 {
+        expect_lt!(x, self.width);
+        expect_lt!(y, self.height);
+
+        self.weights[(y * self.width + x) as usize]
+    }
+
     pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
         expect_lt!(x, self.width);
         expect_lt!(y, self.height);
 
         let pos = (y * self.width + x) as usize;
         self.pixels[pos] = color;
+        self.weights[pos] = 1.0;
+    }
+
+    /// Adds a sample with the given reconstruction filter weight to a pixel.
+    pub fn add_sample(&mut self, x: u32, y: u32, color: &Color, weight: f64) {
+        self.add_weighted_sum(x, y, &(*color * weight), weight);
+    }
+
+    /// Adds a weighted sum of samples and the sum of their weights to a pixel.
+    pub fn add_weighted_sum(&mut self, x: u32, y: u32, weighted_sum: &Color, weight: f64) {
+        expect_lt!(x, self.width);
+        expect_lt!(y, self.height);
+
+        let pos = (y * self.width + x) as usize;
+        self.pixels[pos] += *weighted_sum;
+        self.weights[pos] += weight;
     }
 
     pub fn set_pixel_line(&mut self, y: u32, pixels: &Vec<Color>) {
@@ -71,12 +163,13 @@ impl RenderBuffer {
         expect_eq!(pixels.len(), self.width as usize);
         let pos = (y * self.width) as usize;
         self.pixels[pos..(pos + self.width as usize)].clone_from_slice(pixels);
+        self.weights[pos..(pos + self.width as usize)].fill(1.0);
     }
 
     pub fn write_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
         let mut out = OutputStream::new(path)?;
 
-        let row_padding = (4 - self.width % 4) % 4;
+        let row_padding = (4 - self.width * 3 % 4) % 4;
         let header_size = 14 + 40;
         let image_size = (self.width * 3 + row_padding) * self.height;
         let file_size = header_size + image_size;
@@ -105,19 +198,306 @@ impl RenderBuffer {
 
         // Pixels are written in rows, starting from bottom-left.
         for y in 0..self.height {
-            let mut index = ((self.height - y - 1) * self.width) as usize;
-            for _ in 0..self.width {
-                let rgb = self.pixels[index].to_rgba32();
+            for x in 0..self.width {
+                let rgb = self.get_pixel(x, self.height - y - 1).to_rgba32();
                 out.write(&[
                     ((rgb >> 16) & 0xff) as u8,
                     ((rgb >> 8) & 0xff) as u8,
                     (rgb & 0xff) as u8,
                 ])?;
-                index += 1;
             }
             out.write(padding.as_slice())?;
         }
 
         Ok(())
     }
+
+    /// Reads an image. Supported are Radiance RGBE (.hdr) and portable float map (.pfm) files, and uncompressed
+    /// 24-bit bitmaps (.bmp) like those written by `write_to_file`.
+    pub fn read_from_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<RenderBuffer> {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hdr") => read_hdr(&mut InputStream::new(path)?),
+            Some("pfm") => read_pfm(&mut InputStream::new(path)?),
+            Some("bmp") => read_bmp(&mut InputStream::new(path)?),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Unsupported image format")),
+        }
+    }
+}
+
+fn read_hdr(input: &mut InputStream) -> std::io::Result<RenderBuffer> {
+    if !input.read_line()?.starts_with("#?") {
+        return Err(invalid_data("Missing Radiance header"));
+    }
+    loop {
+        let line = input.read_line()?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid_data("Unsupported Radiance pixel format"));
+        }
+    }
+
+    // Only the standard orientation with rows from top to bottom and columns from left to right is supported.
+    let resolution = input.read_line()?;
+    let tokens: Vec<&str> = resolution.split_whitespace().collect();
+    if tokens.len() != 4 || tokens[0] != "-Y" || tokens[2] != "+X" {
+        return Err(invalid_data("Unsupported Radiance resolution string"));
+    }
+    let height = parse_dimension(tokens[1])?;
+    let width = parse_dimension(tokens[3])?;
+
+    let mut buffer = RenderBuffer::new(width, height);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+
+    for y in 0..height {
+        read_hdr_scanline(input, &mut scanline)?;
+        for (x, rgbe) in scanline.iter().enumerate() {
+            buffer.set_pixel(x as u32, y, rgbe_to_color(rgbe));
+        }
+    }
+
+    Ok(buffer)
+}
+
+fn read_hdr_scanline(input: &mut InputStream, scanline: &mut [[u8; 4]]) -> std::io::Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    input.read(&mut first)?;
+
+    if !(8..0x8000).contains(&width) || first[0] != 2 || first[1] != 2 || first[2] & 0x80 != 0 {
+        scanline[0] = first;
+        for rgbe in scanline.iter_mut().skip(1) {
+            input.read(rgbe)?;
+        }
+        return Ok(());
+    }
+
+    if ((first[2] as usize) << 8 | first[3] as usize) != width {
+        return Err(invalid_data("Invalid Radiance scanline width"));
+    }
+
+    // Run-length encoded scanlines store each of the four components separately.
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = input.read_u8()? as usize;
+            if count > 128 {
+                let run = count - 128;
+                if x + run > width {
+                    return Err(invalid_data("Invalid Radiance run length"));
+                }
+                let value = input.read_u8()?;
+                for rgbe in scanline[x..(x + run)].iter_mut() {
+                    rgbe[channel] = value;
+                }
+                x += run;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(invalid_data("Invalid Radiance run length"));
+                }
+                for rgbe in scanline[x..(x + count)].iter_mut() {
+                    rgbe[channel] = input.read_u8()?;
+                }
+                x += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe_to_color(rgbe: &[u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::black();
+    }
+    let f = 2.0f64.powi(rgbe[3] as i32 - 136);
+    Color {
+        r: (rgbe[0] as f64 + 0.5) * f,
+        g: (rgbe[1] as f64 + 0.5) * f,
+        b: (rgbe[2] as f64 + 0.5) * f,
+        a: 1.0,
+    }
+}
+
+fn read_pfm(input: &mut InputStream) -> std::io::Result<RenderBuffer> {
+    let channels = match input.read_token()?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid_data("Missing PFM header")),
+    };
+    let width = parse_dimension(&input.read_token()?)?;
+    let height = parse_dimension(&input.read_token()?)?;
+    let scale: f64 = input
+        .read_token()?
+        .parse()
+        .map_err(|_| invalid_data("Invalid PFM scale"))?;
+    let little_endian = scale < 0.0;
+
+    let mut buffer = RenderBuffer::new(width, height);
+    let mut values = [0.0f64; 3];
+
+    // Rows are stored from bottom to top.
+    for row in 0..height {
+        let y = height - row - 1;
+        for x in 0..width {
+            for value in values.iter_mut().take(channels) {
+                *value = input.read_f32(little_endian)? as f64;
+            }
+            let color = if channels == 3 {
+                Color {
+                    r: values[0],
+                    g: values[1],
+                    b: values[2],
+                    a: 1.0,
+                }
+            } else {
+                Color {
+                    r: values[0],
+                    g: values[0],
+                    b: values[0],
+                    a: 1.0,
+                }
+            };
+            buffer.set_pixel(x, y, color);
+        }
+    }
+
+    Ok(buffer)
+}
+
+fn read_bmp(input: &mut InputStream) -> std::io::Result<RenderBuffer> {
+    let mut magic = [0u8; 2];
+    input.read(&mut magic)?;
+    if &magic != b"BM" {
+        return Err(invalid_data("Missing bitmap header"));
+    }
+    input.read_u32_le()?;
+    input.read_u32_le()?;
+    let pixel_offset = input.read_u32_le()?;
+
+    let info_size = input.read_u32_le()?;
+    let width = input.read_u32_le()? as i32;
+    let height = input.read_u32_le()? as i32;
+    input.read_u16_le()?;
+    let bits_per_pixel = input.read_u16_le()?;
+    let compression = input.read_u32_le()?;
+    if info_size < 40 || pixel_offset < 14 + info_size || bits_per_pixel != 24 || compression != 0 {
+        return Err(invalid_data("Unsupported bitmap format"));
+    }
+    if width <= 0 || height == 0 {
+        return Err(invalid_data("Invalid image dimension"));
+    }
+    // Skips the rest of the info header, and whatever lies between it and the pixels.
+    let mut skipped = vec![0u8; (pixel_offset - 14 - 20) as usize];
+    input.read(&mut skipped)?;
+
+    let width = width as u32;
+    let rows = height.unsigned_abs();
+    let mut buffer = RenderBuffer::new(width, rows);
+    let mut row = vec![0u8; ((width * 3).div_ceil(4) * 4) as usize];
+
+    // Rows are stored from bottom to top, unless the height is negative. Every byte stands for the middle
+    // of its interval, so that writing the image again gives the same bytes.
+    for i in 0..rows {
+        let y = if height > 0 { rows - i - 1 } else { i };
+        input.read(&mut row)?;
+        for x in 0..width {
+            let bgr = &row[(x * 3) as usize..(x * 3 + 3) as usize];
+            let channel = |byte: u8| (byte as f64 + 0.5) / 255.0;
+            let color = Color {
+                r: channel(bgr[2]),
+                g: channel(bgr[1]),
+                b: channel(bgr[0]),
+                a: 1.0,
+            };
+            buffer.set_pixel(x, y, color);
+        }
+    }
+
+    Ok(buffer)
+}
+
+fn parse_dimension(token: &str) -> std::io::Result<u32> {
+    token
+        .parse::<u32>()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| invalid_data("Invalid image dimension"))
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn read_pfm_flips_rows() {
+        let path = std::env::temp_dir().join("ard_read_pfm_flips_rows.pfm");
+        let mut data = b"PF\n1 2\n-1.0\n".to_vec();
+        for value in [0.25f32, 0.5, 1.0, 2.0, 4.0, 8.0].iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let buffer = RenderBuffer::read_from_file(&path).unwrap();
+        assert_eq!(1, buffer.width());
+        assert_eq!(2, buffer.height());
+        assert_close!(2.0, buffer.get_pixel(0, 0).r);
+        assert_close!(0.25, buffer.get_pixel(0, 1).r);
+        assert_close!(1.0, buffer.get_pixel(0, 1).b);
+    }
+
+    #[test]
+    fn bitmaps_read_back_as_written() {
+        let path = std::env::temp_dir().join("ard_bitmaps_read_back_as_written.bmp");
+        let mut buffer = RenderBuffer::new(3, 2);
+        buffer.set_pixel(0, 0, Color::white());
+        let blue = Color {
+            r: 0.1,
+            g: 0.5,
+            b: 0.9,
+            a: 1.0,
+        };
+        buffer.set_pixel(2, 0, blue);
+        buffer.set_pixel(1, 1, Color::white() * 2.0);
+        buffer.write_to_file(&path).unwrap();
+
+        let read = RenderBuffer::read_from_file(&path).unwrap();
+        assert_eq!((3, 2), (read.width(), read.height()));
+        for y in 0..2 {
+            for x in 0..3 {
+                let written = buffer.get_pixel(x, y).to_rgba32() & 0xffffff;
+                assert_eq!(written, read.get_pixel(x, y).to_rgba32() & 0xffffff);
+            }
+        }
+    }
+
+    #[test]
+    fn read_hdr_decodes_run_length_encoded_scanlines() {
+        let path = std::env::temp_dir().join("ard_read_hdr_rle.hdr");
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        data.extend_from_slice(&[136, 128]);
+        data.extend_from_slice(&[136, 0]);
+        data.extend_from_slice(&[136, 64]);
+        data.extend_from_slice(&[136, 129]);
+        std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let buffer = RenderBuffer::read_from_file(&path).unwrap();
+        assert_eq!(8, buffer.width());
+        let pixel = buffer.get_pixel(7, 0);
+        assert_close!(1.00390625, pixel.r);
+        assert_close!(0.00390625, pixel.g);
+        assert_close!(0.50390625, pixel.b);
+    }
 }
@@ -0,0 +1,412 @@
+use std::f64::consts::PI;
+use std::io::Result;
+use std::path::Path;
+
+use crate::color::Color;
+use crate::io::{InputStream, OutputStream};
+use crate::math::{Matrix4, Ray3, Vector2, Vector3};
+use crate::sampler::Distribution2D;
+use crate::serialize::{unsupported, write_tagged, Deserialize, Serialize};
+use crate::warp::{
+    cosine_hemisphere, cosine_hemisphere_pdf, to_world, uniform_cone, uniform_cone_pdf, uniform_sphere,
+    uniform_sphere_pdf,
+};
+use crate::RenderBuffer;
+
+/// A direction towards a light sampled from a point in the scene.
+#[derive(Clone, Copy, Debug)]
+pub struct LightSample {
+    pub direction: Vector3,
+    pub radiance: Color,
+    /// Density with respect to solid angle.
+    pub pdf: f64,
+    /// Distance to the light along `direction`, infinite for lights at infinity.
+    pub distance: f64,
+    /// Surface normal of the light at the sampled point.
+    pub normal: Vector3,
+}
+
+/// A ray hitting the surface of a light.
+#[derive(Clone, Copy, Debug)]
+pub struct LightHit {
+    pub t: f64,
+    pub normal: Vector3,
+    /// Radiance emitted back along the ray.
+    pub radiance: Color,
+}
+
+/// A ray leaving the surface of a light, used to start paths at the light.
+#[derive(Clone, Copy, Debug)]
+pub struct EmissionSample {
+    pub ray: Ray3,
+    pub normal: Vector3,
+    pub radiance: Color,
+    /// Density of the ray origin with respect to surface area.
+    pub pdf_position: f64,
+    /// Density of the ray direction with respect to solid angle.
+    pub pdf_direction: f64,
+}
+
+pub trait Light: Send + Sync {
+    /// Samples a direction from `point` towards the light using the uniform sample `u`.
+    fn sample(&self, point: &Vector3, u: Vector2) -> Option<LightSample>;
+
+    /// Returns the density with respect to solid angle with which `sample` picks `direction` from `point`.
+    fn pdf(&self, point: &Vector3, direction: &Vector3) -> f64;
+
+    /// Lights at infinity surround the whole scene and are only reached by rays leaving it.
+    fn is_infinite(&self) -> bool;
+
+    /// Returns the radiance arriving along a ray in `direction` that leaves the scene without hitting anything.
+    fn environment(&self, _direction: &Vector3) -> Color {
+        Color::default()
+    }
+
+    /// Intersects `ray` with the emitting surface of the light. Lights at infinity have no surface.
+    fn hit(&self, _ray: &Ray3) -> Option<LightHit> {
+        None
+    }
+
+    /// Samples a ray leaving the light from the uniform samples for its origin and direction.
+    fn sample_emission(&self, _u_position: Vector2, _u_direction: Vector2) -> Option<EmissionSample> {
+        None
+    }
+
+    /// Returns the densities of the origin and direction with which `sample_emission` produces a ray leaving
+    /// `point` with surface normal `normal` in `direction`.
+    fn pdf_emission(&self, _point: &Vector3, _normal: &Vector3, _direction: &Vector3) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+
+    /// Writes the type and the fields of the light, e.g. to ship the scene to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("light"))
+    }
+}
+
+/// A light at infinity defined by an equirectangular (latitude-longitude) image.
+/// The image centre looks along the negative z axis and the top row is straight up along the y axis.
+/// Directions are importance sampled proportional to the luminance of the image.
+#[derive(Clone, Debug)]
+pub struct EnvironmentLight {
+    image: RenderBuffer,
+    rotation: f64,
+    intensity: f64,
+    to_local: Matrix4,
+    to_world: Matrix4,
+    distribution: Distribution2D,
+}
+
+impl Light for EnvironmentLight {
+    fn sample(&self, _: &Vector3, u: Vector2) -> Option<LightSample> {
+        let (uv, map_pdf) = self.distribution.sample_continuous(u);
+        if map_pdf <= 0.0 {
+            return None;
+        }
+
+        let theta = uv.y * PI;
+        let sin_theta = theta.sin();
+        if sin_theta <= 0.0 {
+            return None;
+        }
+
+        let direction = self.to_world.transform_vector3(uv_to_direction(uv));
+        Some(LightSample {
+            direction,
+            radiance: self.lookup(uv),
+            pdf: map_pdf / (2.0 * PI * PI * sin_theta),
+            distance: f64::INFINITY,
+            normal: -direction,
+        })
+    }
+
+    fn pdf(&self, _: &Vector3, direction: &Vector3) -> f64 {
+        let uv = direction_to_uv(&self.to_local.transform_vector3(*direction));
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+
+    fn environment(&self, direction: &Vector3) -> Color {
+        self.lookup(direction_to_uv(&self.to_local.transform_vector3(*direction)))
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "environment", self)
+    }
+}
+
+impl Serialize for EnvironmentLight {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.image.write_to(out)?;
+        self.rotation.write_to(out)?;
+        self.intensity.write_to(out)
+    }
+}
+
+impl Deserialize for EnvironmentLight {
+    fn read_from(input: &mut InputStream) -> Result<EnvironmentLight> {
+        let image = RenderBuffer::read_from(input)?;
+        let rotation = f64::read_from(input)?;
+        let intensity = f64::read_from(input)?;
+        Ok(EnvironmentLight::new(image, rotation, intensity))
+    }
+}
+
+impl EnvironmentLight {
+    /// Creates an environment light from `image`, rotated by `rotation` radians around the y axis
+    /// and with its radiance scaled by `intensity`.
+    pub fn new(image: RenderBuffer, rotation: f64, intensity: f64) -> EnvironmentLight {
+        let width = image.width() as usize;
+        let height = image.height() as usize;
+        let mut function = Vec::with_capacity(width * height);
+
+        // Rows near the poles cover less solid angle, so their pixels are weighted by sin(theta).
+        for y in 0..height {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                let c = image.get_pixel(x as u32, y as u32);
+                function.push(luminance(&c) * sin_theta);
+            }
+        }
+
+        EnvironmentLight {
+            distribution: Distribution2D::new(&function, width, height),
+            image,
+            rotation,
+            intensity,
+            to_local: Matrix4::rotation_y(-rotation),
+            to_world: Matrix4::rotation_y(rotation),
+        }
+    }
+
+    /// Loads an environment light from an equirectangular .hdr or .pfm image.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        rotation: f64,
+        intensity: f64,
+    ) -> Result<EnvironmentLight> {
+        Ok(EnvironmentLight::new(
+            RenderBuffer::read_from_file(path)?,
+            rotation,
+            intensity,
+        ))
+    }
+
+    fn lookup(&self, uv: Vector2) -> Color {
+        let width = self.image.width();
+        let height = self.image.height();
+        let x = ((uv.x * width as f64) as u32).min(width - 1);
+        let y = ((uv.y * height as f64) as u32).min(height - 1);
+        let c = self.image.get_pixel(x, y);
+        Color {
+            r: c.r * self.intensity,
+            g: c.g * self.intensity,
+            b: c.b * self.intensity,
+            a: 1.0,
+        }
+    }
+}
+
+/// A spherical light emitting the same radiance from every point of its surface in every direction.
+#[derive(Clone, Debug)]
+pub struct SphereLight {
+    pub center: Vector3,
+    pub radius: f64,
+    pub radiance: Color,
+}
+
+impl Light for SphereLight {
+    fn sample(&self, point: &Vector3, u: Vector2) -> Option<LightSample> {
+        // Directions are sampled uniformly in the cone of directions covered by the sphere.
+        let to_center = self.center - *point;
+        let distance_squared = to_center.length_squared();
+        if distance_squared <= self.radius * self.radius {
+            return None;
+        }
+
+        let distance = distance_squared.sqrt();
+        let sin_max_squared = self.radius * self.radius / distance_squared;
+        let cos_max = (1.0 - sin_max_squared).max(0.0).sqrt();
+        let direction = to_world(&uniform_cone(u, cos_max), &(to_center / distance));
+
+        let hit = self.hit(&Ray3::new(*point, direction))?;
+
+        Some(LightSample {
+            direction,
+            radiance: hit.radiance,
+            pdf: uniform_cone_pdf(cos_max),
+            distance: hit.t,
+            normal: hit.normal,
+        })
+    }
+
+    fn pdf(&self, point: &Vector3, direction: &Vector3) -> f64 {
+        let distance_squared = (self.center - *point).length_squared();
+        if distance_squared <= self.radius * self.radius
+            || self.hit(&Ray3::new(*point, direction.normalized())).is_none()
+        {
+            return 0.0;
+        }
+        let cos_max = (1.0 - self.radius * self.radius / distance_squared).max(0.0).sqrt();
+        uniform_cone_pdf(cos_max)
+    }
+
+    fn is_infinite(&self) -> bool {
+        false
+    }
+
+    fn hit(&self, ray: &Ray3) -> Option<LightHit> {
+        let v = ray.origin - self.center;
+        let a = ray.direction.dot(&ray.direction);
+        let b = 2.0 * v.dot(&ray.direction);
+        let c = v.dot(&v) - self.radius * self.radius;
+        let disc = b * b - 4.0 * a * c;
+        if disc < 0.0 {
+            return None;
+        }
+
+        let e = disc.sqrt();
+        let t1 = (-b - e) / (2.0 * a);
+        let t2 = (-b + e) / (2.0 * a);
+        let t = if t1 > 0.0001 {
+            t1
+        } else if t2 > 0.0001 {
+            t2
+        } else {
+            return None;
+        };
+
+        // Only the outside of the sphere emits light.
+        let normal = (ray.point_at(t) - self.center) / self.radius;
+        let radiance = if normal.dot(&ray.direction) < 0.0 {
+            self.radiance
+        } else {
+            Color::default()
+        };
+        Some(LightHit {
+            t,
+            normal,
+            radiance,
+        })
+    }
+
+    fn sample_emission(&self, u_position: Vector2, u_direction: Vector2) -> Option<EmissionSample> {
+        let normal = uniform_sphere(u_position);
+        let origin = self.center + normal * self.radius;
+
+        // Cosine weighted directions around the surface normal.
+        let local = cosine_hemisphere(u_direction);
+        let direction = to_world(&local, &normal);
+
+        Some(EmissionSample {
+            ray: Ray3::new(origin, direction),
+            normal,
+            radiance: self.radiance,
+            pdf_position: uniform_sphere_pdf() / (self.radius * self.radius),
+            pdf_direction: cosine_hemisphere_pdf(local.z),
+        })
+    }
+
+    fn pdf_emission(&self, _: &Vector3, normal: &Vector3, direction: &Vector3) -> (f64, f64) {
+        (
+            uniform_sphere_pdf() / (self.radius * self.radius),
+            cosine_hemisphere_pdf(normal.dot(direction)),
+        )
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "sphere", self)
+    }
+}
+
+impl Serialize for SphereLight {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.center.write_to(out)?;
+        self.radius.write_to(out)?;
+        self.radiance.write_to(out)
+    }
+}
+
+impl Deserialize for SphereLight {
+    fn read_from(input: &mut InputStream) -> Result<SphereLight> {
+        Ok(SphereLight {
+            center: Vector3::read_from(input)?,
+            radius: f64::read_from(input)?,
+            radiance: Color::read_from(input)?,
+        })
+    }
+}
+
+pub fn luminance(c: &Color) -> f64 {
+    0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+}
+
+pub(crate) fn direction_to_uv(direction: &Vector3) -> Vector2 {
+    let d = direction.normalized();
+    let phi = d.x.atan2(-d.z);
+    let u = 0.5 + phi / (2.0 * PI);
+    let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+    Vector2::new(u.clamp(0.0, 1.0), v)
+}
+
+pub(crate) fn uv_to_direction(uv: Vector2) -> Vector3 {
+    let theta = uv.y * PI;
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    Vector3::new(
+        theta.sin() * phi.sin(),
+        theta.cos(),
+        -theta.sin() * phi.cos(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uv_mapping_round_trips() {
+        let direction = Vector3::new(0.3, 0.5, -0.8).normalized();
+        let uv = direction_to_uv(&direction);
+        assert_close!(direction, uv_to_direction(uv));
+        assert_close!(Vector3::new(0.0, 0.0, -1.0), uv_to_direction(Vector2::new(0.5, 0.5)));
+    }
+
+    #[test]
+    fn sampled_pdf_matches_pdf_of_direction() {
+        let mut image = RenderBuffer::new(8, 4);
+        image.set_pixel(
+            5,
+            1,
+            Color {
+                r: 4.0,
+                g: 2.0,
+                b: 1.0,
+                a: 1.0,
+            },
+        );
+        let light = EnvironmentLight::new(image, 0.7, 1.0);
+        let sample = light.sample(&Vector3::zero(), Vector2::new(0.3, 0.6)).unwrap();
+        assert!((sample.pdf - light.pdf(&Vector3::zero(), &sample.direction)).abs() < 1e-6);
+        assert_close!(4.0, sample.radiance.r);
+    }
+
+    #[test]
+    fn sphere_light_sample_hits_sphere() {
+        let light = SphereLight {
+            center: Vector3::new(0.0, 3.0, 0.0),
+            radius: 0.5,
+            radiance: Color::white(),
+        };
+        let sample = light.sample(&Vector3::zero(), Vector2::new(0.7, 0.2)).unwrap();
+        let hit = Ray3::new(Vector3::zero(), sample.direction).point_at(sample.distance);
+        assert!(((hit - light.center).length() - 0.5).abs() < 1e-6);
+        assert_close!(sample.pdf, light.pdf(&Vector3::zero(), &sample.direction));
+    }
+}
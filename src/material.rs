@@ -1,12 +1,45 @@
+use rand::{Rng, RngCore};
+use std::io::Result;
+use std::sync::Arc;
+
 use crate::{TraceContext};
 use crate::color::{Color};
-use crate::math::{Ray3, Vector3};
-use crate::sampler::{HemiSphereSampler, Sampler, UnitSphereSampler};
+use crate::io::{InputStream, OutputStream};
+use crate::math::{Ray3, Vector2, Vector3};
+use crate::medium::HomogeneousMedium;
+use crate::sampler::HemiSphereSampler;
+use crate::serialize::{invalid_data, unsupported, write_tagged, Deserialize, Serialize};
 use crate::shapes::Intersection;
+use crate::spectrum::{rgb_to_spectrum, REFERENCE_WAVELENGTH};
+use crate::warp::{to_world, uniform_sphere};
 
 pub trait Material : Send + Sync {
 
-    fn scatter(&self, trace_context: &TraceContext, ray: &Ray3, intersection: &Intersection, attenuation: &mut Color, scattered: &mut Ray3) -> bool;
+    /// Random choices, e.g. between reflection and refraction, are drawn from `rng`. Materials whose
+    /// scattering direction depends on the wavelength terminate the secondary wavelengths of the path.
+    fn scatter(&self, trace_context: &mut TraceContext, ray: &Ray3, intersection: &Intersection, rng: &mut dyn RngCore, attenuation: &mut Color, scattered: &mut Ray3) -> bool;
+
+    /// The `attenuation` of a scattering event at `wavelength` in spectral mode, for materials whose
+    /// reflectance is known at every wavelength. Other materials uplift their RGB attenuation.
+    fn spectral_attenuation(&self, _ray: &Ray3, _intersection: &Intersection, attenuation: &Color, wavelength: f64) -> f64 {
+        rgb_to_spectrum(attenuation, wavelength)
+    }
+
+    /// Evaluates the scattering function times the cosine term for light arriving from `direction`.
+    /// Materials that only scatter into discrete directions return black and are not lit by light sampling.
+    fn evaluate(&self, _ray: &Ray3, _intersection: &Intersection, _direction: &Vector3) -> Color {
+        Color::default()
+    }
+
+    /// Returns the density with respect to solid angle with which `scatter` picks `direction`.
+    fn pdf(&self, _ray: &Ray3, _intersection: &Intersection, _direction: &Vector3) -> f64 {
+        0.0
+    }
+
+    /// Writes the type and the fields of the material, e.g. to ship the scene to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("material"))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -14,9 +47,13 @@ pub struct NullMaterial {
 }
 
 impl Material for NullMaterial {
-    fn scatter(&self, _: &TraceContext, _: &Ray3, _: &Intersection, _: &mut Color, _: &mut Ray3) -> bool {
+    fn scatter(&self, _: &mut TraceContext, _: &Ray3, _: &Intersection, _: &mut dyn RngCore, _: &mut Color, _: &mut Ray3) -> bool {
         false
     }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        "null".write_to(out)
+    }
 }
 
 impl NullMaterial {
@@ -31,7 +68,7 @@ pub struct NormalMaterial {
 }
 
 impl Material for NormalMaterial {
-    fn scatter(&self, _: &TraceContext, _: &Ray3, intersection: &Intersection, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
+    fn scatter(&self, _: &mut TraceContext, _: &Ray3, intersection: &Intersection, _: &mut dyn RngCore, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
         scattered.origin = intersection.point + intersection.normal * 0.01;
         scattered.direction = intersection.normal;
         let unit = Vector3::new(intersection.normal.x.abs(), intersection.normal.y.abs(), intersection.normal.z.abs());
@@ -40,6 +77,10 @@ impl Material for NormalMaterial {
         attenuation.b = unit.z;
         true
     }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        "normal".write_to(out)
+    }
 }
 
 impl NormalMaterial {
@@ -57,16 +98,10 @@ pub struct Lambertian {
 
 impl Material for Lambertian {
 
-    fn scatter(&self, trace_context: &TraceContext, _: &Ray3, intersection: &Intersection, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
-        let w = intersection.normal;
-        let v = (w.cross(&Vector3::new(0.0072, 1.0, 0.0034))).normalized();
-        let u = v.cross(&w);
-
-        let sample = self.samples.sample(trace_context.set_index, trace_context.sample_index);
-
-        let target = u * sample.x + v * sample.y + w * sample.z;
+    fn scatter(&self, _: &mut TraceContext, _: &Ray3, intersection: &Intersection, rng: &mut dyn RngCore, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
+        let sample = self.samples.warp(Vector2::new(rng.gen(), rng.gen()));
 
-        let normal = target.normalized();
+        let normal = to_world(&sample, &intersection.normal);
 
         scattered.origin = intersection.point + normal * 0.01;
         scattered.direction = normal;
@@ -77,6 +112,39 @@ impl Material for Lambertian {
 
         true
     }
+
+    fn evaluate(&self, ray: &Ray3, intersection: &Intersection, direction: &Vector3) -> Color {
+        // The scatter weight is the albedo, so the scattering function is proportional to the sampling density.
+        let pdf = self.pdf(ray, intersection, direction);
+        Color {
+            r: self.albedo.r * pdf,
+            g: self.albedo.g * pdf,
+            b: self.albedo.b * pdf,
+            a: 1.0,
+        }
+    }
+
+    fn pdf(&self, _: &Ray3, intersection: &Intersection, direction: &Vector3) -> f64 {
+        self.samples.pdf(intersection.normal.dot(direction))
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "lambertian", self)
+    }
+}
+
+impl Serialize for Lambertian {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.samples.write_to(out)?;
+        self.albedo.write_to(out)
+    }
+}
+
+impl Deserialize for Lambertian {
+    fn read_from(input: &mut InputStream) -> Result<Lambertian> {
+        let samples = HemiSphereSampler::read_from(input)?;
+        Ok(Lambertian::new(&samples, &Color::read_from(input)?))
+    }
 }
 
 impl Lambertian {
@@ -91,35 +159,521 @@ impl Lambertian {
 
 #[derive(Clone, Debug)]
 pub struct Metal {
-    samples: UnitSphereSampler,
     albedo: Color,
     fuzziness: f64,
+    conductor: Option<ComplexRefractiveIndex>,
 }
 
 impl Material for Metal {
 
-    fn scatter(&self, trace_context: &TraceContext, ray: &Ray3, intersection: &Intersection, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
-        let sample = self.samples.sample(trace_context.set_index, trace_context.sample_index);
-        let reflected = (ray.direction.reflect(&intersection.normal) + sample * self.fuzziness).normalized();
+    fn scatter(&self, _: &mut TraceContext, ray: &Ray3, intersection: &Intersection, rng: &mut dyn RngCore, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
+        let mut reflected = ray.direction.reflect(&intersection.normal);
+        if self.fuzziness > 0.0 {
+            // A point distributed uniformly in the unit ball.
+            let sample = uniform_sphere(Vector2::new(rng.gen(), rng.gen())) * rng.gen::<f64>().cbrt();
+            reflected = (reflected + sample * self.fuzziness).normalized();
+        }
 
         scattered.origin = intersection.point;
         scattered.direction = reflected;
 
-        attenuation.r = self.albedo.r;
-        attenuation.g = self.albedo.g;
-        attenuation.b = self.albedo.b;
+        *attenuation = match &self.conductor {
+            Some(conductor) => {
+                let [r, g, b] = RGB_WAVELENGTHS.map(|wavelength| conductor.reflectance(ray, intersection, wavelength));
+                Color { r, g, b, a: 1.0 }
+            }
+            None => self.albedo,
+        };
 
         scattered.direction.dot(&intersection.normal) > 0.0
     }
+
+    fn spectral_attenuation(&self, ray: &Ray3, intersection: &Intersection, attenuation: &Color, wavelength: f64) -> f64 {
+        match &self.conductor {
+            Some(conductor) => conductor.reflectance(ray, intersection, wavelength),
+            None => rgb_to_spectrum(attenuation, wavelength),
+        }
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "metal", self)
+    }
+}
+
+impl Serialize for Metal {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.albedo.write_to(out)?;
+        self.fuzziness.write_to(out)?;
+        self.conductor.write_to(out)
+    }
+}
+
+impl Deserialize for Metal {
+    fn read_from(input: &mut InputStream) -> Result<Metal> {
+        let albedo = Color::read_from(input)?;
+        let fuzziness = f64::read_from(input)?;
+        Ok(Metal { conductor: Deserialize::read_from(input)?, ..Metal::new(&albedo, fuzziness) })
+    }
 }
 
 impl Metal {
 
-    pub fn new(samples: &UnitSphereSampler, albedo: &Color, fuzziness: f64,) -> Metal {
+    pub fn new(albedo: &Color, fuzziness: f64,) -> Metal {
         Metal {
-            samples: samples.clone(),
             albedo: *albedo,
             fuzziness: fuzziness,
+            conductor: None,
         }
     }
-}
\ No newline at end of file
+
+    /// A metal reflecting with the Fresnel reflectance of a conductor, which depends on the wavelength.
+    pub fn conductor(ior: &ComplexRefractiveIndex, fuzziness: f64) -> Metal {
+        Metal {
+            conductor: Some(ior.clone()),
+            ..Metal::new(&Color::white(), fuzziness)
+        }
+    }
+}
+
+/// Wavelengths in nanometres at which wavelength dependent reflectances are evaluated for the red, green and
+/// blue channels when rendering in RGB.
+const RGB_WAVELENGTHS: [f64; 3] = [610.0, 550.0, 465.0];
+
+/// The complex refractive index n + ik of a conductor, tabulated as [λ, n, k] with the wavelength λ in
+/// nanometres and interpolated linearly in between.
+#[derive(Clone, Debug)]
+pub struct ComplexRefractiveIndex {
+    samples: Vec<[f64; 3]>,
+}
+
+impl Serialize for ComplexRefractiveIndex {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.samples.write_to(out)
+    }
+}
+
+impl Deserialize for ComplexRefractiveIndex {
+    fn read_from(input: &mut InputStream) -> Result<ComplexRefractiveIndex> {
+        let samples = Vec::<[f64; 3]>::read_from(input)?;
+        if samples.is_empty() || samples.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+            return Err(invalid_data("Invalid complex refractive index"));
+        }
+        Ok(ComplexRefractiveIndex { samples })
+    }
+}
+
+impl ComplexRefractiveIndex {
+
+    /// `samples` are [λ, n, k] with increasing wavelengths λ in nanometres.
+    pub fn new(samples: &[[f64; 3]]) -> ComplexRefractiveIndex {
+        expect_neq!(samples.len(), 0);
+        ComplexRefractiveIndex { samples: samples.to_vec() }
+    }
+
+    pub fn gold() -> ComplexRefractiveIndex {
+        ComplexRefractiveIndex::new(&[
+            [400.0, 1.47, 1.95], [450.0, 1.40, 1.88], [500.0, 0.97, 1.87], [550.0, 0.43, 2.45],
+            [600.0, 0.25, 2.98], [650.0, 0.17, 3.52], [700.0, 0.16, 3.95],
+        ])
+    }
+
+    pub fn copper() -> ComplexRefractiveIndex {
+        ComplexRefractiveIndex::new(&[
+            [400.0, 1.18, 2.21], [450.0, 1.17, 2.36], [500.0, 1.13, 2.56], [550.0, 1.02, 2.58],
+            [600.0, 0.25, 3.41], [650.0, 0.21, 3.67], [700.0, 0.21, 4.20],
+        ])
+    }
+
+    pub fn silver() -> ComplexRefractiveIndex {
+        ComplexRefractiveIndex::new(&[
+            [400.0, 0.05, 2.10], [500.0, 0.05, 3.10], [600.0, 0.06, 3.90], [700.0, 0.04, 4.80],
+        ])
+    }
+
+    pub fn aluminium() -> ComplexRefractiveIndex {
+        ComplexRefractiveIndex::new(&[
+            [400.0, 0.49, 4.86], [500.0, 0.77, 6.08], [600.0, 1.20, 7.26], [700.0, 1.83, 8.31],
+        ])
+    }
+
+    /// Returns n and k at `wavelength`, clamped to the tabulated range.
+    pub fn at(&self, wavelength: f64) -> (f64, f64) {
+        let upper = self.samples.partition_point(|sample| sample[0] < wavelength);
+        if upper == 0 || upper == self.samples.len() {
+            let [_, n, k] = self.samples[upper.min(self.samples.len() - 1)];
+            return (n, k);
+        }
+        let [l0, n0, k0] = self.samples[upper - 1];
+        let [l1, n1, k1] = self.samples[upper];
+        let t = (wavelength - l0) / (l1 - l0);
+        (n0 + t * (n1 - n0), k0 + t * (k1 - k0))
+    }
+
+    /// The Fresnel reflectance for `ray` hitting the conductor at `intersection`.
+    fn reflectance(&self, ray: &Ray3, intersection: &Intersection, wavelength: f64) -> f64 {
+        let (n, k) = self.at(wavelength);
+        let cos_i = ray.direction.normalized().dot(&intersection.normal).abs().min(1.0);
+        fresnel_conductor(cos_i, n, k)
+    }
+}
+
+/// The refractive index of a dielectric as a function of the wavelength in nanometres.
+#[derive(Clone, Debug)]
+pub enum RefractiveIndex {
+    Constant(f64),
+    /// Cauchy's equation n = a + b / λ² with λ in micrometres.
+    Cauchy { a: f64, b: f64 },
+    /// The Sellmeier equation n² = 1 + Σ b λ² / (λ² - c) with λ in micrometres.
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Serialize for RefractiveIndex {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        match self {
+            RefractiveIndex::Constant(n) => write_tagged(out, "constant", n),
+            RefractiveIndex::Cauchy { a, b } => write_tagged(out, "cauchy", &[*a, *b]),
+            RefractiveIndex::Sellmeier { b, c } => {
+                "sellmeier".write_to(out)?;
+                b.write_to(out)?;
+                c.write_to(out)
+            }
+        }
+    }
+}
+
+impl Deserialize for RefractiveIndex {
+    fn read_from(input: &mut InputStream) -> Result<RefractiveIndex> {
+        match String::read_from(input)?.as_str() {
+            "constant" => Ok(RefractiveIndex::Constant(f64::read_from(input)?)),
+            "cauchy" => {
+                let [a, b] = <[f64; 2]>::read_from(input)?;
+                Ok(RefractiveIndex::Cauchy { a, b })
+            }
+            "sellmeier" => Ok(RefractiveIndex::Sellmeier {
+                b: Deserialize::read_from(input)?,
+                c: Deserialize::read_from(input)?,
+            }),
+            _ => Err(invalid_data("Unknown refractive index")),
+        }
+    }
+}
+
+impl RefractiveIndex {
+
+    /// The borosilicate crown glass BK7.
+    pub fn bk7() -> RefractiveIndex {
+        RefractiveIndex::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        }
+    }
+
+    /// The dense flint glass SF11, which disperses light much more than crown glass.
+    pub fn sf11() -> RefractiveIndex {
+        RefractiveIndex::Sellmeier {
+            b: [1.737_596_95, 0.313_747_346, 1.898_781_01],
+            c: [0.013_188_707, 0.062_306_814_2, 155.236_290],
+        }
+    }
+
+    pub fn at(&self, wavelength: f64) -> f64 {
+        let micrometres = wavelength * 1e-3;
+        let l2 = micrometres * micrometres;
+        match self {
+            RefractiveIndex::Constant(n) => *n,
+            RefractiveIndex::Cauchy { a, b } => a + b / l2,
+            RefractiveIndex::Sellmeier { b, c } => {
+                let n2 = 1.0 + b.iter().zip(c.iter()).map(|(b, c)| b * l2 / (l2 - c)).sum::<f64>();
+                n2.sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, RefractiveIndex::Constant(_))
+    }
+}
+
+/// A smooth dielectric such as glass or water. Rays are reflected or refracted with probabilities given by the
+/// Fresnel reflectance, and `tint` colors the transmitted light. Inside and outside are told apart by the
+/// direction of the normal, so the material needs shapes with outward normals like spheres.
+/// In spectral mode a dispersive index splits white light into its colors; otherwise the index at the
+/// reference wavelength is used.
+#[derive(Clone, Debug)]
+pub struct Dielectric {
+    ior: RefractiveIndex,
+    tint: Color,
+}
+
+impl Material for Dielectric {
+
+    fn scatter(&self, trace_context: &mut TraceContext, ray: &Ray3, intersection: &Intersection, rng: &mut dyn RngCore, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
+        let ior = match &mut trace_context.wavelengths {
+            Some(wavelengths) if self.ior.is_dispersive() => {
+                wavelengths.terminate_secondary();
+                self.ior.at(wavelengths.hero())
+            }
+            _ => self.ior.at(REFERENCE_WAVELENGTH),
+        };
+
+        let direction = ray.direction.normalized();
+        let (normal, eta) = if direction.dot(&intersection.normal) < 0.0 {
+            (intersection.normal, 1.0 / ior)
+        } else {
+            (-intersection.normal, ior)
+        };
+
+        let refracted = direction.refract(&normal, eta);
+        let reflectance = match refracted {
+            Some(refracted) => fresnel_dielectric(-direction.dot(&normal), -refracted.dot(&normal), eta),
+            None => 1.0,
+        };
+
+        match refracted {
+            Some(refracted) if rng.gen::<f64>() >= reflectance => {
+                scattered.origin = intersection.point - normal * 0.001;
+                scattered.direction = refracted;
+                *attenuation = self.tint;
+            }
+            _ => {
+                scattered.origin = intersection.point + normal * 0.001;
+                scattered.direction = direction.reflect(&normal);
+                *attenuation = Color::white();
+            }
+        }
+
+        true
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "dielectric", self)
+    }
+}
+
+impl Serialize for Dielectric {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.ior.write_to(out)?;
+        self.tint.write_to(out)
+    }
+}
+
+impl Deserialize for Dielectric {
+    fn read_from(input: &mut InputStream) -> Result<Dielectric> {
+        let ior = RefractiveIndex::read_from(input)?;
+        if ior.at(REFERENCE_WAVELENGTH) <= 0.0 {
+            return Err(invalid_data("Invalid refractive index"));
+        }
+        let tint = Color::read_from(input)?;
+        Ok(Dielectric::with_refractive_index(&ior, &tint))
+    }
+}
+
+impl Dielectric {
+
+    pub fn new(ior: f64, tint: &Color) -> Dielectric {
+        Dielectric::with_refractive_index(&RefractiveIndex::Constant(ior), tint)
+    }
+
+    pub fn with_refractive_index(ior: &RefractiveIndex, tint: &Color) -> Dielectric {
+        expect_lt!(0.0, ior.at(REFERENCE_WAVELENGTH));
+        Dielectric { ior: ior.clone(), tint: *tint }
+    }
+}
+
+/// Fresnel reflectance of unpolarized light at a smooth dielectric interface.
+fn fresnel_dielectric(cos_i: f64, cos_t: f64, eta: f64) -> f64 {
+    let parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (0.5 * (parallel * parallel + perpendicular * perpendicular)).min(1.0)
+}
+
+/// Fresnel reflectance of unpolarized light at a smooth conductor with the complex refractive index n + ik.
+fn fresnel_conductor(cos_i: f64, n: f64, k: f64) -> f64 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = n * n - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * n * n * k * k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos_i * a;
+    let perpendicular = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+    0.5 * (parallel + perpendicular)
+}
+
+/// A translucent material for skin, wax or marble.
+/// Light is transmitted diffusely through the surface and then performs a random walk through a
+/// homogeneous medium filling the closed shape, see `medium::SubsurfaceVolume`.
+#[derive(Clone, Debug)]
+pub struct Subsurface {
+    samples: HemiSphereSampler,
+    medium: Arc<HomogeneousMedium>,
+}
+
+impl Material for Subsurface {
+
+    fn scatter(&self, _: &mut TraceContext, ray: &Ray3, intersection: &Intersection, rng: &mut dyn RngCore, attenuation: &mut Color, scattered: &mut Ray3) -> bool {
+        let w = if ray.direction.dot(&intersection.normal) > 0.0 {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+
+        let sample = self.samples.warp(Vector2::new(rng.gen(), rng.gen()));
+
+        let direction = to_world(&sample, &w);
+
+        scattered.origin = intersection.point + w * 0.01;
+        scattered.direction = direction;
+
+        attenuation.r = 1.0;
+        attenuation.g = 1.0;
+        attenuation.b = 1.0;
+
+        true
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "subsurface", self)
+    }
+}
+
+impl Serialize for Subsurface {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.samples.write_to(out)?;
+        self.medium.write_to(out)
+    }
+}
+
+impl Deserialize for Subsurface {
+    fn read_from(input: &mut InputStream) -> Result<Subsurface> {
+        Ok(Subsurface {
+            samples: HemiSphereSampler::read_from(input)?,
+            medium: Arc::new(HomogeneousMedium::read_from(input)?),
+        })
+    }
+}
+
+impl Subsurface {
+
+    /// Creates a subsurface material from the multiple-scattering albedo, i.e. the perceived color of
+    /// the surface, and the mean free path per channel in scene units.
+    pub fn new(samples: &HemiSphereSampler, albedo: &Color, mean_free_path: &Color, g: f64) -> Subsurface {
+        let sigma_t = Color {
+            r: 1.0 / mean_free_path.r,
+            g: 1.0 / mean_free_path.g,
+            b: 1.0 / mean_free_path.b,
+            a: 1.0,
+        };
+        let single_scattering_albedo = Color {
+            r: single_scattering_albedo(albedo.r),
+            g: single_scattering_albedo(albedo.g),
+            b: single_scattering_albedo(albedo.b),
+            a: 1.0,
+        };
+        let sigma_s = sigma_t * single_scattering_albedo;
+        let sigma_a = Color {
+            r: sigma_t.r - sigma_s.r,
+            g: sigma_t.g - sigma_s.g,
+            b: sigma_t.b - sigma_s.b,
+            a: 1.0,
+        };
+
+        Subsurface {
+            samples: samples.clone(),
+            medium: Arc::new(HomogeneousMedium::new(&sigma_a, &sigma_s, g)),
+        }
+    }
+
+    pub fn medium(&self) -> Arc<HomogeneousMedium> {
+        Arc::clone(&self.medium)
+    }
+}
+
+/// Inverts the multiple-scattering albedo of a random walk to the single-scattering albedo of the medium
+/// using the fit by van de Hulst.
+fn single_scattering_albedo(albedo: f64) -> f64 {
+    let a = albedo.clamp(0.0, 1.0);
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+    1.0 - s * s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn fresnel_reflectance_of_glass_at_normal_incidence() {
+        // ((n1 - n2) / (n1 + n2))^2 for air to glass.
+        assert_close!(0.04, fresnel_dielectric(1.0, 1.0, 1.0 / 1.5));
+    }
+
+    #[test]
+    fn dielectrics_reflect_with_the_fresnel_reflectance() {
+        let glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5, &Color::white()));
+        let ray = Ray3::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let intersection = Intersection {
+            ray,
+            t: 1.0,
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            uv: Vector2::new(0.0, 0.0),
+            material: Arc::clone(&glass),
+        };
+        let mut context = TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        };
+
+        // The same ray is reflected or refracted as the random numbers decide.
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 100_000;
+        let mut reflected = 0;
+        for _ in 0..n {
+            let mut attenuation = Color::black();
+            let mut scattered = ray;
+            assert!(glass.scatter(&mut context, &ray, &intersection, &mut rng, &mut attenuation, &mut scattered));
+            if scattered.direction.y > 0.0 {
+                reflected += 1;
+            }
+        }
+        assert!((reflected as f64 / n as f64 - 0.04).abs() < 0.003);
+    }
+
+    #[test]
+    fn gold_reflects_more_red_than_blue() {
+        let (n, k) = ComplexRefractiveIndex::gold().at(650.0);
+        let normal_incidence = ((n - 1.0) * (n - 1.0) + k * k) / ((n + 1.0) * (n + 1.0) + k * k);
+        assert_close!(normal_incidence, fresnel_conductor(1.0, n, k));
+        // All metals reflect everything at grazing angles.
+        assert_close!(1.0, fresnel_conductor(0.0, n, k));
+
+        let gold = ComplexRefractiveIndex::gold();
+        let reflectance = |wavelength| {
+            let (n, k) = gold.at(wavelength);
+            fresnel_conductor(1.0, n, k)
+        };
+        assert!(reflectance(650.0) > 0.9);
+        assert!(reflectance(450.0) < 0.5);
+    }
+
+    #[test]
+    fn sellmeier_index_of_bk7_matches_catalogue() {
+        let bk7 = RefractiveIndex::bk7();
+        assert!((bk7.at(REFERENCE_WAVELENGTH) - 1.5168).abs() < 1e-4);
+        assert!(bk7.at(450.0) > bk7.at(650.0));
+    }
+
+    #[test]
+    fn single_scattering_albedo_keeps_extremes() {
+        assert!(single_scattering_albedo(0.0).abs() < 1e-4);
+        assert!((single_scattering_albedo(1.0) - 1.0).abs() < 1e-4);
+        assert!(single_scattering_albedo(0.5) > 0.5);
+    }
+}
@@ -9,7 +9,7 @@ use std::ops::Sub;
 use std::ops::SubAssign;
 use super::*;
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Vector2 {
     pub x: f64,
     pub y: f64,
@@ -70,7 +70,7 @@ impl Vector2 {
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Vector3 {
     pub x: f64,
     pub y: f64,
@@ -247,6 +247,19 @@ impl Vector3 {
     pub fn reflect(&self, reflector: &Vector3) -> Vector3 {
         *self - 2.0 * self.dot(reflector) * *reflector
     }
+
+    /// Refracts the unit direction `self` at a surface with unit `normal` facing against it, where `eta` is the
+    /// ratio of the refractive indices on the incident and the transmitted side. Returns `None` on total
+    /// internal reflection.
+    pub fn refract(&self, normal: &Vector3, eta: f64) -> Option<Vector3> {
+        let cos_i = -self.dot(normal);
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        if sin2_t > 1.0 {
+            return None;
+        }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some(eta * *self + (eta * cos_i - cos_t) * *normal)
+    }
 }
 
 #[cfg(test)]
@@ -275,6 +288,16 @@ mod tests {
         assert_close!(result, actual);
     }
 
+    #[test]
+    fn refract_keeps_normal_incidence_and_reflects_totally() {
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        let down = Vector3::new(0.0, -1.0, 0.0);
+        assert_close!(down, down.refract(&normal, 1.0 / 1.5).unwrap());
+
+        let grazing = Vector3::new(1.0, -0.1, 0.0).normalized();
+        assert!(grazing.refract(&normal, 1.5).is_none());
+    }
+
     #[test]
     fn length_tests() {
         assert_close!(1.0, Vector3::new(1.0, 0.0, 0.0).length());
@@ -0,0 +1,699 @@
+use rand::{Rng, RngCore};
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::io::{InputStream, OutputStream};
+use crate::material::Subsurface;
+use crate::math::{Ray3, Vector3};
+use crate::serialize::{invalid_data, unsupported, write_tagged, Deserialize, Serialize};
+use crate::shapes::{Hitable, Intersection};
+use crate::warp::to_world;
+
+/// Number of boundary crossings a `Volume` looks for along a single ray.
+const MAX_BOUNDARY_CROSSINGS: usize = 16;
+const CROSSING_EPSILON: f64 = 0.001;
+
+/// The Henyey-Greenstein phase function.
+/// Positive values of `g` favour forward scattering, negative values backward scattering
+/// and zero gives isotropic scattering.
+#[derive(Clone, Copy, Debug)]
+pub struct HenyeyGreenstein {
+    pub g: f64,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f64) -> HenyeyGreenstein {
+        expect_lt!(g.abs(), 1.0);
+        HenyeyGreenstein { g }
+    }
+
+    pub fn isotropic() -> HenyeyGreenstein {
+        HenyeyGreenstein { g: 0.0 }
+    }
+
+    /// Evaluates the phase function for the cosine between the propagation direction
+    /// of the incoming ray and the scattered direction.
+    pub fn evaluate(&self, cos_theta: f64) -> f64 {
+        let denom = 1.0 + self.g * self.g - 2.0 * self.g * cos_theta;
+        (1.0 - self.g * self.g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    /// Samples a new propagation direction for a ray travelling along `direction`.
+    /// The phase function is sampled exactly, so the scattering weight is always one.
+    pub fn sample(&self, direction: &Vector3, rng: &mut dyn RngCore) -> Vector3 {
+        let u1: f64 = rng.gen();
+        let u2: f64 = rng.gen();
+
+        let cos_theta = if self.g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let sqr = (1.0 - self.g * self.g) / (1.0 + self.g - 2.0 * self.g * u1);
+            (1.0 + self.g * self.g - sqr * sqr) / (2.0 * self.g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+
+        let local = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        to_world(&local, direction)
+    }
+}
+
+impl Serialize for HenyeyGreenstein {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.g.write_to(out)
+    }
+}
+
+impl Deserialize for HenyeyGreenstein {
+    fn read_from(input: &mut InputStream) -> Result<HenyeyGreenstein> {
+        let g = f64::read_from(input)?;
+        if g.abs() >= 1.0 || g.is_nan() {
+            return Err(invalid_data("Invalid phase function"));
+        }
+        Ok(HenyeyGreenstein::new(g))
+    }
+}
+
+/// A participating medium that absorbs and scatters light travelling through it.
+pub trait Medium: Send + Sync {
+    /// Samples a scattering event along `ray` between `t_min` and `t_max`.
+    /// Returns the distance of the event if the ray scatters inside the medium, or `None` if it
+    /// passes through. In both cases `weight` receives the throughput weight of the sampled event.
+    fn sample(
+        &self,
+        ray: &Ray3,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut dyn RngCore,
+        weight: &mut Color,
+    ) -> Option<f64>;
+
+    /// The weight `sample` gives a ray that passes through between `t_min` and `t_max`. A ray that passed
+    /// through further is weighted like this when another medium scatters it at `t_max`.
+    fn pass_weight(&self, ray: &Ray3, t_min: f64, t_max: f64) -> Color;
+
+    /// Estimates the transmittance along `ray` between `t_min` and `t_max`.
+    fn transmittance(&self, ray: &Ray3, t_min: f64, t_max: f64, rng: &mut dyn RngCore) -> Color;
+
+    fn phase_function(&self) -> &HenyeyGreenstein;
+
+    /// Writes the type and the fields of the medium, e.g. to ship the scene to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("medium"))
+    }
+}
+
+/// A medium with constant absorption and scattering coefficients.
+#[derive(Clone, Debug)]
+pub struct HomogeneousMedium {
+    sigma_a: Color,
+    sigma_s: Color,
+    phase: HenyeyGreenstein,
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample(
+        &self,
+        ray: &Ray3,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut dyn RngCore,
+        weight: &mut Color,
+    ) -> Option<f64> {
+        let sigma_t = self.sigma_t();
+        let channel = rng.gen_range(0..3);
+        let sigma_channel = channel_value(&sigma_t, channel);
+        let u: f64 = rng.gen();
+
+        let t = if sigma_channel > 0.0 {
+            t_min - (1.0 - u).ln() / sigma_channel
+        } else {
+            f64::INFINITY
+        };
+        if t >= t_max {
+            *weight = self.pass_weight(ray, t_min, t_max);
+            return None;
+        }
+
+        // The distance is sampled from one channel, so the pdf is the average over all channels.
+        let transmittance = exp_color(&sigma_t, t - t_min);
+        let density = sigma_t * transmittance;
+        let pdf = (density.r + density.g + density.b) / 3.0;
+        *weight = if pdf > 0.0 {
+            transmittance * self.sigma_s * (1.0 / pdf)
+        } else {
+            Color::white()
+        };
+        weight.a = 1.0;
+        Some(t)
+    }
+
+    fn pass_weight(&self, _: &Ray3, t_min: f64, t_max: f64) -> Color {
+        let transmittance = exp_color(&self.sigma_t(), t_max - t_min);
+        let pdf = (transmittance.r + transmittance.g + transmittance.b) / 3.0;
+        let mut weight = if pdf > 0.0 {
+            transmittance * (1.0 / pdf)
+        } else {
+            Color::white()
+        };
+        weight.a = 1.0;
+        weight
+    }
+
+    fn transmittance(&self, _: &Ray3, t_min: f64, t_max: f64, _: &mut dyn RngCore) -> Color {
+        exp_color(&self.sigma_t(), t_max - t_min)
+    }
+
+    fn phase_function(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "homogeneous", self)
+    }
+}
+
+impl Serialize for HomogeneousMedium {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.sigma_a.write_to(out)?;
+        self.sigma_s.write_to(out)?;
+        self.phase.write_to(out)
+    }
+}
+
+impl Deserialize for HomogeneousMedium {
+    fn read_from(input: &mut InputStream) -> Result<HomogeneousMedium> {
+        Ok(HomogeneousMedium {
+            sigma_a: Color::read_from(input)?,
+            sigma_s: Color::read_from(input)?,
+            phase: HenyeyGreenstein::read_from(input)?,
+        })
+    }
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: &Color, sigma_s: &Color, g: f64) -> HomogeneousMedium {
+        HomogeneousMedium {
+            sigma_a: *sigma_a,
+            sigma_s: *sigma_s,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    fn sigma_t(&self) -> Color {
+        self.sigma_a + self.sigma_s
+    }
+}
+
+/// A heterogeneous medium defined by a dense grid of density values spanning an axis-aligned box.
+/// The extinction coefficient at a point is `sigma_t` scaled by the trilinearly interpolated density.
+/// Scattering events are sampled with delta tracking and transmittance is estimated with ratio tracking.
+#[derive(Clone, Debug)]
+pub struct GridMedium {
+    min: Vector3,
+    max: Vector3,
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    density: Vec<f64>,
+    max_density: f64,
+    sigma_t: f64,
+    albedo: Color,
+    phase: HenyeyGreenstein,
+}
+
+impl Medium for GridMedium {
+    fn sample(
+        &self,
+        ray: &Ray3,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut dyn RngCore,
+        weight: &mut Color,
+    ) -> Option<f64> {
+        *weight = Color::white();
+        let majorant = self.sigma_t * self.max_density;
+        if majorant <= 0.0 {
+            return None;
+        }
+
+        let mut t = t_min;
+        loop {
+            let u: f64 = rng.gen();
+            t -= (1.0 - u).ln() / majorant;
+            if t >= t_max {
+                return None;
+            }
+            if self.density_at(&ray.point_at(t)) / self.max_density > rng.gen::<f64>() {
+                weight.r = self.albedo.r;
+                weight.g = self.albedo.g;
+                weight.b = self.albedo.b;
+                return Some(t);
+            }
+        }
+    }
+
+    /// Delta tracking passes rays through with the probability of the transmittance.
+    fn pass_weight(&self, _: &Ray3, _: f64, _: f64) -> Color {
+        Color::white()
+    }
+
+    fn transmittance(&self, ray: &Ray3, t_min: f64, t_max: f64, rng: &mut dyn RngCore) -> Color {
+        let majorant = self.sigma_t * self.max_density;
+        let mut transmittance = 1.0;
+        if majorant > 0.0 {
+            let mut t = t_min;
+            loop {
+                let u: f64 = rng.gen();
+                t -= (1.0 - u).ln() / majorant;
+                if t >= t_max {
+                    break;
+                }
+                transmittance *= 1.0 - self.density_at(&ray.point_at(t)) / self.max_density;
+            }
+        }
+        Color {
+            r: transmittance,
+            g: transmittance,
+            b: transmittance,
+            a: 1.0,
+        }
+    }
+
+    fn phase_function(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "grid", self)
+    }
+}
+
+impl Serialize for GridMedium {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.min.write_to(out)?;
+        self.max.write_to(out)?;
+        [self.nx, self.ny, self.nz].write_to(out)?;
+        self.density.write_to(out)?;
+        self.sigma_t.write_to(out)?;
+        self.albedo.write_to(out)?;
+        self.phase.write_to(out)
+    }
+}
+
+impl Deserialize for GridMedium {
+    fn read_from(input: &mut InputStream) -> Result<GridMedium> {
+        let min = Vector3::read_from(input)?;
+        let max = Vector3::read_from(input)?;
+        let [nx, ny, nz] = <[usize; 3]>::read_from(input)?;
+        let density = Vec::<f64>::read_from(input)?;
+        let cells = nx.checked_mul(ny).and_then(|n| n.checked_mul(nz));
+        if density.is_empty() || cells != Some(density.len()) {
+            return Err(invalid_data("Invalid voxel grid"));
+        }
+        Ok(GridMedium {
+            min,
+            max,
+            nx,
+            ny,
+            nz,
+            max_density: density.iter().cloned().fold(0.0, f64::max),
+            density,
+            sigma_t: f64::read_from(input)?,
+            albedo: Color::read_from(input)?,
+            phase: HenyeyGreenstein::read_from(input)?,
+        })
+    }
+}
+
+impl GridMedium {
+    /// Creates a grid medium from `nx * ny * nz` density values stored with x varying fastest, then y, then z.
+    pub fn new(
+        min: Vector3,
+        max: Vector3,
+        dimensions: (usize, usize, usize),
+        density: Vec<f64>,
+        sigma_t: f64,
+        albedo: &Color,
+        g: f64,
+    ) -> GridMedium {
+        let (nx, ny, nz) = dimensions;
+        expect_neq!(nx * ny * nz, 0);
+        expect_eq!(density.len(), nx * ny * nz);
+
+        let max_density = density.iter().cloned().fold(0.0, f64::max);
+
+        GridMedium {
+            min,
+            max,
+            nx,
+            ny,
+            nz,
+            density,
+            max_density,
+            sigma_t,
+            albedo: *albedo,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    /// Loads a grid medium from a dense voxel file.
+    /// The file is plain text: the three grid dimensions `nx ny nz` followed by `nx * ny * nz`
+    /// density values, all separated by whitespace. Lines starting with `#` are ignored.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        min: Vector3,
+        max: Vector3,
+        sigma_t: f64,
+        albedo: &Color,
+        g: f64,
+    ) -> Result<GridMedium> {
+        let contents = std::fs::read_to_string(path)?;
+        let (dimensions, density) = parse_voxel_grid(&contents)?;
+        Ok(GridMedium::new(min, max, dimensions, density, sigma_t, albedo, g))
+    }
+
+    fn density_at(&self, point: &Vector3) -> f64 {
+        let extent = self.max - self.min;
+        let local = *point - self.min;
+        let gx = local.x / extent.x * self.nx as f64 - 0.5;
+        let gy = local.y / extent.y * self.ny as f64 - 0.5;
+        let gz = local.z / extent.z * self.nz as f64 - 0.5;
+
+        let x0 = gx.floor();
+        let y0 = gy.floor();
+        let z0 = gz.floor();
+        let dx = gx - x0;
+        let dy = gy - y0;
+        let dz = gz - z0;
+
+        let d = |x: f64, y: f64, z: f64| self.voxel(x as i64, y as i64, z as i64);
+
+        let d00 = lerp(dx, d(x0, y0, z0), d(x0 + 1.0, y0, z0));
+        let d10 = lerp(dx, d(x0, y0 + 1.0, z0), d(x0 + 1.0, y0 + 1.0, z0));
+        let d01 = lerp(dx, d(x0, y0, z0 + 1.0), d(x0 + 1.0, y0, z0 + 1.0));
+        let d11 = lerp(dx, d(x0, y0 + 1.0, z0 + 1.0), d(x0 + 1.0, y0 + 1.0, z0 + 1.0));
+
+        lerp(dz, lerp(dy, d00, d10), lerp(dy, d01, d11))
+    }
+
+    fn voxel(&self, x: i64, y: i64, z: i64) -> f64 {
+        if x < 0
+            || y < 0
+            || z < 0
+            || x >= self.nx as i64
+            || y >= self.ny as i64
+            || z >= self.nz as i64
+        {
+            return 0.0;
+        }
+        self.density[(z as usize * self.ny + y as usize) * self.nx + x as usize]
+    }
+}
+
+/// A medium-filled region of space bounded by a closed shape.
+/// The boundary itself is invisible; only the medium inside it interacts with light.
+#[derive(Clone)]
+pub struct Volume {
+    boundary: Arc<dyn Hitable>,
+    medium: Arc<dyn Medium>,
+}
+
+impl Hitable for Volume {
+    fn intersect(&self, _: &Ray3) -> Option<Intersection> {
+        None
+    }
+
+    fn medium_segments(&self, ray: &Ray3) -> Vec<MediumSegment> {
+        let mut crossings = Vec::new();
+        let mut offset = 0.0;
+
+        while crossings.len() < MAX_BOUNDARY_CROSSINGS {
+            let shifted = Ray3::new(ray.point_at(offset), ray.direction);
+            match self.boundary.intersect(&shifted) {
+                Some(hit) => {
+                    let t = offset + hit.t;
+                    crossings.push(t);
+                    offset = t + CROSSING_EPSILON;
+                }
+                None => break,
+            }
+        }
+
+        // An odd number of crossings means the ray starts inside the closed boundary.
+        if crossings.len() % 2 == 1 {
+            crossings.insert(0, 0.0);
+        }
+
+        crossings
+            .chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(|c| MediumSegment {
+                t_min: c[0],
+                t_max: c[1],
+                medium: Arc::clone(&self.medium),
+            })
+            .collect()
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "volume", self)
+    }
+}
+
+impl Serialize for Volume {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.boundary.write_to(out)?;
+        self.medium.write_to(out)
+    }
+}
+
+impl Deserialize for Volume {
+    fn read_from(input: &mut InputStream) -> Result<Volume> {
+        let boundary = Deserialize::read_from(input)?;
+        Ok(Volume::new(boundary, Deserialize::read_from(input)?))
+    }
+}
+
+impl Volume {
+    pub fn new(boundary: Arc<dyn Hitable>, medium: Arc<dyn Medium>) -> Volume {
+        Volume { boundary, medium }
+    }
+}
+
+/// A closed shape made of a subsurface scattering material.
+/// The surface of `shape` lets light in and out, while the medium of the material fills its interior.
+#[derive(Clone)]
+pub struct SubsurfaceVolume {
+    shape: Arc<dyn Hitable>,
+    material: Arc<Subsurface>,
+    volume: Volume,
+}
+
+impl Hitable for SubsurfaceVolume {
+    fn intersect(&self, ray: &Ray3) -> Option<Intersection> {
+        self.shape.intersect(ray).map(|intersection| Intersection {
+            material: self.material.clone(),
+            ..intersection
+        })
+    }
+
+    fn medium_segments(&self, ray: &Ray3) -> Vec<MediumSegment> {
+        self.volume.medium_segments(ray)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "subsurface_volume", self)
+    }
+}
+
+impl Serialize for SubsurfaceVolume {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.shape.write_to(out)?;
+        self.material.as_ref().write_to(out)
+    }
+}
+
+impl Deserialize for SubsurfaceVolume {
+    fn read_from(input: &mut InputStream) -> Result<SubsurfaceVolume> {
+        let shape = Deserialize::read_from(input)?;
+        let material = Subsurface::read_from(input)?;
+        Ok(SubsurfaceVolume::new(shape, Arc::new(material)))
+    }
+}
+
+impl SubsurfaceVolume {
+    pub fn new(shape: Arc<dyn Hitable>, material: Arc<Subsurface>) -> SubsurfaceVolume {
+        SubsurfaceVolume {
+            volume: Volume::new(Arc::clone(&shape), material.medium()),
+            shape,
+            material,
+        }
+    }
+}
+
+/// A section of a ray that travels through a medium.
+#[derive(Clone)]
+pub struct MediumSegment {
+    pub t_min: f64,
+    pub t_max: f64,
+    pub medium: Arc<dyn Medium>,
+}
+
+fn parse_voxel_grid(contents: &str) -> Result<((usize, usize, usize), Vec<f64>)> {
+    let mut tokens = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split_whitespace());
+
+    let mut dimension = || -> Result<usize> {
+        tokens
+            .next()
+            .and_then(|token| token.parse::<usize>().ok())
+            .filter(|&n| n > 0)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid voxel grid dimensions"))
+    };
+    let dimensions = (dimension()?, dimension()?, dimension()?);
+
+    let density = tokens
+        .map(|token| {
+            token
+                .parse::<f64>()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid voxel density value"))
+        })
+        .collect::<Result<Vec<f64>>>()?;
+
+    if density.len() != dimensions.0 * dimensions.1 * dimensions.2 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Voxel count does not match grid dimensions",
+        ));
+    }
+
+    Ok((dimensions, density))
+}
+
+fn channel_value(color: &Color, channel: usize) -> f64 {
+    match channel {
+        0 => color.r,
+        1 => color.g,
+        _ => color.b,
+    }
+}
+
+/// The transmittance over `distance`. Channels without extinction let everything through, even over an
+/// infinite distance.
+fn exp_color(sigma: &Color, distance: f64) -> Color {
+    let transmittance = |sigma: f64| {
+        if sigma == 0.0 {
+            1.0
+        } else {
+            (-sigma * distance).exp()
+        }
+    };
+    Color {
+        r: transmittance(sigma.r),
+        g: transmittance(sigma.g),
+        b: transmittance(sigma.b),
+        a: 1.0,
+    }
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    (1.0 - t) * a + t * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::NullMaterial;
+    use crate::shapes::Sphere;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn henyey_greenstein_mean_cosine_is_g() {
+        let phase = HenyeyGreenstein::new(0.6);
+        let direction = Vector3::new(0.0, 0.0, 1.0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 20000;
+        let mean = (0..n)
+            .map(|_| phase.sample(&direction, &mut rng).dot(&direction))
+            .sum::<f64>()
+            / n as f64;
+        assert!((mean - 0.6).abs() < 0.02);
+    }
+
+    #[test]
+    fn chromatic_media_transmit_clear_channels_to_infinity() {
+        // Red passes through without extinction, as through an atmosphere on rays leaving the scene.
+        let medium = HomogeneousMedium::new(
+            &Color {
+                r: 0.0,
+                g: 0.5,
+                b: 0.0,
+                a: 1.0,
+            },
+            &Color {
+                r: 0.0,
+                g: 0.0,
+                b: 0.2,
+                a: 1.0,
+            },
+            0.0,
+        );
+        let ray = Ray3::new(Vector3::zero(), Vector3::new(1.0, 0.0, 0.0));
+        let mut rng = StdRng::seed_from_u64(1);
+        let transmittance = medium.transmittance(&ray, 0.0, f64::INFINITY, &mut rng);
+        assert_eq!(
+            (1.0, 0.0, 0.0),
+            (transmittance.r, transmittance.g, transmittance.b)
+        );
+
+        let n = 10000;
+        let mut transmitted = 0.0;
+        for _ in 0..n {
+            let mut weight = Color::white();
+            let scattered = medium.sample(&ray, 0.0, f64::INFINITY, &mut rng, &mut weight);
+            assert!(weight.r.is_finite() && weight.g.is_finite() && weight.b.is_finite());
+            if scattered.is_none() {
+                transmitted += weight.r;
+            }
+        }
+        assert!((transmitted / n as f64 - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn volume_segments_inside_and_outside() {
+        let volume = Volume::new(
+            Arc::new(Sphere {
+                center: Vector3::zero(),
+                radius: 1.0,
+                material: Arc::new(NullMaterial::new()),
+            }),
+            Arc::new(HomogeneousMedium::new(&Color::white(), &Color::white(), 0.0)),
+        );
+        let direction = Vector3::new(1.0, 0.0, 0.0);
+
+        let outside = volume.medium_segments(&Ray3::new(Vector3::new(-3.0, 0.0, 0.0), direction));
+        assert_eq!(1, outside.len());
+        assert!((outside[0].t_min - 2.0).abs() < 1e-6);
+        assert!((outside[0].t_max - 4.0).abs() < 1e-6);
+
+        let inside = volume.medium_segments(&Ray3::new(Vector3::zero(), direction));
+        assert_eq!(1, inside.len());
+        assert_close!(0.0, inside[0].t_min);
+        assert!((inside[0].t_max - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_voxel_grid_reads_dimensions_and_values() {
+        let (dimensions, density) = parse_voxel_grid("# test\n2 1 1\n0.5 1.0\n").unwrap();
+        assert_eq!((2, 1, 1), dimensions);
+        assert_eq!(vec![0.5, 1.0], density);
+        assert!(parse_voxel_grid("2 1 1\n0.5\n").is_err());
+    }
+}
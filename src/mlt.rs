@@ -0,0 +1,398 @@
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::io::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::color::Color;
+use crate::film::Film;
+use crate::integrator::{Integrator, PathTracer};
+use crate::io::{InputStream, OutputStream};
+use crate::light::luminance;
+use crate::math::{Ray3, Vector2};
+use crate::sampler::{stream_seed, Distribution1D, PrimarySampleStream};
+use crate::scene::Scene;
+use crate::serialize::{invalid_data, write_tagged, Deserialize, Serialize};
+use crate::stats::Helpers;
+use crate::TraceContext;
+
+#[derive(Clone, Debug)]
+pub struct MetropolisConfig {
+    /// Number of independent paths that estimate the brightness of the image and seed the chains.
+    pub bootstrap_samples: usize,
+    /// Number of Markov chains, which are shared out among the render threads.
+    pub chains: usize,
+    /// Average number of mutations per pixel.
+    pub mutations_per_pixel: usize,
+    /// Probability of a mutation replacing all primary samples instead of perturbing them.
+    pub large_step_probability: f64,
+    /// Standard deviation of the perturbations of the primary samples.
+    pub sigma: f64,
+}
+
+impl Default for MetropolisConfig {
+    fn default() -> MetropolisConfig {
+        MetropolisConfig {
+            bootstrap_samples: 100_000,
+            chains: 1000,
+            mutations_per_pixel: 100,
+            large_step_probability: 0.3,
+            sigma: 0.01,
+        }
+    }
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.). Paths are traced by a path tracer
+/// drawing all its random numbers, including the film position, from a `PrimarySampleStream`. Markov chains
+/// mutate these numbers so that paths are visited in proportion to their luminance, which lets the chains
+/// explore small regions of path space that carry much of the light once they have found them.
+/// Every mutation splats both the current and the proposed path, weighted by the acceptance probability.
+/// The chains run in `preprocess`; camera rays traced by the renderer only see the splats.
+pub struct MetropolisIntegrator {
+    config: MetropolisConfig,
+    path_tracer: PathTracer,
+}
+
+impl Integrator for MetropolisIntegrator {
+    fn preprocess(&self, scene: &Scene, film: &Film) {
+        let bootstrap = self.bootstrap(scene, film);
+        // The average luminance of all paths normalizes the chains, which only know relative brightness.
+        let brightness = bootstrap.integral();
+        if brightness <= 0.0 {
+            return;
+        }
+
+        let num_pixels = film.width as usize * film.height as usize;
+        let total_mutations = self.config.mutations_per_pixel * num_pixels;
+        let chains = self.config.chains.min(total_mutations);
+        let mutations_per_chain = total_mutations / chains;
+        let scale = brightness * num_pixels as f64 / (chains * mutations_per_chain) as f64;
+
+        let next_chain = AtomicUsize::new(0);
+        let helpers = Helpers::new();
+        thread::scope(|scope| {
+            for _ in 0..film.num_threads.max(1) {
+                scope.spawn(|| {
+                    helpers.run(|| loop {
+                        let chain = next_chain.fetch_add(1, Ordering::Relaxed);
+                        if chain >= chains {
+                            break;
+                        }
+                        self.run_chain(scene, film, &bootstrap, chain, mutations_per_chain, scale);
+                    })
+                });
+            }
+        });
+        helpers.finish();
+    }
+
+    /// All light reaches the film through the splats of the chains.
+    fn radiance(
+        &self,
+        _: &Scene,
+        _: &Film,
+        _: &TraceContext,
+        _: &Ray3,
+        _: &mut dyn RngCore,
+    ) -> Color {
+        Color::black()
+    }
+
+    fn splats_only(&self) -> bool {
+        true
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "metropolis", self)
+    }
+}
+
+impl Serialize for MetropolisIntegrator {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        let config = &self.config;
+        [
+            config.bootstrap_samples,
+            config.chains,
+            config.mutations_per_pixel,
+        ]
+        .write_to(out)?;
+        config.large_step_probability.write_to(out)?;
+        config.sigma.write_to(out)
+    }
+}
+
+impl Deserialize for MetropolisIntegrator {
+    fn read_from(input: &mut InputStream) -> Result<MetropolisIntegrator> {
+        let [bootstrap_samples, chains, mutations_per_pixel] = <[usize; 3]>::read_from(input)?;
+        if bootstrap_samples == 0 || chains == 0 {
+            return Err(invalid_data("Invalid Metropolis config"));
+        }
+        Ok(MetropolisIntegrator::new(&MetropolisConfig {
+            bootstrap_samples,
+            chains,
+            mutations_per_pixel,
+            large_step_probability: f64::read_from(input)?,
+            sigma: f64::read_from(input)?,
+        }))
+    }
+}
+
+impl MetropolisIntegrator {
+    pub fn new(config: &MetropolisConfig) -> MetropolisIntegrator {
+        expect_neq!(config.bootstrap_samples, 0);
+        expect_neq!(config.chains, 0);
+        MetropolisIntegrator {
+            config: config.clone(),
+            path_tracer: PathTracer::new(),
+        }
+    }
+
+    /// Traces independent paths with the streams that seed the chains and returns the distribution of their
+    /// luminance. Its integral is the average luminance of a path.
+    fn bootstrap(&self, scene: &Scene, film: &Film) -> Distribution1D {
+        let count = self.config.bootstrap_samples;
+        let num_threads = film.num_threads.max(1) as usize;
+        let mut weights = vec![0.0; count];
+        let chunk_size = count.div_ceil(num_threads);
+        let helpers = Helpers::new();
+        thread::scope(|scope| {
+            for (chunk, weights) in weights.chunks_mut(chunk_size).enumerate() {
+                let helpers = &helpers;
+                scope.spawn(move || {
+                    helpers.run(|| {
+                        for (i, weight) in weights.iter_mut().enumerate() {
+                            let mut stream = self.stream(film, chunk * chunk_size + i);
+                            *weight = luminance(&self.trace(scene, film, &mut stream).2);
+                        }
+                    })
+                });
+            }
+        });
+        helpers.finish();
+        Distribution1D::new(&weights)
+    }
+
+    /// The primary sample stream of the bootstrap path `index`.
+    fn stream(&self, film: &Film, index: usize) -> PrimarySampleStream {
+        PrimarySampleStream::new(
+            stream_seed(film.seed, index as u64),
+            self.config.sigma,
+            self.config.large_step_probability,
+        )
+    }
+
+    /// Starts a chain at a bootstrap path chosen in proportion to its luminance and mutates it.
+    fn run_chain(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        bootstrap: &Distribution1D,
+        chain: usize,
+        mutations: usize,
+        scale: f64,
+    ) {
+        // The chains draw from other streams than the bootstrap paths.
+        let mut rng = StdRng::seed_from_u64(stream_seed(!film.seed, chain as u64));
+        let (_, _, index) = bootstrap.sample_continuous(rng.gen());
+        // A stream with the seed of the bootstrap path replays it.
+        let mut stream = self.stream(film, index);
+        let mut current = self.trace(scene, film, &mut stream);
+        let mut current_luminance = luminance(&current.2);
+
+        for _ in 0..mutations {
+            stream.start_iteration();
+            let proposed = self.trace(scene, film, &mut stream);
+            let proposed_luminance = luminance(&proposed.2);
+            let acceptance = if current_luminance > 0.0 {
+                (proposed_luminance / current_luminance).min(1.0)
+            } else {
+                1.0
+            };
+
+            if proposed_luminance > 0.0 {
+                film.splat_unscaled(
+                    proposed.0,
+                    proposed.1,
+                    &(proposed.2 * (acceptance * scale / proposed_luminance)),
+                );
+            }
+            if current_luminance > 0.0 {
+                film.splat_unscaled(
+                    current.0,
+                    current.1,
+                    &(current.2 * ((1.0 - acceptance) * scale / current_luminance)),
+                );
+            }
+
+            if rng.gen::<f64>() < acceptance {
+                current = proposed;
+                current_luminance = proposed_luminance;
+                stream.accept();
+            } else {
+                stream.reject();
+            }
+        }
+    }
+
+    /// Traces the path given by the primary samples of `stream`. Returns the pixel it passes and its radiance.
+    fn trace(
+        &self,
+        scene: &Scene,
+        film: &Film,
+        stream: &mut PrimarySampleStream,
+    ) -> (u32, u32, Color) {
+        let raster = Vector2::new(
+            stream.next_sample() * film.width as f64,
+            stream.next_sample() * film.height as f64,
+        );
+        let x = (raster.x as u32).min(film.width - 1);
+        let y = (raster.y as u32).min(film.height - 1);
+
+        // The same film coordinates as those of the camera rays of the renderer.
+        let half = Vector2::new(0.5, 0.5);
+        let image_dim = Vector2::new(film.width as f64, film.height as f64);
+        let film_position = film.pixel_size * (raster - 0.5 * image_dim - half);
+        let ray = film
+            .camera()
+            .generate_ray(film_position.x, -film_position.y);
+
+        let trace_context = TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        };
+        let radiance = self
+            .path_tracer
+            .radiance(scene, film, &trace_context, &ray, stream);
+        (x, y, radiance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Camera, OrthographicCamera};
+    use crate::light::SphereLight;
+    use crate::material::{Lambertian, Material};
+    use crate::math::Vector3;
+    use crate::sampler::HemiSphereSampler;
+    use crate::shapes::{Hitable, Intersection, Plane};
+    use std::sync::Arc;
+
+    #[test]
+    fn small_steps_perturb_scattered_directions_slightly() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(
+            &HemiSphereSampler::jittered_sampler(8, 1.0),
+            &Color::white(),
+        ));
+        let ray = Ray3::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let intersection = Intersection {
+            ray,
+            t: 1.0,
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            uv: Vector2::new(0.0, 0.0),
+            material: Arc::clone(&material),
+        };
+        let mut context = TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        };
+        let mut scatter = |stream: &mut PrimarySampleStream| {
+            let mut attenuation = Color::black();
+            let mut scattered = ray;
+            material.scatter(
+                &mut context,
+                &ray,
+                &intersection,
+                stream,
+                &mut attenuation,
+                &mut scattered,
+            );
+            scattered.direction
+        };
+
+        // Small steps perturb the direction slightly, unless a primary sample wraps around the unit square,
+        // but many of them wander far from the first direction.
+        let mut stream = PrimarySampleStream::new(1, 0.01, 0.0);
+        let first = scatter(&mut stream);
+        let mut current = first;
+        let mut close = 0;
+        let mut farthest = 1.0f64;
+        for _ in 0..1000 {
+            stream.start_iteration();
+            let proposed = scatter(&mut stream);
+            if proposed.dot(&current) > 0.95 {
+                close += 1;
+            }
+            farthest = farthest.min(proposed.dot(&first));
+            stream.accept();
+            current = proposed;
+        }
+        assert!(close > 950, "{}", close);
+        assert!(farthest < 0.5, "{}", farthest);
+    }
+
+    #[test]
+    fn chains_preserve_the_brightness_of_a_lit_plane() {
+        let objects: Vec<Arc<dyn Hitable>> = vec![Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &(Color::white() * 0.5),
+            )),
+        })];
+        let scene = Scene {
+            objects: Arc::new(objects),
+            lights: vec![Arc::new(SphereLight {
+                center: Vector3::new(0.0, 3.0, 0.0),
+                radius: 0.5,
+                radiance: Color::white() * 10.0,
+            })],
+            atmosphere: None,
+            ambient_color: Color::black(),
+            max_trace_depth: 8,
+        };
+        let camera: Arc<dyn Camera> = Arc::new(OrthographicCamera::new(
+            &Vector3::new(0.0, 1.0, 0.0),
+            &Vector3::zero(),
+            &Vector3::new(0.0, 0.0, -1.0),
+        ));
+        let film = Film::new(8, 6, 0.1, &camera);
+
+        let mlt = MetropolisIntegrator::new(&MetropolisConfig {
+            bootstrap_samples: 20_000,
+            chains: 16,
+            mutations_per_pixel: 100,
+            ..MetropolisConfig::default()
+        });
+        mlt.preprocess(&scene, &film);
+
+        let mut mean = 0.0;
+        let mut expected = 0.0;
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let splat = film.get_unscaled_splat(x, y).g;
+                assert!(splat > 0.0);
+                mean += splat;
+
+                // A sphere light of radiance L and radius r at height h lights a diffuse surface of albedo rho
+                // at distance d to rho * L * r^2 * h / d^3.
+                let dx = film.pixel_size * (x as f64 - 4.0);
+                let dz = film.pixel_size * (y as f64 - 3.0);
+                let d = (9.0 + dx * dx + dz * dz).sqrt();
+                expected += 0.5 * 10.0 * 0.25 * 3.0 / (d * d * d);
+            }
+        }
+        assert!(
+            (mean - expected).abs() < 0.02 * expected,
+            "{} vs {}",
+            mean,
+            expected
+        );
+    }
+}
@@ -0,0 +1,735 @@
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f64::consts::PI;
+use std::io::Result;
+use std::sync::{Arc, RwLock};
+
+use crate::color::Color;
+use crate::film::Film;
+use crate::integrator::Integrator;
+use crate::io::{InputStream, OutputStream};
+use crate::light::Light;
+use crate::math::{Ray3, Vector2, Vector3};
+use crate::scene::{power_heuristic, Scene};
+use crate::serialize::{invalid_data, write_tagged, Deserialize, Serialize};
+use crate::shapes::Intersection;
+use crate::TraceContext;
+
+/// A packet of light flux arriving at a diffuse surface.
+#[derive(Clone, Copy, Debug)]
+pub struct Photon {
+    pub position: Vector3,
+    /// Direction the photon travelled in.
+    pub direction: Vector3,
+    pub power: Color,
+    /// Whether the photon was scattered diffusely before, i.e. carries indirect light.
+    pub indirect: bool,
+}
+
+/// Photons stored in a balanced kd-tree for nearest neighbour queries. The tree is implicit: every subrange
+/// of `photons` has its splitting photon in the middle and the photons on either side in the halves.
+#[derive(Clone, Debug, Default)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Neighbour {
+    distance_squared: f64,
+    index: usize,
+}
+
+impl PartialEq for Neighbour {
+    fn eq(&self, other: &Neighbour) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbour {}
+
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Neighbour) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Neighbour) -> Ordering {
+        self.distance_squared.total_cmp(&other.distance_squared)
+    }
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Returns up to `count` photons nearest to `point` within `max_distance` that are accepted by `filter`,
+    /// together with their squared distances, nearest first.
+    pub fn nearest(
+        &self,
+        point: &Vector3,
+        count: usize,
+        max_distance: f64,
+        filter: &dyn Fn(&Photon) -> bool,
+    ) -> Vec<(f64, &Photon)> {
+        let mut heap = BinaryHeap::with_capacity(count + 1);
+        let mut max_distance_squared = max_distance * max_distance;
+        if count > 0 {
+            self.search(
+                0,
+                self.photons.len(),
+                point,
+                count,
+                filter,
+                &mut heap,
+                &mut max_distance_squared,
+            );
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|n| (n.distance_squared, &self.photons[n.index]))
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search(
+        &self,
+        begin: usize,
+        end: usize,
+        point: &Vector3,
+        count: usize,
+        filter: &dyn Fn(&Photon) -> bool,
+        heap: &mut BinaryHeap<Neighbour>,
+        max_distance_squared: &mut f64,
+    ) {
+        if begin >= end {
+            return;
+        }
+
+        let middle = begin + (end - begin) / 2;
+        let photon = &self.photons[middle];
+        let axis = self.axes[middle] as usize;
+        let delta = component(point, axis) - component(&photon.position, axis);
+        let (near, far) = if delta < 0.0 {
+            ((begin, middle), (middle + 1, end))
+        } else {
+            ((middle + 1, end), (begin, middle))
+        };
+
+        self.search(
+            near.0,
+            near.1,
+            point,
+            count,
+            filter,
+            heap,
+            max_distance_squared,
+        );
+
+        let distance_squared = (photon.position - *point).length_squared();
+        if distance_squared < *max_distance_squared && filter(photon) {
+            heap.push(Neighbour {
+                distance_squared,
+                index: middle,
+            });
+            if heap.len() > count {
+                heap.pop();
+            }
+            if heap.len() == count {
+                *max_distance_squared = heap.peek().unwrap().distance_squared;
+            }
+        }
+
+        if delta * delta < *max_distance_squared {
+            self.search(
+                far.0,
+                far.1,
+                point,
+                count,
+                filter,
+                heap,
+                max_distance_squared,
+            );
+        }
+    }
+
+    /// Estimates the radiance reflected at `intersection` towards the origin of its ray from the density of
+    /// the `count` nearest photons accepted by `filter`.
+    pub fn radiance(
+        &self,
+        intersection: &Intersection,
+        count: usize,
+        max_distance: f64,
+        filter: &dyn Fn(&Photon) -> bool,
+    ) -> Color {
+        let neighbours = self.nearest(&intersection.point, count, max_distance, filter);
+        if neighbours.is_empty() {
+            return Color::black();
+        }
+
+        // With all photons found the search radius is the distance to the farthest one.
+        let radius_squared = if neighbours.len() == count {
+            neighbours[neighbours.len() - 1].0
+        } else {
+            max_distance * max_distance
+        };
+        if radius_squared <= 0.0 {
+            return Color::black();
+        }
+
+        let material = &intersection.material;
+        let side = -intersection.ray.direction.dot(&intersection.normal);
+        let mut flux = Color::black();
+        for (_, photon) in neighbours {
+            let incoming = -photon.direction;
+            let cos_theta = incoming.dot(&intersection.normal);
+            if cos_theta * side <= 0.0 {
+                continue;
+            }
+            let f = material.evaluate(&intersection.ray, intersection, &incoming)
+                * (1.0 / cos_theta.abs());
+            flux += f * photon.power;
+        }
+        flux * (1.0 / (PI * radius_squared))
+    }
+}
+
+fn component(v: &Vector3, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let mut min = photons[0].position;
+    let mut max = photons[0].position;
+    for photon in photons.iter() {
+        let p = photon.position;
+        min = Vector3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+        max = Vector3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        component(&a.position, axis).total_cmp(&component(&b.position, axis))
+    });
+    axes[middle] = axis as u8;
+
+    let (left, right) = photons.split_at_mut(middle);
+    let (left_axes, right_axes) = axes.split_at_mut(middle);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+#[derive(Clone, Debug)]
+pub struct PhotonMapperConfig {
+    /// Number of photons stored in the global map, which holds every photon arriving at a diffuse surface.
+    pub global_photons: usize,
+    /// Number of photons stored in the caustic map, which holds photons that reached a diffuse surface
+    /// through specular reflections and refractions only.
+    pub caustic_photons: usize,
+    /// Number of nearest photons used for a radiance estimate.
+    pub gather_count: usize,
+    /// Upper bound on the radius of a radiance estimate.
+    pub max_gather_distance: f64,
+    /// Number of rays shot at every visible diffuse surface to estimate indirect light from the global map
+    /// at the surfaces they hit. With zero the global map is looked up directly at the visible surface,
+    /// which is faster but shows the low frequency noise of the photon density.
+    pub final_gather_rays: u32,
+}
+
+impl Default for PhotonMapperConfig {
+    fn default() -> PhotonMapperConfig {
+        PhotonMapperConfig {
+            global_photons: 200_000,
+            caustic_photons: 100_000,
+            gather_count: 100,
+            max_gather_distance: 0.5,
+            final_gather_rays: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct PhotonMaps {
+    global: PhotonMap,
+    caustic: PhotonMap,
+}
+
+/// Photon mapping. Before rendering, photons are traced from the area lights and stored in a global and a
+/// caustic map. Camera rays follow specular surfaces up to the first diffuse one, where direct light is
+/// computed by light sampling, caustics are looked up in the caustic map and indirect light comes from the
+/// global map, optionally through a final gather step. Participating media are ignored.
+pub struct PhotonMapper {
+    config: PhotonMapperConfig,
+    maps: RwLock<Arc<PhotonMaps>>,
+}
+
+impl Integrator for PhotonMapper {
+    fn preprocess(&self, scene: &Scene, film: &Film) {
+        let mut rng = StdRng::seed_from_u64(film.seed);
+        let global = self.trace_photons(scene, self.config.global_photons, false, &mut rng);
+        let caustic = self.trace_photons(scene, self.config.caustic_photons, true, &mut rng);
+        *self.maps.write().unwrap() = Arc::new(PhotonMaps {
+            global: PhotonMap::new(global),
+            caustic: PhotonMap::new(caustic),
+        });
+    }
+
+    fn radiance(
+        &self,
+        scene: &Scene,
+        _: &Film,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let maps = Arc::clone(&self.maps.read().unwrap());
+        self.trace_ray(scene, &maps, trace_context, ray, 0, rng)
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "photons", self)
+    }
+}
+
+impl Serialize for PhotonMapper {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        let config = &self.config;
+        [
+            config.global_photons,
+            config.caustic_photons,
+            config.gather_count,
+        ]
+        .write_to(out)?;
+        config.max_gather_distance.write_to(out)?;
+        config.final_gather_rays.write_to(out)
+    }
+}
+
+impl Deserialize for PhotonMapper {
+    fn read_from(input: &mut InputStream) -> Result<PhotonMapper> {
+        let [global_photons, caustic_photons, gather_count] = <[usize; 3]>::read_from(input)?;
+        if gather_count == 0 {
+            return Err(invalid_data("Invalid photon mapper config"));
+        }
+        Ok(PhotonMapper::new(&PhotonMapperConfig {
+            global_photons,
+            caustic_photons,
+            gather_count,
+            max_gather_distance: f64::read_from(input)?,
+            final_gather_rays: u32::read_from(input)?,
+        }))
+    }
+}
+
+impl PhotonMapper {
+    pub fn new(config: &PhotonMapperConfig) -> PhotonMapper {
+        expect_neq!(config.gather_count, 0);
+        PhotonMapper {
+            config: config.clone(),
+            maps: RwLock::new(Arc::new(PhotonMaps::default())),
+        }
+    }
+
+    /// Emits photons from the area lights until `count` photons are stored or too many photons got lost.
+    /// For the caustic map only photons reaching a diffuse surface after specular bounces are kept.
+    fn trace_photons(
+        &self,
+        scene: &Scene,
+        count: usize,
+        caustics_only: bool,
+        rng: &mut dyn RngCore,
+    ) -> Vec<Photon> {
+        let lights: Vec<&Arc<dyn Light>> =
+            scene.lights.iter().filter(|l| !l.is_infinite()).collect();
+        let mut photons = Vec::with_capacity(count);
+        if lights.is_empty() || count == 0 {
+            return photons;
+        }
+
+        let max_emitted = 100 * count;
+        let mut emitted = 0;
+        while photons.len() < count && emitted < max_emitted {
+            emitted += 1;
+
+            let index = ((rng.gen::<f64>() * lights.len() as f64) as usize).min(lights.len() - 1);
+            let pdf_choice = 1.0 / lights.len() as f64;
+            let u_position = Vector2::new(rng.gen(), rng.gen());
+            let u_direction = Vector2::new(rng.gen(), rng.gen());
+            let emission = match lights[index].sample_emission(u_position, u_direction) {
+                Some(emission) if emission.pdf_position > 0.0 && emission.pdf_direction > 0.0 => {
+                    emission
+                }
+                _ => continue,
+            };
+
+            let mut power = emission.radiance
+                * (emission.normal.dot(&emission.ray.direction).abs()
+                    / (emission.pdf_position * emission.pdf_direction * pdf_choice));
+            let mut ray = Ray3::new(
+                emission.ray.origin + emission.normal * 0.01,
+                emission.ray.direction,
+            );
+            let mut specular_bounces = 0;
+            let mut diffuse_bounces = 0;
+
+            for _ in 0..scene.max_trace_depth {
+                let intersection = match scene.intersect(&ray) {
+                    Some(intersection) => intersection,
+                    None => break,
+                };
+                if scene
+                    .intersect_light(&ray)
+                    .is_some_and(|hit| hit.0.t < intersection.t)
+                {
+                    break;
+                }
+
+                let material = &intersection.material;
+                let mut trace_context = TraceContext {
+                    set_index: 0,
+                    sample_index: 0,
+                    dimension: 0,
+                    wavelengths: None,
+                };
+                let mut scattered = Ray3::default();
+                let mut attenuation = Color::black();
+                let scatters = material.scatter(
+                    &mut trace_context,
+                    &ray,
+                    &intersection,
+                    rng,
+                    &mut attenuation,
+                    &mut scattered,
+                );
+                let diffuse =
+                    scatters && material.pdf(&ray, &intersection, &scattered.direction) > 0.0;
+
+                if diffuse {
+                    let photon = Photon {
+                        position: intersection.point,
+                        direction: ray.direction,
+                        power,
+                        indirect: diffuse_bounces > 0,
+                    };
+                    if caustics_only {
+                        if specular_bounces > 0 {
+                            photons.push(photon);
+                        }
+                        break;
+                    }
+                    photons.push(photon);
+                    diffuse_bounces += 1;
+                } else {
+                    specular_bounces += 1;
+                }
+
+                if !scatters || photons.len() >= count {
+                    break;
+                }
+
+                if diffuse {
+                    // Russian roulette keeps the photon powers roughly constant.
+                    let survival = attenuation.r.max(attenuation.g).max(attenuation.b).min(1.0);
+                    if survival <= 0.0 || rng.gen::<f64>() >= survival {
+                        break;
+                    }
+                    power = power * attenuation * (1.0 / survival);
+                } else {
+                    power = power * attenuation;
+                }
+                ray = scattered;
+            }
+        }
+
+        let scale = 1.0 / emitted as f64;
+        for photon in photons.iter_mut() {
+            photon.power = photon.power * scale;
+        }
+        photons
+    }
+
+    fn trace_ray(
+        &self,
+        scene: &Scene,
+        maps: &PhotonMaps,
+        trace_context: &TraceContext,
+        ray: &Ray3,
+        depth: u32,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let intersection = scene.intersect(ray);
+        let t_surface = intersection.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+        if let Some((hit, _)) = scene.intersect_light(ray).filter(|hit| hit.0.t < t_surface) {
+            return hit.radiance;
+        }
+        let intersection = match intersection {
+            Some(intersection) => intersection,
+            None => return scene.background(ray, None),
+        };
+
+        let material = &intersection.material;
+        let mut scattered = Ray3::default();
+        let mut attenuation = Color::black();
+        let scatters = material.scatter(
+            &mut trace_context.clone(),
+            ray,
+            &intersection,
+            rng,
+            &mut attenuation,
+            &mut scattered,
+        );
+
+        if material.pdf(ray, &intersection, &scattered.direction) > 0.0 {
+            let light_origin = intersection.point + intersection.normal * 0.01;
+            let direct =
+                scene.direct_lighting(&light_origin, trace_context, rng, true, &|direction| {
+                    (
+                        material.evaluate(ray, &intersection, direction),
+                        material.pdf(ray, &intersection, direction),
+                    )
+                });
+            let caustics = maps.caustic.radiance(
+                &intersection,
+                self.config.gather_count,
+                self.config.max_gather_distance,
+                &|_| true,
+            );
+            direct + caustics + self.indirect(scene, maps, &intersection, rng)
+        } else if scatters && depth < scene.max_trace_depth {
+            self.trace_ray(
+                scene,
+                maps,
+                &trace_context.next_dimension(),
+                &scattered,
+                depth + 1,
+                rng,
+            ) * attenuation
+        } else if scatters {
+            Color::black()
+        } else {
+            attenuation
+        }
+    }
+
+    /// Estimates the light arriving at `intersection` after at least one diffuse bounce elsewhere.
+    fn indirect(
+        &self,
+        scene: &Scene,
+        maps: &PhotonMaps,
+        intersection: &Intersection,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        if self.config.final_gather_rays == 0 {
+            return maps.global.radiance(
+                intersection,
+                self.config.gather_count,
+                self.config.max_gather_distance,
+                &|photon| photon.indirect,
+            );
+        }
+
+        let material = &intersection.material;
+        let ray = &intersection.ray;
+        let mut indirect = Color::black();
+        for _ in 0..self.config.final_gather_rays {
+            let mut trace_context = TraceContext {
+                set_index: 0,
+                sample_index: 0,
+                dimension: 0,
+                wavelengths: None,
+            };
+            let mut gather_ray = Ray3::default();
+            let mut attenuation = Color::black();
+            if !material.scatter(
+                &mut trace_context,
+                ray,
+                intersection,
+                rng,
+                &mut attenuation,
+                &mut gather_ray,
+            ) {
+                continue;
+            }
+            let pdf = material.pdf(ray, intersection, &gather_ray.direction);
+            indirect += attenuation * self.gather(scene, maps, &gather_ray, pdf, 0, rng);
+        }
+        indirect * (1.0 / self.config.final_gather_rays as f64)
+    }
+
+    /// Follows a gather ray through specular surfaces and returns the radiance of the global map at the
+    /// first diffuse surface. Lights are weighted against the light sampling done at the gathering surface.
+    fn gather(
+        &self,
+        scene: &Scene,
+        maps: &PhotonMaps,
+        ray: &Ray3,
+        scatter_pdf: f64,
+        depth: u32,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let intersection = scene.intersect(ray);
+        let t_surface = intersection.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+        if let Some((hit, light)) = scene.intersect_light(ray).filter(|hit| hit.0.t < t_surface) {
+            return if depth == 0 {
+                hit.radiance * power_heuristic(scatter_pdf, light.pdf(&ray.origin, &ray.direction))
+            } else {
+                hit.radiance
+            };
+        }
+        let intersection = match intersection {
+            Some(intersection) => intersection,
+            None if depth == 0 => return scene.background(ray, Some(scatter_pdf)),
+            None => return scene.background(ray, None),
+        };
+
+        let material = &intersection.material;
+        let mut trace_context = TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        };
+        let mut scattered = Ray3::default();
+        let mut attenuation = Color::black();
+        let scatters = material.scatter(
+            &mut trace_context,
+            ray,
+            &intersection,
+            rng,
+            &mut attenuation,
+            &mut scattered,
+        );
+
+        if material.pdf(ray, &intersection, &scattered.direction) > 0.0 {
+            maps.global.radiance(
+                &intersection,
+                self.config.gather_count,
+                self.config.max_gather_distance,
+                &|_| true,
+            )
+        } else if scatters && depth < scene.max_trace_depth {
+            self.gather(scene, maps, &scattered, scatter_pdf, depth + 1, rng) * attenuation
+        } else {
+            Color::black()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::SphereLight;
+    use crate::material::Lambertian;
+    use crate::sampler::HemiSphereSampler;
+    use crate::scene::is_black;
+    use crate::shapes::{Hitable, Plane};
+
+    #[test]
+    fn nearest_photons_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let photons: Vec<Photon> = (0..500)
+            .map(|_| Photon {
+                position: Vector3::new(rng.gen(), rng.gen(), rng.gen()),
+                direction: Vector3::new(0.0, -1.0, 0.0),
+                power: Color::white(),
+                indirect: false,
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone());
+        let point = Vector3::new(0.4, 0.5, 0.6);
+
+        let mut expected: Vec<f64> = photons
+            .iter()
+            .map(|p| (p.position - point).length_squared())
+            .collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let found: Vec<f64> = map
+            .nearest(&point, 10, 1.0, &|_| true)
+            .iter()
+            .map(|n| n.0)
+            .collect();
+        assert_eq!(expected[..10].to_vec(), found);
+    }
+
+    #[test]
+    fn photon_density_matches_direct_light() {
+        let plane: Arc<dyn Hitable> = Arc::new(Plane {
+            point: Vector3::zero(),
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            material: Arc::new(Lambertian::new(
+                &HemiSphereSampler::jittered_sampler(8, 1.0),
+                &(Color::white() * 0.5),
+            )),
+        });
+        let scene = Scene {
+            objects: Arc::new(vec![Arc::clone(&plane)]),
+            lights: vec![Arc::new(SphereLight {
+                center: Vector3::new(0.0, 3.0, 0.0),
+                radius: 0.5,
+                radiance: Color::white() * 10.0,
+            })],
+            atmosphere: None,
+            ambient_color: Color::black(),
+            max_trace_depth: 4,
+        };
+        let mapper = PhotonMapper::new(&PhotonMapperConfig::default());
+        let photons = mapper.trace_photons(&scene, 200_000, false, &mut StdRng::seed_from_u64(1));
+        let map = PhotonMap::new(photons);
+
+        let ray = Ray3::new(
+            Vector3::new(1.0, 1.0, 0.0),
+            Vector3::new(-1.0, -1.0, 0.0).normalized(),
+        );
+        let intersection = plane.intersect(&ray).unwrap();
+        let radiance = map.radiance(&intersection, 1600, 1.0, &|_| true);
+
+        // A sphere light of radiance L subtending sin(alpha) = r / d lights a diffuse surface of albedo rho
+        // facing it to rho * L * (r / d)^2.
+        let expected = 0.5 * 10.0 * 0.25 / 9.0;
+        assert!(
+            (radiance.g - expected).abs() < 0.15 * expected,
+            "{} vs {}",
+            radiance.g,
+            expected
+        );
+
+        // Direct light is not part of the indirect estimate.
+        assert!(is_black(&map.radiance(
+            &intersection,
+            1600,
+            1.0,
+            &|photon| photon.indirect
+        )));
+    }
+}
@@ -0,0 +1,61 @@
+//! Feedback from running renders and their cancellation.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The state of a render after a tile has been traced.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// The pass the tile belongs to.
+    pub pass: u32,
+    /// Tiles traced so far, counting every pass.
+    pub tiles_done: usize,
+    /// Tiles of all passes of the render. Renders with a budget count up to the end of the current pass.
+    pub tiles_total: usize,
+    /// Camera rays traced so far.
+    pub camera_rays: u64,
+    /// Time since the render started, or since it was resumed.
+    pub elapsed: Duration,
+    /// Estimated time until the last pass is done, from the rate at which tiles were traced so far.
+    pub eta: Duration,
+}
+
+impl Progress {
+    /// The fraction of the render that is done, between 0 and 1.
+    pub fn fraction(&self) -> f64 {
+        self.tiles_done as f64 / self.tiles_total as f64
+    }
+}
+
+/// Receives the progress of a render. It is called by the render threads, possibly at the same time.
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress) + Send + Sync> ProgressObserver for F {
+    fn on_progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Stops a render from another thread. The render threads finish the pixels they are tracing and leave
+/// the image of all samples traced so far. Clones share the token, and a cancelled token stays cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
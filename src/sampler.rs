@@ -1,30 +1,74 @@
 use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::{self, Rng};
+use rand::{self, Rng, RngCore, SeedableRng};
 use std::f64::consts::PI;
 
 use crate::math::{Vector2, Vector3};
+use crate::warp::{cosine_power_hemisphere, cosine_power_hemisphere_pdf, uniform_sphere};
+use crate::TraceContext;
 
 pub trait Sampler<T>
 where
     T: Copy + Clone,
 {
+    /// Returns sample `sample_index` of set `set_index`. Both indices wrap around, so any pair is valid.
     fn sample(&self, set_index: usize, sample_index: usize) -> T;
+
+    /// Returns the sample of the current dimension of a path. Every dimension draws from another set, so
+    /// the samples of a pixel stay stratified in each dimension without being correlated across dimensions.
+    fn sample_for(&self, trace_context: &TraceContext) -> T {
+        let set_index = trace_context
+            .set_index
+            .wrapping_add(trace_context.dimension.wrapping_mul(DIMENSION_SET_STRIDE));
+        self.sample(set_index, trace_context.sample_index)
+    }
 }
 
+/// Offset between the sets of consecutive dimensions, a prime that does not divide the usual 83 sets.
+const DIMENSION_SET_STRIDE: usize = 37;
+
+/// Precomputed sets of samples of a pattern, e.g. points in the unit square or directions. The sets hold
+/// the same samples in different orders, or different randomizations of the pattern.
 #[derive(Clone, Debug)]
-pub struct UnitSquareSampler {
-    pub samples: Vec<Vec<Vector2>>,
+pub struct PatternSampler<T> {
+    pub samples: Vec<Vec<T>>,
 }
 
-impl Sampler<Vector2> for UnitSquareSampler {
-    fn sample(&self, set_index: usize, sample_index: usize) -> Vector2 {
-        let mod_set_index = self.samples.len() % set_index;
-        let mod_sample_index = self.samples[mod_set_index].len() % sample_index;
-        self.samples[mod_set_index][mod_sample_index]
+impl<T: Copy> Sampler<T> for PatternSampler<T> {
+    fn sample(&self, set_index: usize, sample_index: usize) -> T {
+        let set = &self.samples[set_index % self.samples.len()];
+        set[sample_index % set.len()]
+    }
+}
+
+impl<T: Copy> PatternSampler<T> {
+    pub fn num_sets(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn samples_per_set(&self) -> usize {
+        self.samples[0].len()
+    }
+
+    /// Maps every sample of every set, e.g. with a warp from the unit square to another domain.
+    pub fn map<U>(&self, f: impl Fn(T) -> U) -> PatternSampler<U> {
+        PatternSampler {
+            samples: self
+                .samples
+                .iter()
+                .map(|set| set.iter().map(|&sample| f(sample)).collect())
+                .collect(),
+        }
     }
 }
 
+/// Points in the unit square.
+pub type UnitSquareSampler = PatternSampler<Vector2>;
+
+/// Points in or on the unit sphere.
+pub type UnitSphereSampler = PatternSampler<Vector3>;
+
 impl UnitSquareSampler {
     pub fn standard_sampler() -> UnitSquareSampler {
         UnitSquareSampler {
@@ -46,13 +90,13 @@ impl UnitSquareSampler {
         }
 
         UnitSquareSampler {
-            samples: create_shuffled_samples(&samples, 83),
+            samples: create_shuffled_samples(&samples, 83, &mut pattern_rng()),
         }
     }
 
     pub fn jittered_sampler(samples_per_axis: usize) -> UnitSquareSampler {
         let mut samples = Vec::with_capacity(samples_per_axis * samples_per_axis);
-        let mut rng = rand::thread_rng();
+        let mut rng = pattern_rng();
         let between = Uniform::new(0.0f64, 1.0);
         let box_dim = 1.0 / (samples_per_axis as f64);
 
@@ -65,64 +109,263 @@ impl UnitSquareSampler {
         }
 
         UnitSquareSampler {
-            samples: create_shuffled_samples(&samples, 83),
+            samples: create_shuffled_samples(&samples, 83, &mut rng),
+        }
+    }
+
+    /// Correlated multi-jittered samples (Kensler): the samples are jittered in an m x n grid of cells and
+    /// also in the m * n fine strata along each axis, shuffling whole rows and columns of the fine grid.
+    /// `num_samples` does not need to be a square; the grid is then filled only partly, leaving different
+    /// cells empty in every set.
+    pub fn multi_jittered_sampler(num_samples: usize) -> UnitSquareSampler {
+        expect_neq!(num_samples, 0);
+        let m = ((num_samples as f64).sqrt().round() as usize).max(1);
+        let n = num_samples.div_ceil(m);
+        let mut rng = pattern_rng();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let mut columns: Vec<usize> = (0..m).collect();
+                    let mut rows: Vec<usize> = (0..n).collect();
+                    let mut cells: Vec<usize> = (0..m * n).collect();
+                    columns.shuffle(&mut rng);
+                    rows.shuffle(&mut rng);
+                    cells.shuffle(&mut rng);
+                    (0..num_samples)
+                        .map(|s| {
+                            let (column, row) = (cells[s] % m, cells[s] / m);
+                            let x = (column as f64
+                                + (rows[row] as f64 + rng.gen::<f64>()) / n as f64)
+                                / m as f64;
+                            let y = (row as f64
+                                + (columns[column] as f64 + rng.gen::<f64>()) / m as f64)
+                                / n as f64;
+                            Vector2::new(x, y)
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// N-rooks or Latin hypercube samples: every one of the `num_samples` strata along each axis holds
+    /// exactly one sample.
+    pub fn n_rooks_sampler(num_samples: usize) -> UnitSquareSampler {
+        expect_neq!(num_samples, 0);
+        let mut rng = pattern_rng();
+        let stratum =
+            |index: usize, rng: &mut StdRng| (index as f64 + rng.gen::<f64>()) / num_samples as f64;
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let mut rows: Vec<usize> = (0..num_samples).collect();
+                    rows.shuffle(&mut rng);
+                    (0..num_samples)
+                        .map(|i| Vector2::new(stratum(i, &mut rng), stratum(rows[i], &mut rng)))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// Blue noise by dart throwing: random candidates are accepted when they keep a minimum distance to all
+    /// samples so far. The distance starts near the densest packing and shrinks whenever darts keep missing.
+    /// Distances wrap around the square, so the pattern tiles.
+    pub fn poisson_disk_sampler(num_samples: usize) -> UnitSquareSampler {
+        expect_neq!(num_samples, 0);
+        let mut rng = pattern_rng();
+        let packing_distance = (2.0 / (3.0f64.sqrt() * num_samples as f64)).sqrt();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let mut samples: Vec<Vector2> = Vec::with_capacity(num_samples);
+                    let mut distance = 0.8 * packing_distance;
+                    let mut misses = 0;
+                    while samples.len() < num_samples {
+                        let candidate = Vector2::new(rng.gen(), rng.gen());
+                        if samples.iter().all(|sample| {
+                            toroidal_distance_squared(sample, &candidate) >= distance * distance
+                        }) {
+                            samples.push(candidate);
+                            misses = 0;
+                        } else {
+                            misses += 1;
+                            if misses == 1000 {
+                                distance *= 0.95;
+                                misses = 0;
+                            }
+                        }
+                    }
+                    samples
+                })
+                .collect(),
+        }
+    }
+
+    pub fn random_sampler(num_samples: usize) -> UnitSquareSampler {
+        let mut rng = pattern_rng();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    (0..num_samples)
+                        .map(|_| Vector2::new(rng.gen(), rng.gen()))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// The Halton sequence in bases 2 and 3. Every set scrambles the digits with its own random permutations.
+    pub fn halton_sampler(num_samples: usize) -> UnitSquareSampler {
+        let mut rng = pattern_rng();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let permutations = [
+                        digit_permutations(2, &mut rng),
+                        digit_permutations(3, &mut rng),
+                    ];
+                    (0..num_samples)
+                        .map(|i| {
+                            Vector2::new(
+                                scrambled_radical_inverse(i as u64, 2, &permutations[0]),
+                                scrambled_radical_inverse(i as u64, 3, &permutations[1]),
+                            )
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// The Hammersley point set, which pairs evenly spaced x with the base 2 radical inverse as y.
+    /// Every set scrambles the bits of y randomly.
+    pub fn hammersley_sampler(num_samples: usize) -> UnitSquareSampler {
+        let mut rng = pattern_rng();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let scramble: u32 = rng.gen();
+                    (0..num_samples)
+                        .map(|i| {
+                            Vector2::new(
+                                (i as f64 + 0.5) / num_samples as f64,
+                                to_unit(van_der_corput(i as u32) ^ scramble),
+                            )
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// The first two dimensions of the Sobol sequence with Owen scrambling, a random permutation of every
+    /// elementary interval that keeps the sequence well stratified. Counts that are powers of two are best.
+    pub fn sobol_sampler(num_samples: usize) -> UnitSquareSampler {
+        let mut rng = pattern_rng();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let seeds: (u32, u32) = (rng.gen(), rng.gen());
+                    (0..num_samples)
+                        .map(|i| {
+                            Vector2::new(
+                                to_unit(owen_scramble(van_der_corput(i as u32), seeds.0)),
+                                to_unit(owen_scramble(sobol(i as u32), seeds.1)),
+                            )
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// A (0,2)-sequence, made of the same generator matrices as `sobol_sampler`, randomized by a random
+    /// digital shift per set, which is cheaper than Owen scrambling but correlates the sets more.
+    pub fn zero_two_sampler(num_samples: usize) -> UnitSquareSampler {
+        let mut rng = pattern_rng();
+        UnitSquareSampler {
+            samples: (0..83)
+                .map(|_| {
+                    let scramble: (u32, u32) = (rng.gen(), rng.gen());
+                    (0..num_samples)
+                        .map(|i| {
+                            Vector2::new(
+                                to_unit(van_der_corput(i as u32) ^ scramble.0),
+                                to_unit(sobol(i as u32) ^ scramble.1),
+                            )
+                        })
+                        .collect()
+                })
+                .collect(),
         }
     }
 }
 
+/// Samples on the hemisphere around the z axis, distributed with density proportional to `cos(theta)^exponent`.
 #[derive(Clone, Debug)]
 pub struct HemiSphereSampler {
-    pub samples: Vec<Vec<Vector3>>,
+    pub pattern: PatternSampler<Vector3>,
+    pub exponent: f64,
 }
 
 impl Sampler<Vector3> for HemiSphereSampler {
     fn sample(&self, set_index: usize, sample_index: usize) -> Vector3 {
-        let mod_set_index = set_index % self.samples.len();
-        let mod_sample_index = sample_index % self.samples[mod_set_index].len();
-        self.samples[mod_set_index][mod_sample_index]
+        self.pattern.sample(set_index, sample_index)
     }
 }
 
 impl HemiSphereSampler {
+    /// Returns the density with respect to solid angle of a direction with the given cosine to the z axis.
+    /// The density is zero for the standard sampler, which always returns the z axis.
+    pub fn pdf(&self, cos_theta: f64) -> f64 {
+        if cos_theta <= 0.0 || !self.exponent.is_finite() {
+            0.0
+        } else {
+            cosine_power_hemisphere_pdf(cos_theta, self.exponent)
+        }
+    }
+
+    /// Maps a point of the unit square to a direction with the same density as the samples, e.g. one drawn
+    /// from the random numbers of a path, which Metropolis light transport can perturb slightly.
+    pub fn warp(&self, u: Vector2) -> Vector3 {
+        if self.exponent.is_finite() {
+            cosine_power_hemisphere(u, self.exponent)
+        } else {
+            Vector3::new(0.0, 0.0, 1.0)
+        }
+    }
+
     pub fn standard_sampler() -> HemiSphereSampler {
         HemiSphereSampler {
-            samples: vec![vec![Vector3::new(0.0, 0.0, 1.0)]],
+            pattern: PatternSampler {
+                samples: vec![vec![Vector3::new(0.0, 0.0, 1.0)]],
+            },
+            exponent: f64::INFINITY,
         }
     }
 
     pub fn regular_sampler(samples_per_axis: usize, e: f64) -> HemiSphereSampler {
-        from_unit_square_sampler(&UnitSquareSampler::regular_sampler(samples_per_axis), e)
+        HemiSphereSampler::from_unit_square_sampler(
+            &UnitSquareSampler::regular_sampler(samples_per_axis),
+            e,
+        )
     }
 
     pub fn jittered_sampler(samples_per_axis: usize, e: f64) -> HemiSphereSampler {
-        from_unit_square_sampler(&UnitSquareSampler::jittered_sampler(samples_per_axis), e)
+        HemiSphereSampler::from_unit_square_sampler(
+            &UnitSquareSampler::jittered_sampler(samples_per_axis),
+            e,
+        )
     }
-}
 
-fn from_unit_square_sampler(sampler: &UnitSquareSampler, e: f64) -> HemiSphereSampler {
-    HemiSphereSampler {
-        samples: sampler
-            .samples
-            .iter()
-            .map(|v| {
-                v.iter()
-                    .map(|p| unit_square_sample_to_hemisphere_sample(e, *p))
-                    .collect()
-            })
-            .collect(),
-    }
-}
-
-#[derive(Clone, Debug)]
-pub struct UnitSphereSampler {
-    pub samples: Vec<Vec<Vector3>>,
-}
-
-impl Sampler<Vector3> for UnitSphereSampler {
-    fn sample(&self, set_index: usize, sample_index: usize) -> Vector3 {
-        let mod_set_index = set_index % self.samples.len();
-        let mod_sample_index = sample_index % self.samples[mod_set_index].len();
-        self.samples[mod_set_index][mod_sample_index]
+    /// Maps the samples of any unit square sampler to the hemisphere with density proportional to
+    /// `cos(theta)^e`.
+    pub fn from_unit_square_sampler(sampler: &UnitSquareSampler, e: f64) -> HemiSphereSampler {
+        HemiSphereSampler {
+            pattern: sampler.map(|p| cosine_power_hemisphere(p, e)),
+            exponent: e,
+        }
     }
 }
 
@@ -135,7 +378,7 @@ impl UnitSphereSampler {
 
     pub fn random_sampler(num_samples: usize) -> UnitSphereSampler {
         let mut samples = Vec::with_capacity(num_samples);
-        let mut rng = rand::thread_rng();
+        let mut rng = pattern_rng();
 
         while samples.len() < num_samples {
             let v = Vector3::new(
@@ -150,35 +393,357 @@ impl UnitSphereSampler {
         }
 
         UnitSphereSampler {
-            samples: create_shuffled_samples(&samples, 83),
+            samples: create_shuffled_samples(&samples, 83, &mut rng),
+        }
+    }
+
+    /// Maps the samples of any unit square sampler uniformly to the surface of the unit sphere.
+    pub fn from_unit_square_sampler(sampler: &UnitSquareSampler) -> UnitSphereSampler {
+        sampler.map(uniform_sphere)
+    }
+}
+
+/// A piecewise-constant distribution over [0, 1) built from `count` function values.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    function: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(function: &[f64]) -> Distribution1D {
+        expect_neq!(function.len(), 0);
+
+        let n = function.len();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for i in 0..n {
+            cdf.push(cdf[i] + function[i].abs() / n as f64);
+        }
+
+        let integral = cdf[n];
+        for (i, value) in cdf.iter_mut().enumerate() {
+            *value = if integral > 0.0 {
+                *value / integral
+            } else {
+                i as f64 / n as f64
+            };
+        }
+
+        Distribution1D {
+            function: function.iter().map(|f| f.abs()).collect(),
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.function.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    /// Maps the uniform sample `u` to a value in [0, 1).
+    /// Returns the value, its density and the index of the segment it falls into.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.count();
+        let offset = self.cdf.partition_point(|&c| c <= u).clamp(1, n) - 1;
+
+        let segment = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if segment > 0.0 {
+            (u - self.cdf[offset]) / segment
+        } else {
+            0.0
+        };
+        let x = ((offset as f64 + du) / n as f64).min(1.0 - f64::EPSILON);
+
+        (x, self.pdf(x), offset)
+    }
+
+    /// Returns the density of the distribution at `x` in [0, 1).
+    pub fn pdf(&self, x: f64) -> f64 {
+        let n = self.count();
+        let offset = ((x * n as f64) as usize).min(n - 1);
+        if self.integral > 0.0 {
+            self.function[offset] / self.integral
+        } else {
+            1.0
         }
     }
 }
 
-fn unit_square_sample_to_hemisphere_sample(e: f64, sample: Vector2) -> Vector3 {
-    let cosphi = (2.0 * PI * sample.x).cos();
-    let sinphi = (2.0 * PI * sample.x).sin();
-    let costheta = (1.0 - sample.y).powf(1.0 / (e + 1.0));
-    let sintheta = (1.0 - costheta * costheta).sqrt();
+/// A piecewise-constant distribution over the unit square built from a grid of `nu * nv` function values
+/// stored row by row. Samples are drawn from the marginal distribution over rows and the conditional
+/// distribution within the selected row.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(function: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        expect_eq!(function.len(), nu * nv);
 
-    Vector3 {
-        x: sintheta * cosphi,
-        y: sintheta * sinphi,
-        z: costheta,
+        let conditional: Vec<Distribution1D> =
+            function.chunks(nu).map(Distribution1D::new).collect();
+        let marginal_function: Vec<f64> = conditional.iter().map(|d| d.integral()).collect();
+
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal_function),
+        }
+    }
+
+    /// Maps the uniform sample `u` to a point in the unit square and returns it with its density.
+    pub fn sample_continuous(&self, u: Vector2) -> (Vector2, f64) {
+        let (y, pdf_y, row) = self.marginal.sample_continuous(u.y);
+        let (x, pdf_x, _) = self.conditional[row].sample_continuous(u.x);
+        (Vector2::new(x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, p: Vector2) -> f64 {
+        let nv = self.marginal.count();
+        let row = ((p.y * nv as f64) as usize).min(nv - 1);
+        self.marginal.pdf(p.y) * self.conditional[row].pdf(p.x)
     }
 }
 
-fn create_shuffled_samples<T>(samples: &Vec<T>, num_sets: usize) -> Vec<Vec<T>>
+/// A replayable stream of primary samples in [0, 1) for Metropolis light transport. Integrators draw from it
+/// like from any random number generator, so the path they trace is a function of the primary samples.
+/// Every iteration mutates the samples, either by small perturbations or by a large step replacing them all,
+/// and rejecting the mutation restores the previous values. Samples are only mutated when they are used.
+#[derive(Clone, Debug)]
+pub struct PrimarySampleStream {
+    rng: StdRng,
+    sigma: f64,
+    large_step_probability: f64,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct PrimarySample {
+    value: f64,
+    modified: u64,
+    backup_value: f64,
+    backup_modified: u64,
+}
+
+impl PrimarySampleStream {
+    /// Creates a stream of independent uniform samples. Streams created with the same `seed` replay the
+    /// same samples.
+    pub fn new(seed: u64, sigma: f64, large_step_probability: f64) -> PrimarySampleStream {
+        PrimarySampleStream {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+        }
+    }
+
+    /// Starts a new mutation and rewinds the stream to its first sample.
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    pub fn is_large_step(&self) -> bool {
+        self.large_step
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.iteration;
+        }
+    }
+
+    /// Restores the samples of the current iteration to their values before the mutation.
+    pub fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.modified == self.iteration {
+                sample.value = sample.backup_value;
+                sample.modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    pub fn next_sample(&mut self) -> f64 {
+        if self.index >= self.samples.len() {
+            self.samples.push(PrimarySample::default());
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+
+        // Samples unused since the last accepted large step have not seen it yet.
+        if sample.modified < self.last_large_step_iteration {
+            sample.value = self.rng.gen();
+            sample.modified = self.last_large_step_iteration;
+        }
+
+        sample.backup_value = sample.value;
+        sample.backup_modified = sample.modified;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // All small steps missed since the sample was last used add up to one wider Gaussian step.
+            let steps = (self.iteration - sample.modified) as f64;
+            let u1: f64 = 1.0 - self.rng.gen::<f64>();
+            let u2: f64 = self.rng.gen();
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            sample.value = (sample.value + normal * self.sigma * steps.sqrt()).rem_euclid(1.0);
+            if sample.value >= 1.0 {
+                sample.value = 0.0;
+            }
+        }
+        sample.modified = self.iteration;
+        sample.value
+    }
+}
+
+impl RngCore for PrimarySampleStream {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Returns the bits of the next primary sample, such that `gen::<f64>()` yields the sample itself.
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_sample() * (1u64 << 53) as f64) as u64) << 11
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Seed of the random numbers that generate sample patterns. Patterns are the same in every run, so that
+/// renders are reproducible; the renderer's seed decides which sets the pixels use.
+const PATTERN_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+fn pattern_rng() -> StdRng {
+    StdRng::seed_from_u64(PATTERN_SEED)
+}
+
+/// Derives the seed of an independent stream of random numbers, e.g. the one of an image line, from the
+/// seed of a render, with the SplitMix64 finalizer.
+pub fn stream_seed(seed: u64, stream: u64) -> u64 {
+    let mut z = seed ^ stream.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn toroidal_distance_squared(a: &Vector2, b: &Vector2) -> f64 {
+    let dx = (a.x - b.x).abs();
+    let dy = (a.y - b.y).abs();
+    let dx = dx.min(1.0 - dx);
+    let dy = dy.min(1.0 - dy);
+    dx * dx + dy * dy
+}
+
+/// Reverses the bits of `index`, the base 2 radical inverse in fixed point.
+fn van_der_corput(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+/// The second dimension of the Sobol sequence in fixed point. Its generator matrix is the upper triangular
+/// Pascal matrix modulo 2, whose columns are built by `v ^= v >> 1`.
+fn sobol(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut v = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+fn to_unit(value: u32) -> f64 {
+    value as f64 / (1u64 << 32) as f64
+}
+
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+/// Owen scrambling in fixed point: every bit is flipped depending on the bits above it, i.e. on the
+/// elementary interval the value lies in, so nested intervals are permuted independently.
+fn owen_scramble(value: u32, seed: u32) -> u32 {
+    let seed = hash(seed);
+    let mut result = value;
+    for bit in 0..32 {
+        let depth = 31 - bit;
+        // A marker bit above the prefix tells prefixes of different lengths apart.
+        let node = ((value as u64 >> (bit + 1)) | (1u64 << depth)) as u32;
+        result ^= (hash(node ^ seed) & 1) << bit;
+    }
+    result
+}
+
+/// One random permutation of the digits in `base` for every digit position resolvable in double precision.
+fn digit_permutations(base: u64, rng: &mut impl Rng) -> Vec<Vec<u64>> {
+    let num_digits = (53.0 / (base as f64).log2()).ceil() as usize;
+    (0..num_digits)
+        .map(|_| {
+            let mut digits: Vec<u64> = (0..base).collect();
+            digits.shuffle(rng);
+            digits
+        })
+        .collect()
+}
+
+/// The radical inverse of `index` in `base` with the digits permuted by `permutations`. The trailing zero
+/// digits of `index` are permuted as well.
+fn scrambled_radical_inverse(mut index: u64, base: u64, permutations: &[Vec<u64>]) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut result = 0.0;
+    for permutation in permutations {
+        result += permutation[(index % base) as usize] as f64 * scale;
+        index /= base;
+        scale *= inverse_base;
+    }
+    result.min(1.0 - f64::EPSILON)
+}
+
+fn create_shuffled_samples<T>(samples: &Vec<T>, num_sets: usize, rng: &mut StdRng) -> Vec<Vec<T>>
 where
     T: Copy,
 {
-    let mut rng = rand::thread_rng();
     let mut sets: Vec<Vec<T>> = Vec::with_capacity(num_sets);
     let num_samples = samples.len();
 
     for i in 0..num_sets {
         let mut indices: Vec<usize> = (0..num_samples).collect();
-        indices.as_mut_slice().shuffle(&mut rng);
+        indices.as_mut_slice().shuffle(rng);
         sets.push(Vec::with_capacity(num_samples));
         for k in 0..num_samples {
             sets[i].push(samples[indices[k]]);
@@ -200,4 +765,232 @@ mod tests {
         assert_close!(0.5, one_per_axis.samples[0][0].x);
         assert_close!(0.5, one_per_axis.samples[0][0].y);
     }
+
+    #[test]
+    fn every_sampler_wraps_set_and_sample_indices() {
+        let square_samplers = [
+            UnitSquareSampler::standard_sampler(),
+            UnitSquareSampler::regular_sampler(4),
+            UnitSquareSampler::jittered_sampler(4),
+            UnitSquareSampler::multi_jittered_sampler(16),
+            UnitSquareSampler::n_rooks_sampler(16),
+            UnitSquareSampler::poisson_disk_sampler(16),
+            UnitSquareSampler::random_sampler(16),
+            UnitSquareSampler::halton_sampler(16),
+            UnitSquareSampler::hammersley_sampler(16),
+            UnitSquareSampler::sobol_sampler(16),
+            UnitSquareSampler::zero_two_sampler(16),
+        ];
+        for sampler in square_samplers.iter() {
+            assert_wraps(sampler, |p| {
+                (0.0..1.0).contains(&p.x) && (0.0..1.0).contains(&p.y)
+            });
+            let hemisphere = HemiSphereSampler::from_unit_square_sampler(sampler, 1.0);
+            assert_wraps(&hemisphere, |d| {
+                d.z >= 0.0 && (d.length() - 1.0).abs() < 1e-9
+            });
+            let sphere = UnitSphereSampler::from_unit_square_sampler(sampler);
+            assert_wraps(&sphere, |d| (d.length() - 1.0).abs() < 1e-9);
+        }
+        assert_wraps(&HemiSphereSampler::standard_sampler(), |d| d.z == 1.0);
+        assert_wraps(&UnitSphereSampler::standard_sampler(), |d| {
+            d.length() == 0.0
+        });
+        assert_wraps(&UnitSphereSampler::random_sampler(16), |d| {
+            d.length() <= 1.0
+        });
+    }
+
+    /// Checks that all samples lie in their domain and that indices past the counts wrap around, starting
+    /// with set 0 and sample 0.
+    fn assert_wraps<T: Copy + PartialEq + std::fmt::Debug>(
+        sampler: &dyn Sampler<T>,
+        in_domain: impl Fn(&T) -> bool,
+    ) {
+        let (num_sets, num_samples) = (83, 16);
+        for set in 0..num_sets {
+            for index in 0..num_samples {
+                let sample = sampler.sample(set, index);
+                assert!(in_domain(&sample), "{:?}", sample);
+                assert_eq!(
+                    sample,
+                    sampler.sample(set + 5 * num_sets, index + 3 * num_samples)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dimensions_of_a_path_draw_from_different_sets() {
+        let sampler = UnitSquareSampler::jittered_sampler(4);
+        let context = TraceContext {
+            set_index: 0,
+            sample_index: 0,
+            dimension: 0,
+            wavelengths: None,
+        };
+        let bounce = context.next_dimension();
+        let pixel: Vec<Vector2> = (0..16)
+            .map(|i| {
+                sampler.sample_for(&TraceContext {
+                    sample_index: i,
+                    ..context.clone()
+                })
+            })
+            .collect();
+        let scattering: Vec<Vector2> = (0..16)
+            .map(|i| {
+                sampler.sample_for(&TraceContext {
+                    sample_index: i,
+                    ..bounce.clone()
+                })
+            })
+            .collect();
+
+        // Each dimension covers all strata of the pattern, but pairs them up differently.
+        for samples in [&pixel, &scattering] {
+            let mut strata: Vec<usize> = samples
+                .iter()
+                .map(|p| 4 * (p.y * 4.0) as usize + (p.x * 4.0) as usize)
+                .collect();
+            strata.sort();
+            assert_eq!((0..16).collect::<Vec<_>>(), strata);
+        }
+        assert_ne!(pixel, scattering);
+    }
+
+    #[test]
+    fn distribution_2d_samples_proportional_to_function() {
+        let distribution = Distribution2D::new(&[0.0, 1.0, 0.0, 3.0], 2, 2);
+        let (p, pdf) = distribution.sample_continuous(Vector2::new(0.5, 0.5));
+        assert!(p.x >= 0.5 && p.y >= 0.5);
+        assert_close!(3.0, pdf);
+        assert_close!(pdf, distribution.pdf(p));
+        assert_close!(0.0, distribution.pdf(Vector2::new(0.25, 0.75)));
+    }
+
+    /// The exact star discrepancy, the largest difference between the fraction of points inside and the area
+    /// of a box anchored at the origin. Only boxes with corners at point coordinates need to be checked.
+    fn star_discrepancy(points: &[Vector2]) -> f64 {
+        let n = points.len() as f64;
+        let mut xs: Vec<f64> = points.iter().map(|p| p.x).chain(Some(1.0)).collect();
+        let mut ys: Vec<f64> = points.iter().map(|p| p.y).chain(Some(1.0)).collect();
+        xs.sort_by(f64::total_cmp);
+        ys.sort_by(f64::total_cmp);
+        let mut discrepancy: f64 = 0.0;
+        for &x in xs.iter() {
+            for &y in ys.iter() {
+                let open = points.iter().filter(|p| p.x < x && p.y < y).count() as f64;
+                let closed = points.iter().filter(|p| p.x <= x && p.y <= y).count() as f64;
+                discrepancy = discrepancy.max(x * y - open / n).max(closed / n - x * y);
+            }
+        }
+        discrepancy
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_beat_random_samples() {
+        let mean_discrepancy = |sampler: &UnitSquareSampler| -> f64 {
+            let sets = &sampler.samples[..16];
+            sets.iter().map(|set| star_discrepancy(set)).sum::<f64>() / sets.len() as f64
+        };
+        // Random points have a discrepancy of about 0.15, these stay below 0.06.
+        let random = mean_discrepancy(&UnitSquareSampler::random_sampler(64));
+        for (name, sampler) in [
+            ("halton", UnitSquareSampler::halton_sampler(64)),
+            ("hammersley", UnitSquareSampler::hammersley_sampler(64)),
+            ("sobol", UnitSquareSampler::sobol_sampler(64)),
+            ("zero_two", UnitSquareSampler::zero_two_sampler(64)),
+        ] {
+            assert_eq!(64, sampler.samples[0].len());
+            let discrepancy = mean_discrepancy(&sampler);
+            assert!(
+                discrepancy < 0.6 * random,
+                "{} {} random {}",
+                name,
+                discrepancy,
+                random
+            );
+        }
+    }
+
+    /// Returns whether no two samples share one of `strata` equal intervals along either axis.
+    fn is_latin_hypercube(samples: &[Vector2], strata: usize) -> bool {
+        let mut columns = vec![false; strata];
+        let mut rows = vec![false; strata];
+        samples.iter().all(|sample| {
+            let column = (sample.x * strata as f64) as usize;
+            let row = (sample.y * strata as f64) as usize;
+            !std::mem::replace(&mut columns[column], true)
+                && !std::mem::replace(&mut rows[row], true)
+        })
+    }
+
+    #[test]
+    fn partly_filled_multi_jittered_patterns_cover_the_whole_square() {
+        for num_samples in [3, 10, 50, 60] {
+            let sampler = UnitSquareSampler::multi_jittered_sampler(num_samples);
+            let samples: Vec<Vector2> = sampler.samples.concat();
+            let mut regions = [0usize; 16];
+            for sample in &samples {
+                regions[(sample.y * 4.0) as usize * 4 + (sample.x * 4.0) as usize] += 1;
+            }
+            let expected = samples.len() as f64 / 16.0;
+            let mean = samples
+                .iter()
+                .fold(Vector2::new(0.0, 0.0), |sum, &s| sum + s)
+                * (1.0 / samples.len() as f64);
+            // The sets leave different cells empty, so together they reach every part of the square.
+            assert!(regions.iter().all(|&count| count as f64 > 0.5 * expected));
+            assert!((mean.x - 0.5).abs() < 0.02 && (mean.y - 0.5).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn stratified_patterns_allow_any_sample_count() {
+        let n_rooks = UnitSquareSampler::n_rooks_sampler(60);
+        assert_eq!(60, n_rooks.samples[0].len());
+        assert!(n_rooks
+            .samples
+            .iter()
+            .all(|set| is_latin_hypercube(set, 60)));
+
+        // 60 samples fill 60 cells of an 8 x 8 grid.
+        let multi_jittered = UnitSquareSampler::multi_jittered_sampler(60);
+        assert_eq!(60, multi_jittered.samples[0].len());
+        assert!(multi_jittered
+            .samples
+            .iter()
+            .all(|set| is_latin_hypercube(set, 64)));
+
+        let poisson_disk = UnitSquareSampler::poisson_disk_sampler(60);
+        let set = &poisson_disk.samples[0];
+        assert_eq!(60, set.len());
+        let min_distance_squared = (0..set.len())
+            .flat_map(|i| (0..i).map(move |j| (i, j)))
+            .map(|(i, j)| toroidal_distance_squared(&set[i], &set[j]))
+            .fold(f64::INFINITY, f64::min);
+        // Half the distance of the densest packing of 60 points.
+        assert!(min_distance_squared.sqrt() > 0.5 * 0.1387);
+    }
+
+    #[test]
+    fn primary_sample_stream_replays_rejected_mutations() {
+        let draw = |stream: &mut PrimarySampleStream| -> Vec<f64> {
+            (0..4).map(|_| stream.gen()).collect()
+        };
+        let mut stream = PrimarySampleStream::new(7, 0.01, 0.3);
+        let initial = draw(&mut stream);
+        assert_eq!(initial, draw(&mut PrimarySampleStream::new(7, 0.01, 0.3)));
+
+        for _ in 0..20 {
+            stream.start_iteration();
+            let mutated = draw(&mut stream);
+            assert!(mutated.iter().all(|u| (0.0..1.0).contains(u)));
+            assert_ne!(initial, mutated);
+            stream.reject();
+            let restored: Vec<f64> = stream.samples.iter().map(|sample| sample.value).collect();
+            assert_eq!(initial, restored);
+        }
+    }
 }
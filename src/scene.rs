@@ -0,0 +1,280 @@
+use rand::{Rng, RngCore};
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::light::{Light, LightHit};
+use crate::math::{Ray3, Vector2, Vector3};
+use crate::medium::{Medium, MediumSegment};
+use crate::shapes::{Hitable, Intersection};
+use crate::spectrum::Radiance;
+use crate::stats;
+use crate::TraceContext;
+
+/// The objects, lights and media of a scene together with the queries shared by all integrators.
+#[derive(Clone)]
+pub struct Scene {
+    pub objects: Arc<Vec<Arc<dyn Hitable>>>,
+    pub lights: Vec<Arc<dyn Light>>,
+    pub atmosphere: Option<Arc<dyn Medium>>,
+    pub ambient_color: Color,
+    pub max_trace_depth: u32,
+}
+
+impl Scene {
+    /// Returns the nearest surface hit by `ray`.
+    pub fn intersect(&self, ray: &Ray3) -> Option<Intersection> {
+        stats::count_nearest_hit_ray();
+        self.objects
+            .iter()
+            .filter_map(|o| o.intersect(ray))
+            .min_by(|a: &Intersection, b: &Intersection| a.t.partial_cmp(&b.t).unwrap())
+    }
+
+    /// Returns the nearest light surface hit by `ray` together with its light.
+    pub fn intersect_light(&self, ray: &Ray3) -> Option<(LightHit, &Arc<dyn Light>)> {
+        self.lights
+            .iter()
+            .filter_map(|light| light.hit(ray).map(|hit| (hit, light)))
+            .min_by(|a, b| a.0.t.partial_cmp(&b.0.t).unwrap())
+    }
+
+    /// Returns the radiance arriving along a ray in `direction` that leaves the scene.
+    /// `scatter_pdf` is the density with which the direction was sampled at the previous scattering event,
+    /// or `None` if light sampling could not have reached the same light.
+    pub fn background(&self, ray: &Ray3, scatter_pdf: Option<f64>) -> Color {
+        let mut radiance = self.ambient_color;
+        for light in self.lights.iter() {
+            let emitted = light.environment(&ray.direction);
+            let weight = scatter_pdf.map_or(1.0, |pdf| {
+                power_heuristic(pdf, light.pdf(&ray.origin, &ray.direction))
+            });
+            radiance += emitted * weight;
+        }
+        radiance
+    }
+
+    /// Estimates the light arriving at `point` directly from the lights, weighted by `scattering`,
+    /// which returns the scattering function times cosine and the scattering density for a direction.
+    /// Light samples are combined with scattered rays by multiple importance sampling unless `use_mis` is false.
+    /// The radiance of the lights is uplifted to the wavelengths of `trace_context` in spectral mode.
+    pub fn direct_lighting<S: Radiance>(
+        &self,
+        point: &Vector3,
+        trace_context: &TraceContext,
+        rng: &mut dyn RngCore,
+        use_mis: bool,
+        scattering: &dyn Fn(&Vector3) -> (S, f64),
+    ) -> S {
+        self.direct_lighting_from(&self.lights, point, trace_context, rng, use_mis, scattering)
+    }
+
+    /// Like `direct_lighting`, but only samples `lights`.
+    pub fn direct_lighting_from<S: Radiance>(
+        &self,
+        lights: &[Arc<dyn Light>],
+        point: &Vector3,
+        trace_context: &TraceContext,
+        rng: &mut dyn RngCore,
+        use_mis: bool,
+        scattering: &dyn Fn(&Vector3) -> (S, f64),
+    ) -> S {
+        let mut direct = S::default();
+
+        for light in lights.iter() {
+            let u = Vector2::new(rng.gen(), rng.gen());
+            let sample = match light.sample(point, u) {
+                Some(sample) if sample.pdf > 0.0 => sample,
+                _ => continue,
+            };
+
+            let (f, pdf) = scattering(&sample.direction);
+            if f.is_black() {
+                continue;
+            }
+
+            let shadow_ray = Ray3::new(*point, sample.direction);
+            let visibility = self.transmittance(&shadow_ray, sample.distance, rng);
+            if is_black(&visibility) {
+                continue;
+            }
+
+            let weight = if use_mis {
+                power_heuristic(sample.pdf, pdf) / sample.pdf
+            } else {
+                1.0 / sample.pdf
+            };
+            direct += f
+                * S::uplift(&sample.radiance, trace_context)
+                * S::uplift(&visibility, trace_context)
+                * weight;
+        }
+
+        direct
+    }
+
+    /// Returns the fraction of light that travels along `ray` up to `distance` without being blocked.
+    /// Light surfaces block as well, except for one ending the ray at `distance`.
+    pub fn transmittance(&self, ray: &Ray3, distance: f64, rng: &mut dyn RngCore) -> Color {
+        stats::count_shadow_ray();
+        let blocked = self
+            .objects
+            .iter()
+            .any(|o| o.intersect(ray).is_some_and(|hit| hit.t < distance))
+            || self
+                .lights
+                .iter()
+                .any(|l| l.hit(ray).is_some_and(|hit| hit.t < distance * 0.999));
+        if blocked {
+            return Color::black();
+        }
+        self.media_transmittance(ray, distance, rng)
+    }
+
+    /// Returns the fraction of light that the media let through along `ray` up to `distance`.
+    pub fn media_transmittance(&self, ray: &Ray3, distance: f64, rng: &mut dyn RngCore) -> Color {
+        let mut transmittance = Color::white();
+        for segment in self.medium_segments(ray) {
+            let t_max = segment.t_max.min(distance);
+            if segment.t_min < t_max {
+                transmittance =
+                    transmittance * segment.medium.transmittance(ray, segment.t_min, t_max, rng);
+            }
+        }
+        transmittance
+    }
+
+    /// Samples the media along `ray` up to the nearest surface at `t_hit`.
+    /// Returns the nearest scattering event together with its weight and medium, if any.
+    /// The weights of media the ray passes through are multiplied into `transmission`.
+    /// Overlapping media are combined by taking the nearest of their scattering events, and every other
+    /// medium lets the ray pass up to that event.
+    pub fn sample_media(
+        &self,
+        ray: &Ray3,
+        t_hit: f64,
+        rng: &mut dyn RngCore,
+        transmission: &mut Color,
+    ) -> Option<(f64, Color, Arc<dyn Medium>)> {
+        let mut segments = self.medium_segments(ray);
+        segments.sort_by(|a, b| a.t_min.total_cmp(&b.t_min));
+
+        // Media only need to be sampled up to the nearest event so far.
+        let mut nearest: Option<(f64, Color, usize)> = None;
+        for (i, segment) in segments.iter().enumerate() {
+            let t_limit = nearest.as_ref().map_or(t_hit, |n| n.0);
+            let t_max = segment.t_max.min(t_limit);
+            if segment.t_min >= t_limit {
+                break;
+            }
+            if segment.t_min >= t_max {
+                continue;
+            }
+            let mut weight = Color::white();
+            if let Some(t) = segment
+                .medium
+                .sample(ray, segment.t_min, t_max, rng, &mut weight)
+            {
+                nearest = Some((t, weight, i));
+            }
+        }
+
+        let t_end = nearest.as_ref().map_or(t_hit, |n| n.0);
+        for (i, segment) in segments.iter().enumerate() {
+            let t_max = segment.t_max.min(t_end);
+            if nearest.as_ref().is_some_and(|n| n.2 == i) || segment.t_min >= t_max {
+                continue;
+            }
+            *transmission = *transmission * segment.medium.pass_weight(ray, segment.t_min, t_max);
+        }
+
+        nearest.map(|(t, weight, i)| (t, weight, Arc::clone(&segments[i].medium)))
+    }
+
+    pub fn medium_segments(&self, ray: &Ray3) -> Vec<MediumSegment> {
+        let mut segments: Vec<MediumSegment> = self
+            .objects
+            .iter()
+            .flat_map(|o| o.medium_segments(ray))
+            .collect();
+        if let Some(atmosphere) = &self.atmosphere {
+            segments.push(MediumSegment {
+                t_min: 0.0,
+                t_max: f64::INFINITY,
+                medium: Arc::clone(atmosphere),
+            });
+        }
+        segments
+    }
+}
+
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let p = pdf * pdf;
+    let o = other_pdf * other_pdf;
+    if p + o > 0.0 {
+        p / (p + o)
+    } else {
+        0.0
+    }
+}
+
+pub fn is_black(c: &Color) -> bool {
+    c.r <= 0.0 && c.g <= 0.0 && c.b <= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::NullMaterial;
+    use crate::medium::{HomogeneousMedium, Volume};
+    use crate::shapes::Sphere;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn overlapping_media_are_sampled_without_bias() {
+        // A purely scattering chromatic fog inside an atmosphere, so the ray either scatters or passes.
+        let scattering = |r, g, b| {
+            Arc::new(HomogeneousMedium::new(
+                &Color::black(),
+                &Color { r, g, b, a: 1.0 },
+                0.0,
+            ))
+        };
+        let fog = Volume::new(
+            Arc::new(Sphere {
+                center: Vector3::zero(),
+                radius: 1.0,
+                material: Arc::new(NullMaterial::new()),
+            }),
+            scattering(1.0, 0.2, 0.6),
+        );
+        let scene = Scene {
+            objects: Arc::new(vec![Arc::new(fog)]),
+            lights: Vec::new(),
+            atmosphere: Some(scattering(0.1, 0.3, 0.5)),
+            ambient_color: Color::black(),
+            max_trace_depth: 1,
+        };
+        let ray = Ray3::new(Vector3::new(-3.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 100000;
+        let (mut passed, mut scattered) = (Color::black(), Color::black());
+        for _ in 0..n {
+            let mut transmission = Color::white();
+            match scene.sample_media(&ray, 6.0, &mut rng, &mut transmission) {
+                Some((_, weight, _)) => scattered += transmission * weight,
+                None => passed += transmission,
+            }
+        }
+        for (passed, scattered, atmosphere, fog) in [
+            (passed.r, scattered.r, 0.1, 1.0),
+            (passed.g, scattered.g, 0.3, 0.2),
+            (passed.b, scattered.b, 0.5, 0.6),
+        ] {
+            let transmittance = f64::exp(-6.0 * atmosphere - 2.0 * fog);
+            assert!((passed / n as f64 - transmittance).abs() < 0.01);
+            assert!((scattered / n as f64 - (1.0 - transmittance)).abs() < 0.01);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Binary encoding of scenes, e.g. to ship them to render workers over the network. Shapes, materials,
+//! lights, media, cameras, integrators and filters are written as a tag naming their type followed by
+//! their fields, so that the readers of their trait objects know which type to recreate.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bdpt::BidirectionalPathTracer;
+use crate::camera::{Camera, OrthographicCamera, PinholeCamera};
+use crate::color::Color;
+use crate::filter::{BoxFilter, Filter, GaussianFilter, LanczosFilter, MitchellFilter, TentFilter};
+use crate::integrator::{
+    AmbientOcclusionIntegrator, DebugIntegrator, DirectLightingIntegrator, Integrator, PathTracer,
+    WhittedIntegrator,
+};
+use crate::io::{InputStream, OutputStream};
+use crate::light::{EnvironmentLight, Light, SphereLight};
+use crate::material::{
+    Dielectric, Lambertian, Material, Metal, NormalMaterial, NullMaterial, Subsurface,
+};
+use crate::math::{Vector2, Vector3};
+use crate::medium::{GridMedium, HomogeneousMedium, Medium, SubsurfaceVolume, Volume};
+use crate::mlt::MetropolisIntegrator;
+use crate::photon::PhotonMapper;
+use crate::sampler::{HemiSphereSampler, PatternSampler};
+use crate::shapes::{Cube, Hitable, Plane, Sphere};
+use crate::sky::{SkyLight, SunLight};
+use crate::RenderBuffer;
+
+pub trait Serialize {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()>;
+}
+
+pub trait Deserialize: Sized {
+    fn read_from(input: &mut InputStream) -> Result<Self>;
+}
+
+/// Writes the tag naming the type of `value`, followed by the value.
+pub fn write_tagged<T: Serialize>(out: &mut OutputStream, tag: &str, value: &T) -> Result<()> {
+    tag.write_to(out)?;
+    value.write_to(out)
+}
+
+/// The error of trait objects whose type cannot be serialized.
+pub fn unsupported(kind: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("This {} cannot be serialized", kind),
+    )
+}
+
+pub fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn unknown_tag(kind: &str, tag: &str) -> Error {
+    invalid_data(&format!("Unknown {} type {}", kind, tag))
+}
+
+impl Serialize for u32 {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        out.write_u32_le(*self)
+    }
+}
+
+impl Deserialize for u32 {
+    fn read_from(input: &mut InputStream) -> Result<u32> {
+        input.read_u32_le()
+    }
+}
+
+impl Serialize for u64 {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        out.write_u64_le(*self)
+    }
+}
+
+impl Deserialize for u64 {
+    fn read_from(input: &mut InputStream) -> Result<u64> {
+        input.read_u64_le()
+    }
+}
+
+impl Serialize for usize {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        out.write_u64_le(*self as u64)
+    }
+}
+
+impl Deserialize for usize {
+    fn read_from(input: &mut InputStream) -> Result<usize> {
+        usize::try_from(input.read_u64_le()?).map_err(|_| invalid_data("Value out of range"))
+    }
+}
+
+impl Serialize for f64 {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        out.write_f64_le(*self)
+    }
+}
+
+impl Deserialize for f64 {
+    fn read_from(input: &mut InputStream) -> Result<f64> {
+        input.read_f64_le()
+    }
+}
+
+impl Serialize for bool {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        out.write(&[*self as u8])
+    }
+}
+
+impl Deserialize for bool {
+    fn read_from(input: &mut InputStream) -> Result<bool> {
+        match input.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("Invalid boolean")),
+        }
+    }
+}
+
+impl Serialize for str {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        out.write_u32_le(self.len() as u32)?;
+        out.write(self.as_bytes())
+    }
+}
+
+impl Serialize for String {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.as_str().write_to(out)
+    }
+}
+
+impl Deserialize for String {
+    fn read_from(input: &mut InputStream) -> Result<String> {
+        let mut bytes = vec![0u8; input.read_u32_le()? as usize];
+        input.read(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| invalid_data("Invalid string"))
+    }
+}
+
+impl Serialize for Duration {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.as_secs().write_to(out)?;
+        self.subsec_nanos().write_to(out)
+    }
+}
+
+impl Deserialize for Duration {
+    fn read_from(input: &mut InputStream) -> Result<Duration> {
+        let seconds = u64::read_from(input)?;
+        let nanos = u32::read_from(input)?;
+        Ok(Duration::new(seconds, nanos))
+    }
+}
+
+impl<T: Serialize> Serialize for Vec<T> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.len().write_to(out)?;
+        for value in self {
+            value.write_to(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Deserialize> Deserialize for Vec<T> {
+    fn read_from(input: &mut InputStream) -> Result<Vec<T>> {
+        let len = usize::read_from(input)?;
+        // The length is not trusted with the allocation, which grows with the values actually read.
+        let mut values = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            values.push(T::read_from(input)?);
+        }
+        Ok(values)
+    }
+}
+
+impl<T: Serialize, const N: usize> Serialize for [T; N] {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        for value in self {
+            value.write_to(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Deserialize, const N: usize> Deserialize for [T; N] {
+    fn read_from(input: &mut InputStream) -> Result<[T; N]> {
+        let mut values = Vec::with_capacity(N);
+        for _ in 0..N {
+            values.push(T::read_from(input)?);
+        }
+        values.try_into().map_err(|_| invalid_data("Invalid array"))
+    }
+}
+
+impl<T: Serialize> Serialize for Option<T> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.is_some().write_to(out)?;
+        match self {
+            Some(value) => value.write_to(out),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Deserialize> Deserialize for Option<T> {
+    fn read_from(input: &mut InputStream) -> Result<Option<T>> {
+        if bool::read_from(input)? {
+            Ok(Some(T::read_from(input)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<A: Serialize, B: Serialize> Serialize for (A, B) {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.0.write_to(out)?;
+        self.1.write_to(out)
+    }
+}
+
+impl<A: Deserialize, B: Deserialize> Deserialize for (A, B) {
+    fn read_from(input: &mut InputStream) -> Result<(A, B)> {
+        let a = A::read_from(input)?;
+        Ok((a, B::read_from(input)?))
+    }
+}
+
+impl Serialize for Vector2 {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.x, self.y].write_to(out)
+    }
+}
+
+impl Deserialize for Vector2 {
+    fn read_from(input: &mut InputStream) -> Result<Vector2> {
+        let [x, y] = <[f64; 2]>::read_from(input)?;
+        Ok(Vector2::new(x, y))
+    }
+}
+
+impl Serialize for Vector3 {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.x, self.y, self.z].write_to(out)
+    }
+}
+
+impl Deserialize for Vector3 {
+    fn read_from(input: &mut InputStream) -> Result<Vector3> {
+        let [x, y, z] = <[f64; 3]>::read_from(input)?;
+        Ok(Vector3::new(x, y, z))
+    }
+}
+
+impl Serialize for Color {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.r, self.g, self.b, self.a].write_to(out)
+    }
+}
+
+impl Deserialize for Color {
+    fn read_from(input: &mut InputStream) -> Result<Color> {
+        let [r, g, b, a] = <[f64; 4]>::read_from(input)?;
+        Ok(Color { r, g, b, a })
+    }
+}
+
+/// Keeps the weighted sums and weights of the pixels, so that partial images survive the trip exactly.
+impl Serialize for RenderBuffer {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.width.write_to(out)?;
+        self.height.write_to(out)?;
+        for (pixel, weight) in self.pixels.iter().zip(&self.weights) {
+            pixel.write_to(out)?;
+            weight.write_to(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deserialize for RenderBuffer {
+    fn read_from(input: &mut InputStream) -> Result<RenderBuffer> {
+        let width = u32::read_from(input)?;
+        let height = u32::read_from(input)?;
+        if width == 0 || height == 0 {
+            return Err(invalid_data("Invalid image dimension"));
+        }
+        let mut buffer = RenderBuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let sum = Color::read_from(input)?;
+                let weight = f64::read_from(input)?;
+                buffer.add_weighted_sum(x, y, &sum, weight);
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+impl<T: Serialize> Serialize for PatternSampler<T> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.samples.write_to(out)
+    }
+}
+
+impl<T: Deserialize> Deserialize for PatternSampler<T> {
+    fn read_from(input: &mut InputStream) -> Result<PatternSampler<T>> {
+        let samples = Vec::<Vec<T>>::read_from(input)?;
+        if samples.is_empty() || samples.iter().any(|set| set.is_empty()) {
+            return Err(invalid_data("Sampler without samples"));
+        }
+        Ok(PatternSampler { samples })
+    }
+}
+
+impl Serialize for HemiSphereSampler {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.pattern.write_to(out)?;
+        self.exponent.write_to(out)
+    }
+}
+
+impl Deserialize for HemiSphereSampler {
+    fn read_from(input: &mut InputStream) -> Result<HemiSphereSampler> {
+        Ok(HemiSphereSampler {
+            pattern: PatternSampler::read_from(input)?,
+            exponent: f64::read_from(input)?,
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Hitable> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Hitable> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Hitable>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "cube" => Arc::new(Cube::read_from(input)?),
+            "sphere" => Arc::new(Sphere::read_from(input)?),
+            "plane" => Arc::new(Plane::read_from(input)?),
+            "volume" => Arc::new(Volume::read_from(input)?),
+            "subsurface_volume" => Arc::new(SubsurfaceVolume::read_from(input)?),
+            _ => return Err(unknown_tag("shape", &tag)),
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Material> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Material> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Material>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "null" => Arc::new(NullMaterial::new()),
+            "normal" => Arc::new(NormalMaterial::new()),
+            "lambertian" => Arc::new(Lambertian::read_from(input)?),
+            "metal" => Arc::new(Metal::read_from(input)?),
+            "dielectric" => Arc::new(Dielectric::read_from(input)?),
+            "subsurface" => Arc::new(Subsurface::read_from(input)?),
+            _ => return Err(unknown_tag("material", &tag)),
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Medium> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Medium> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Medium>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "homogeneous" => Arc::new(HomogeneousMedium::read_from(input)?),
+            "grid" => Arc::new(GridMedium::read_from(input)?),
+            _ => return Err(unknown_tag("medium", &tag)),
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Light> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Light> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Light>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "sphere" => Arc::new(SphereLight::read_from(input)?),
+            "environment" => Arc::new(EnvironmentLight::read_from(input)?),
+            "sky" => Arc::new(SkyLight::read_from(input)?),
+            "sun" => Arc::new(SunLight::read_from(input)?),
+            _ => return Err(unknown_tag("light", &tag)),
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Camera> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Camera> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Camera>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "orthographic" => Arc::new(OrthographicCamera::read_from(input)?),
+            "pinhole" => Arc::new(PinholeCamera::read_from(input)?),
+            _ => return Err(unknown_tag("camera", &tag)),
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Integrator> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Integrator> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Integrator>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "path" => Arc::new(PathTracer::read_from(input)?),
+            "whitted" => Arc::new(WhittedIntegrator::new()),
+            "ambient_occlusion" => Arc::new(AmbientOcclusionIntegrator::read_from(input)?),
+            "direct" => Arc::new(DirectLightingIntegrator::new()),
+            "debug" => Arc::new(DebugIntegrator::read_from(input)?),
+            "bdpt" => Arc::new(BidirectionalPathTracer::read_from(input)?),
+            "metropolis" => Arc::new(MetropolisIntegrator::read_from(input)?),
+            "photons" => Arc::new(PhotonMapper::read_from(input)?),
+            _ => return Err(unknown_tag("integrator", &tag)),
+        })
+    }
+}
+
+impl Serialize for Arc<dyn Filter> {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.serialize(out)
+    }
+}
+
+impl Deserialize for Arc<dyn Filter> {
+    fn read_from(input: &mut InputStream) -> Result<Arc<dyn Filter>> {
+        let tag = String::read_from(input)?;
+        Ok(match tag.as_str() {
+            "box" => Arc::new(BoxFilter::read_from(input)?),
+            "tent" => Arc::new(TentFilter::read_from(input)?),
+            "gaussian" => Arc::new(GaussianFilter::read_from(input)?),
+            "mitchell" => Arc::new(MitchellFilter::read_from(input)?),
+            "lanczos" => Arc::new(LanczosFilter::read_from(input)?),
+            _ => return Err(unknown_tag("filter", &tag)),
+        })
+    }
+}
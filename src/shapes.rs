@@ -1,10 +1,16 @@
 use std::f64;
+use std::f64::consts::PI;
+use std::io::Result;
 use std::mem;
 use std::option::Option;
 use std::sync::Arc;
 
+use crate::io::{InputStream, OutputStream};
 use crate::material::Material;
-use crate::math::{Matrix4, Ray3, Vector3};
+use crate::math::{Matrix4, Ray3, Vector2, Vector3};
+use crate::medium::MediumSegment;
+use crate::serialize::{unsupported, write_tagged, Deserialize, Serialize};
+use crate::stats;
 
 #[derive(Clone)]
 pub struct Intersection {
@@ -12,11 +18,23 @@ pub struct Intersection {
     pub t: f64,
     pub point: Vector3,
     pub normal: Vector3,
+    /// Surface coordinates of the hit point.
+    pub uv: Vector2,
     pub material: Arc<dyn Material>,
 }
 
 pub trait Hitable: Send + Sync {
     fn intersect(&self, ray: &Ray3) -> Option<Intersection>;
+
+    /// Returns the sections of `ray` that travel through a participating medium owned by this object.
+    fn medium_segments(&self, _ray: &Ray3) -> Vec<MediumSegment> {
+        Vec::new()
+    }
+
+    /// Writes the type and the fields of the shape, e.g. to ship the scene to render workers.
+    fn serialize(&self, _out: &mut OutputStream) -> Result<()> {
+        Err(unsupported("shape"))
+    }
 }
 
 #[derive(Clone)]
@@ -30,18 +48,45 @@ pub struct Cube {
 
 impl Hitable for Cube {
     fn intersect(&self, ray: &Ray3) -> Option<Intersection> {
+        stats::count_intersection_test("cube");
         if let Some((t, normal)) = self.intersection_with_normal(ray) {
+            let point = ray.point_at(t - 0.0001);
             Some(Intersection {
                 ray: *ray,
-                t: t,
-                point: ray.point_at(t - 0.0001),
-                normal: normal,
+                t,
+                point,
+                normal,
+                uv: self.uv_at(&point),
                 material: self.material.clone(),
             })
         } else {
             None
         }
     }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "cube", self)
+    }
+}
+
+impl Serialize for Cube {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.center, self.u, self.v, self.w].write_to(out)?;
+        self.material.write_to(out)
+    }
+}
+
+impl Deserialize for Cube {
+    fn read_from(input: &mut InputStream) -> Result<Cube> {
+        let [center, u, v, w] = <[Vector3; 4]>::read_from(input)?;
+        Ok(Cube {
+            center,
+            u,
+            v,
+            w,
+            material: Deserialize::read_from(input)?,
+        })
+    }
 }
 
 impl Cube {
@@ -63,6 +108,26 @@ impl Cube {
         }
     }
 
+    /// Returns the coordinates of `point` on the face of the cube it lies on, each in [0, 1].
+    fn uv_at(&self, point: &Vector3) -> Vector2 {
+        let p = *point - self.center;
+        let a = p.dot(&self.u) / self.u.length_squared();
+        let b = p.dot(&self.v) / self.v.length_squared();
+        let c = p.dot(&self.w) / self.w.length_squared();
+
+        let (s, t) = if a.abs() >= b.abs() && a.abs() >= c.abs() {
+            (b, c)
+        } else if b.abs() >= c.abs() {
+            (a, c)
+        } else {
+            (a, b)
+        };
+        Vector2::new(
+            (0.5 * (s + 1.0)).clamp(0.0, 1.0),
+            (0.5 * (t + 1.0)).clamp(0.0, 1.0),
+        )
+    }
+
     pub fn intersection_with_normal(&self, ray: &Ray3) -> Option<(f64, Vector3)> {
         let mut tmin = f64::MIN;
         let mut vmin = Vector3::zero();
@@ -143,6 +208,7 @@ pub struct Sphere {
 
 impl Hitable for Sphere {
     fn intersect(self: &Sphere, ray: &Ray3) -> Option<Intersection> {
+        stats::count_intersection_test("sphere");
         let v = ray.origin - self.center;
         let a = ray.direction.dot(&ray.direction);
         let b = (v * 2.0).dot(&ray.direction);
@@ -169,15 +235,42 @@ impl Hitable for Sphere {
 
         let point = ray.point_at(t);
         let normal = (point - self.center) / self.radius;
+        let uv = Vector2::new(
+            0.5 + normal.z.atan2(normal.x) / (2.0 * PI),
+            normal.y.clamp(-1.0, 1.0).acos() / PI,
+        );
 
         Some(Intersection {
             ray: *ray,
-            t: t,
-            point: point,
-            normal: normal,
+            t,
+            point,
+            normal,
+            uv,
             material: self.material.clone(),
         })
     }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "sphere", self)
+    }
+}
+
+impl Serialize for Sphere {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.center.write_to(out)?;
+        self.radius.write_to(out)?;
+        self.material.write_to(out)
+    }
+}
+
+impl Deserialize for Sphere {
+    fn read_from(input: &mut InputStream) -> Result<Sphere> {
+        Ok(Sphere {
+            center: Vector3::read_from(input)?,
+            radius: f64::read_from(input)?,
+            material: Deserialize::read_from(input)?,
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -189,19 +282,47 @@ pub struct Plane {
 
 impl Hitable for Plane {
     fn intersect(self: &Plane, ray: &Ray3) -> Option<Intersection> {
+        stats::count_intersection_test("plane");
         let t = (self.point - ray.origin).dot(&self.normal) / ray.direction.dot(&self.normal);
         if t > 0.0001 {
+            let point = ray.point_at(t);
+            let tangent = self.normal.cross(&Vector3::new(0.0072, 1.0, 0.0034)).normalized();
+            let bitangent = self.normal.cross(&tangent);
+            let offset = point - self.point;
             Some(Intersection {
                 ray: *ray,
-                t: t,
-                point: ray.point_at(t),
+                t,
+                point,
                 normal: self.normal,
+                uv: Vector2::new(offset.dot(&tangent), offset.dot(&bitangent)),
                 material: self.material.clone(),
             })
         } else {
             None
         }
     }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "plane", self)
+    }
+}
+
+impl Serialize for Plane {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.point.write_to(out)?;
+        self.normal.write_to(out)?;
+        self.material.write_to(out)
+    }
+}
+
+impl Deserialize for Plane {
+    fn read_from(input: &mut InputStream) -> Result<Plane> {
+        Ok(Plane {
+            point: Vector3::read_from(input)?,
+            normal: Vector3::read_from(input)?,
+            material: Deserialize::read_from(input)?,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,374 @@
+use std::f64::consts::PI;
+use std::io::Result;
+
+use crate::color::Color;
+use crate::io::{InputStream, OutputStream};
+use crate::light::{direction_to_uv, luminance, uv_to_direction, Light, LightSample};
+use crate::math::{Vector2, Vector3};
+use crate::sampler::Distribution2D;
+use crate::serialize::{write_tagged, Deserialize, Serialize};
+use crate::warp::{to_world, uniform_cone, uniform_cone_pdf};
+
+/// Resolution of the table used to importance sample the sky.
+const SKY_TABLE_WIDTH: usize = 64;
+const SKY_TABLE_HEIGHT: usize = 32;
+
+/// Angular radius of the sun as seen from the earth in radians.
+pub const SUN_ANGULAR_RADIUS: f64 = 0.004_65;
+
+/// Luminance of the sun outside the atmosphere in kcd/m², the unit of the sky model.
+const SUN_LUMINANCE: f64 = 1.6e6;
+
+/// Returns the direction towards the sun for an elevation above the horizon and an azimuth measured from the
+/// negative z axis towards the positive x axis, both in radians.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vector3 {
+    Vector3::new(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        -elevation.cos() * azimuth.cos(),
+    )
+}
+
+/// The analytic daylight sky model by Preetham, Shirley and Smits.
+/// The radiance of the sky is given in kcd/m² scaled by `intensity`. Directions below the horizon are black.
+#[derive(Clone, Debug)]
+pub struct SkyLight {
+    sun_elevation: f64,
+    sun_azimuth: f64,
+    sun_direction: Vector3,
+    turbidity: f64,
+    intensity: f64,
+    theta_sun: f64,
+    zenith: [f64; 3],
+    /// Perez distribution coefficients for the x and y chromaticity and the luminance Y.
+    perez: [[f64; 5]; 3],
+    distribution: Distribution2D,
+}
+
+impl Light for SkyLight {
+    fn sample(&self, _: &Vector3, u: Vector2) -> Option<LightSample> {
+        let (uv, map_pdf) = self.distribution.sample_continuous(u);
+        let sin_theta = (uv.y * PI).sin();
+        if map_pdf <= 0.0 || sin_theta <= 0.0 {
+            return None;
+        }
+
+        let direction = uv_to_direction(uv);
+        Some(LightSample {
+            direction,
+            radiance: self.environment(&direction),
+            pdf: map_pdf / (2.0 * PI * PI * sin_theta),
+            distance: f64::INFINITY,
+            normal: -direction,
+        })
+    }
+
+    fn pdf(&self, _: &Vector3, direction: &Vector3) -> f64 {
+        let uv = direction_to_uv(direction);
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+
+    fn environment(&self, direction: &Vector3) -> Color {
+        let d = direction.normalized();
+        if d.y <= 0.0 {
+            return Color::default();
+        }
+
+        let theta = d.y.acos();
+        let gamma = d.dot(&self.sun_direction).clamp(-1.0, 1.0).acos();
+
+        let mut xyy = [0.0; 3];
+        for (i, value) in xyy.iter_mut().enumerate() {
+            *value = self.zenith[i] * perez(&self.perez[i], theta, gamma)
+                / perez(&self.perez[i], 0.0, self.theta_sun);
+        }
+
+        let mut c = xyy_to_rgb(xyy[0], xyy[1], xyy[2]);
+        c.r *= self.intensity;
+        c.g *= self.intensity;
+        c.b *= self.intensity;
+        c
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "sky", self)
+    }
+}
+
+impl Serialize for SkyLight {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [
+            self.sun_elevation,
+            self.sun_azimuth,
+            self.turbidity,
+            self.intensity,
+        ]
+        .write_to(out)
+    }
+}
+
+impl Deserialize for SkyLight {
+    fn read_from(input: &mut InputStream) -> Result<SkyLight> {
+        let [elevation, azimuth, turbidity, intensity] = <[f64; 4]>::read_from(input)?;
+        Ok(SkyLight::new(elevation, azimuth, turbidity, intensity))
+    }
+}
+
+impl SkyLight {
+    /// Creates a sky for the sun at the given elevation and azimuth in radians, see `sun_direction`.
+    /// The turbidity describes the haziness of the atmosphere, from about 2 for a clear sky to 10 for haze.
+    pub fn new(sun_elevation: f64, sun_azimuth: f64, turbidity: f64, intensity: f64) -> SkyLight {
+        let t = turbidity;
+        let theta_sun = PI * 0.5 - sun_elevation.clamp(0.0, PI * 0.5);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let th = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+        let tt = [t * t, t, 1.0];
+        let zenith_x = chromaticity(&tt, &ZENITH_X, &th);
+        let zenith_y = chromaticity(&tt, &ZENITH_Y, &th);
+
+        let perez = [
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+        ];
+
+        let mut sky = SkyLight {
+            sun_elevation,
+            sun_azimuth,
+            sun_direction: sun_direction(sun_elevation, sun_azimuth),
+            turbidity,
+            intensity,
+            theta_sun,
+            zenith: [zenith_x, zenith_y, zenith_luminance],
+            perez,
+            distribution: Distribution2D::new(&[1.0], 1, 1),
+        };
+
+        let mut function = Vec::with_capacity(SKY_TABLE_WIDTH * SKY_TABLE_HEIGHT);
+        for y in 0..SKY_TABLE_HEIGHT {
+            let v = (y as f64 + 0.5) / SKY_TABLE_HEIGHT as f64;
+            for x in 0..SKY_TABLE_WIDTH {
+                let u = (x as f64 + 0.5) / SKY_TABLE_WIDTH as f64;
+                let radiance = sky.environment(&uv_to_direction(Vector2::new(u, v)));
+                function.push(luminance(&radiance) * (v * PI).sin());
+            }
+        }
+        sky.distribution = Distribution2D::new(&function, SKY_TABLE_WIDTH, SKY_TABLE_HEIGHT);
+
+        sky
+    }
+
+    pub fn zenith_luminance(&self) -> f64 {
+        self.zenith[2] * self.intensity
+    }
+
+    /// Creates the sun disk matching this sky. Its color is the extraterrestrial sun attenuated by Rayleigh and
+    /// aerosol scattering along the path through the atmosphere.
+    pub fn sun(&self) -> SunLight {
+        let theta_deg = self.theta_sun.to_degrees();
+        let air_mass = 1.0 / (self.theta_sun.cos() + 0.15 * (93.885 - theta_deg).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+
+        let transmittance = |lambda: f64| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+            rayleigh * aerosol
+        };
+
+        let scale = if self.sun_direction.y > 0.0 {
+            SUN_LUMINANCE * self.intensity
+        } else {
+            0.0
+        };
+        let radiance = Color {
+            r: transmittance(0.65) * scale,
+            g: transmittance(0.55) * scale,
+            b: transmittance(0.45) * scale,
+            a: 1.0,
+        };
+
+        SunLight::new(self.sun_direction, SUN_ANGULAR_RADIUS, radiance)
+    }
+}
+
+/// A light at infinity covering a small disk of directions with constant radiance, like the sun.
+#[derive(Clone, Debug)]
+pub struct SunLight {
+    direction: Vector3,
+    cos_max: f64,
+    radiance: Color,
+}
+
+impl Light for SunLight {
+    fn sample(&self, _: &Vector3, u: Vector2) -> Option<LightSample> {
+        if self.radiance.r <= 0.0 && self.radiance.g <= 0.0 && self.radiance.b <= 0.0 {
+            return None;
+        }
+
+        let direction = to_world(&uniform_cone(u, self.cos_max), &self.direction);
+        Some(LightSample {
+            direction,
+            radiance: self.radiance,
+            pdf: self.cone_pdf(),
+            distance: f64::INFINITY,
+            normal: -direction,
+        })
+    }
+
+    fn pdf(&self, _: &Vector3, direction: &Vector3) -> f64 {
+        if direction.normalized().dot(&self.direction) >= self.cos_max {
+            self.cone_pdf()
+        } else {
+            0.0
+        }
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+
+    fn environment(&self, direction: &Vector3) -> Color {
+        if direction.normalized().dot(&self.direction) >= self.cos_max {
+            self.radiance
+        } else {
+            Color::default()
+        }
+    }
+
+    fn serialize(&self, out: &mut OutputStream) -> Result<()> {
+        write_tagged(out, "sun", self)
+    }
+}
+
+impl Serialize for SunLight {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.direction.write_to(out)?;
+        self.cos_max.write_to(out)?;
+        self.radiance.write_to(out)
+    }
+}
+
+impl Deserialize for SunLight {
+    fn read_from(input: &mut InputStream) -> Result<SunLight> {
+        Ok(SunLight {
+            direction: Vector3::read_from(input)?,
+            cos_max: f64::read_from(input)?,
+            radiance: Color::read_from(input)?,
+        })
+    }
+}
+
+impl SunLight {
+    pub fn new(direction: Vector3, angular_radius: f64, radiance: Color) -> SunLight {
+        SunLight {
+            direction: direction.normalized(),
+            cos_max: angular_radius.cos(),
+            radiance,
+        }
+    }
+
+    fn cone_pdf(&self) -> f64 {
+        uniform_cone_pdf(self.cos_max)
+    }
+}
+
+const ZENITH_X: [[f64; 4]; 3] = [
+    [0.00166, -0.00375, 0.00209, 0.0],
+    [-0.02903, 0.06377, -0.03202, 0.00394],
+    [0.11693, -0.21196, 0.06052, 0.25886],
+];
+
+const ZENITH_Y: [[f64; 4]; 3] = [
+    [0.00275, -0.00610, 0.00317, 0.0],
+    [-0.04214, 0.08970, -0.04153, 0.00516],
+    [0.15346, -0.26756, 0.06670, 0.26688],
+];
+
+fn chromaticity(turbidity: &[f64; 3], m: &[[f64; 4]; 3], theta: &[f64; 4]) -> f64 {
+    let mut value = 0.0;
+    for (i, row) in m.iter().enumerate() {
+        for (j, coefficient) in row.iter().enumerate() {
+            value += turbidity[i] * coefficient * theta[j];
+        }
+    }
+    value
+}
+
+fn perez(coefficients: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    let cos_gamma = gamma.cos();
+    (1.0 + a * (b / theta.cos().max(0.01)).exp())
+        * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    Color {
+        r: (3.2406 * cx - 1.5372 * luminance - 0.4986 * cz).max(0.0),
+        g: (-0.9689 * cx + 1.8758 * luminance + 0.0415 * cz).max(0.0),
+        b: (0.0557 * cx - 0.2040 * luminance + 1.0570 * cz).max(0.0),
+        a: 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zenith_matches_zenith_luminance() {
+        let sky = SkyLight::new(0.6, 0.3, 3.0, 1.0);
+        let zenith = sky.environment(&Vector3::new(0.0, 1.0, 0.0));
+        assert!((luminance(&zenith) - sky.zenith_luminance()).abs() < 0.01 * sky.zenith_luminance());
+    }
+
+    #[test]
+    fn sky_is_black_below_horizon_and_bluer_than_sun() {
+        let sky = SkyLight::new(0.6, 0.3, 3.0, 1.0);
+        let below = sky.environment(&Vector3::new(0.0, -1.0, 0.0));
+        assert_close!(0.0, below.r + below.g + below.b);
+
+        let zenith = sky.environment(&Vector3::new(0.0, 1.0, 0.0));
+        assert!(zenith.b > zenith.r);
+
+        let sun = sky.sun().environment(&sun_direction(0.6, 0.3));
+        assert!(sun.r > sun.b);
+    }
+
+    #[test]
+    fn sun_sample_pdf_matches_pdf() {
+        let sun = SkyLight::new(0.3, 0.0, 2.5, 1.0).sun();
+        let sample = sun.sample(&Vector3::zero(), Vector2::new(0.4, 0.7)).unwrap();
+        assert_close!(sample.pdf, sun.pdf(&Vector3::zero(), &sample.direction));
+    }
+}
@@ -0,0 +1,354 @@
+use std::ops::{Add, AddAssign, Mul};
+
+use crate::color::Color;
+use crate::math::Vector3;
+use crate::TraceContext;
+
+/// Shortest wavelength in nanometres sampled in spectral mode.
+pub const WAVELENGTH_MIN: f64 = 360.0;
+/// Longest wavelength in nanometres sampled in spectral mode.
+pub const WAVELENGTH_MAX: f64 = 830.0;
+/// Number of wavelengths traced along every path.
+pub const SPECTRUM_SAMPLES: usize = 4;
+/// Wavelength in nanometres at which wavelength dependent properties are evaluated when rendering in RGB,
+/// the helium d-line used to specify the refractive index of glasses.
+pub const REFERENCE_WAVELENGTH: f64 = 587.56;
+
+/// Integral of the CIE Y matching function over the sampled range, which maps a constant spectrum of 1 to Y = 1.
+const CIE_Y_INTEGRAL: f64 = 106.922_074_5;
+
+/// The wavelengths carried by one path. The hero wavelength is sampled uniformly and the others are spaced
+/// evenly from it over the range, so that all of them are distributed uniformly.
+#[derive(Clone, Debug)]
+pub struct SampledWavelengths {
+    pub lambda: [f64; SPECTRUM_SAMPLES],
+    secondary_terminated: bool,
+}
+
+impl SampledWavelengths {
+    pub fn sample(u: f64) -> SampledWavelengths {
+        let mut lambda = [0.0; SPECTRUM_SAMPLES];
+        for (i, wavelength) in lambda.iter_mut().enumerate() {
+            let offset = (u + i as f64 / SPECTRUM_SAMPLES as f64).fract();
+            *wavelength = WAVELENGTH_MIN + offset * (WAVELENGTH_MAX - WAVELENGTH_MIN);
+        }
+        SampledWavelengths {
+            lambda,
+            secondary_terminated: false,
+        }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    /// The probability density of each wavelength.
+    pub fn pdf(&self) -> f64 {
+        1.0 / (WAVELENGTH_MAX - WAVELENGTH_MIN)
+    }
+
+    /// Drops all but the hero wavelength, for paths whose direction depends on the wavelength like
+    /// refraction through a dispersive dielectric.
+    pub fn terminate_secondary(&mut self) {
+        self.secondary_terminated = true;
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.secondary_terminated
+    }
+
+    /// The smooth spectrum with the RGB color `rgb` at these wavelengths, see `rgb_to_spectrum`.
+    pub fn uplift(&self, rgb: &Color) -> SampledSpectrum {
+        self.map(|wavelength| rgb_to_spectrum(rgb, wavelength))
+    }
+
+    /// Evaluates `spectrum` at these wavelengths.
+    pub fn map(&self, spectrum: impl Fn(f64) -> f64) -> SampledSpectrum {
+        SampledSpectrum {
+            values: self.lambda.map(spectrum),
+        }
+    }
+
+    /// Estimates the CIE XYZ tristimulus values of the light a path carried at these wavelengths.
+    pub fn to_xyz(&self, radiance: &SampledSpectrum) -> Vector3 {
+        let count = if self.secondary_terminated {
+            1
+        } else {
+            SPECTRUM_SAMPLES
+        };
+        let mut xyz = Vector3::zero();
+        for (&wavelength, &value) in self.lambda.iter().zip(radiance.values.iter()).take(count) {
+            xyz += cie_xyz(wavelength) * value;
+        }
+        xyz * (1.0 / (count as f64 * self.pdf() * CIE_Y_INTEGRAL))
+    }
+
+    /// Converts the radiance of a path through CIE XYZ to RGB, see `to_xyz`.
+    pub fn to_rgb(&self, radiance: &SampledSpectrum) -> Color {
+        xyz_to_rgb(&self.to_xyz(radiance))
+    }
+}
+
+/// The values of a spectrum at the wavelengths of a path.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SampledSpectrum {
+    pub values: [f64; SPECTRUM_SAMPLES],
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn add(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        let mut sum = self;
+        sum += rhs;
+        sum
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, rhs: SampledSpectrum) {
+        for (value, other) in self.values.iter_mut().zip(rhs.values.iter()) {
+            *value += other;
+        }
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(self, rhs: SampledSpectrum) -> SampledSpectrum {
+        let mut product = self;
+        for (value, other) in product.values.iter_mut().zip(rhs.values.iter()) {
+            *value *= other;
+        }
+        product
+    }
+}
+
+impl Mul<f64> for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(self, rhs: f64) -> SampledSpectrum {
+        SampledSpectrum {
+            values: self.values.map(|value| value * rhs),
+        }
+    }
+}
+
+/// The quantity integrators carry along paths: RGB colors, or in spectral mode the values of spectra at the
+/// wavelengths of the path. Colors of the scene enter paths through `uplift`, so that spectral paths multiply
+/// spectra rather than colors.
+pub trait Radiance:
+    Copy + Default + Add<Output = Self> + AddAssign + Mul<Output = Self> + Mul<f64, Output = Self>
+{
+    /// `color` at the wavelengths of `trace_context`.
+    fn uplift(color: &Color, trace_context: &TraceContext) -> Self;
+
+    /// Like `uplift`, but evaluates `spectrum` for quantities that are known at every wavelength.
+    fn uplift_with(
+        color: &Color,
+        trace_context: &TraceContext,
+        spectrum: &dyn Fn(f64) -> f64,
+    ) -> Self;
+
+    /// Converts the radiance of a path with the wavelengths of `trace_context` to RGB.
+    fn to_rgb(&self, trace_context: &TraceContext) -> Color;
+
+    fn max_value(&self) -> f64;
+
+    fn is_black(&self) -> bool;
+}
+
+impl Radiance for Color {
+    fn uplift(color: &Color, _: &TraceContext) -> Color {
+        *color
+    }
+
+    fn uplift_with(color: &Color, _: &TraceContext, _: &dyn Fn(f64) -> f64) -> Color {
+        *color
+    }
+
+    fn to_rgb(&self, _: &TraceContext) -> Color {
+        *self
+    }
+
+    fn max_value(&self) -> f64 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    fn is_black(&self) -> bool {
+        self.r <= 0.0 && self.g <= 0.0 && self.b <= 0.0
+    }
+}
+
+impl Radiance for SampledSpectrum {
+    fn uplift(color: &Color, trace_context: &TraceContext) -> SampledSpectrum {
+        spectral_wavelengths(trace_context).uplift(color)
+    }
+
+    fn uplift_with(
+        _: &Color,
+        trace_context: &TraceContext,
+        spectrum: &dyn Fn(f64) -> f64,
+    ) -> SampledSpectrum {
+        spectral_wavelengths(trace_context).map(spectrum)
+    }
+
+    fn to_rgb(&self, trace_context: &TraceContext) -> Color {
+        spectral_wavelengths(trace_context).to_rgb(self)
+    }
+
+    fn max_value(&self) -> f64 {
+        self.values
+            .iter()
+            .cloned()
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+
+    fn is_black(&self) -> bool {
+        self.values.iter().all(|&value| value <= 0.0)
+    }
+}
+
+fn spectral_wavelengths(trace_context: &TraceContext) -> &SampledWavelengths {
+    trace_context
+        .wavelengths
+        .as_ref()
+        .expect("spectral paths carry wavelengths")
+}
+
+/// Reflectances of Smits' basis spectra in ten equal bins from 380 to 720 nm.
+const SMITS_WHITE: [f64; 10] = [1.0, 1.0, 0.9999, 0.9993, 0.9992, 0.9998, 1.0, 1.0, 1.0, 1.0];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0, 0.0, 0.0,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0, 1.0, 0.9685, 0.2229, 0.0, 0.0458, 0.8369, 1.0, 1.0, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0, 0.1088, 0.6651, 1.0, 1.0, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0, 0.0, 0.0, 0.0, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0, 0.0, 0.0273, 0.7937, 1.0, 0.9418, 0.1719, 0.0, 0.0, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0, 1.0, 0.8916, 0.3323, 0.0, 0.0, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Evaluates a smooth spectrum with the given RGB color at `wavelength` in nanometres, using Smits' method
+/// of combining white, one secondary and one primary basis spectrum.
+pub fn rgb_to_spectrum(rgb: &Color, wavelength: f64) -> f64 {
+    let bin = (((wavelength - 380.0) / 34.0).max(0.0) as usize).min(9);
+    let (r, g, b) = (rgb.r, rgb.g, rgb.b);
+    if r <= g && r <= b {
+        let white = r * SMITS_WHITE[bin] + (g.min(b) - r) * SMITS_CYAN[bin];
+        if g <= b {
+            white + (b - g) * SMITS_BLUE[bin]
+        } else {
+            white + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        let white = g * SMITS_WHITE[bin] + (r.min(b) - g) * SMITS_MAGENTA[bin];
+        if r <= b {
+            white + (b - r) * SMITS_BLUE[bin]
+        } else {
+            white + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        let white = b * SMITS_WHITE[bin] + (r.min(g) - b) * SMITS_YELLOW[bin];
+        if r <= g {
+            white + (g - r) * SMITS_GREEN[bin]
+        } else {
+            white + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+/// The CIE 1931 color matching functions at `wavelength` in nanometres, in the multi-lobe Gaussian fit of
+/// Wyman, Sloan and Shirley.
+pub fn cie_xyz(wavelength: f64) -> Vector3 {
+    let lobe = |mean: f64, sigma_below: f64, sigma_above: f64| {
+        let sigma = if wavelength < mean {
+            sigma_below
+        } else {
+            sigma_above
+        };
+        let t = (wavelength - mean) / sigma;
+        (-0.5 * t * t).exp()
+    };
+    Vector3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    )
+}
+
+/// Linear sRGB of the constant spectrum of 1. Dividing by it white balances the conversion to the equal
+/// energy white point, so that white survives the round trip through `rgb_to_spectrum`.
+const EQUAL_ENERGY_WHITE: [f64; 3] = [1.200_524, 0.949_127, 0.908_227];
+
+/// Converts CIE XYZ tristimulus values to linear sRGB.
+pub fn xyz_to_rgb(xyz: &Vector3) -> Color {
+    Color {
+        r: (3.240_454 * xyz.x - 1.537_138 * xyz.y - 0.498_531 * xyz.z) / EQUAL_ENERGY_WHITE[0],
+        g: (-0.969_266 * xyz.x + 1.876_011 * xyz.y + 0.041_556 * xyz.z) / EQUAL_ENERGY_WHITE[1],
+        b: (0.055_643 * xyz.x - 0.204_026 * xyz.y + 1.057_225 * xyz.z) / EQUAL_ENERGY_WHITE[2],
+        a: 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Integrates the round trip RGB -> spectrum -> XYZ -> RGB with many sets of hero wavelengths.
+    fn round_trip(rgb: &Color) -> Color {
+        let count = 4000;
+        let mut sum = Color::black();
+        for i in 0..count {
+            let wavelengths = SampledWavelengths::sample((i as f64 + 0.5) / count as f64);
+            sum += wavelengths.to_rgb(&wavelengths.uplift(rgb));
+        }
+        sum * (1.0 / count as f64)
+    }
+
+    #[test]
+    fn rgb_survives_round_trip_through_spectrum() {
+        for rgb in [
+            Color::white(),
+            Color {
+                r: 0.2,
+                g: 0.5,
+                b: 0.8,
+                a: 1.0,
+            },
+            Color {
+                r: 1.0,
+                g: 0.0,
+                b: 0.0,
+                a: 1.0,
+            },
+        ] {
+            let result = round_trip(&rgb);
+            assert!((result.r - rgb.r).abs() < 0.02, "{:?} -> {:?}", rgb, result);
+            assert!((result.g - rgb.g).abs() < 0.02, "{:?} -> {:?}", rgb, result);
+            assert!((result.b - rgb.b).abs() < 0.02, "{:?} -> {:?}", rgb, result);
+        }
+    }
+
+    #[test]
+    fn terminated_secondaries_keep_the_estimate_unbiased() {
+        let count = 4000;
+        let mut sum = Vector3::zero();
+        for i in 0..count {
+            let mut wavelengths = SampledWavelengths::sample((i as f64 + 0.5) / count as f64);
+            wavelengths.terminate_secondary();
+            sum += wavelengths.to_xyz(&wavelengths.uplift(&Color::white()));
+        }
+        assert!((sum.y / count as f64 - 1.0).abs() < 0.01);
+    }
+}
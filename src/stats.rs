@@ -0,0 +1,268 @@
+//! Optional counters of the work renders do, to find out why a render is slow. Every thread counts into
+//! counters of its own while counting is enabled for it. Threads helping another one hand their counts to it
+//! when they are done, so that the thread running a render ends up with the counts of all of them.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Result;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::io::{InputStream, OutputStream};
+use crate::serialize::{Deserialize, Serialize};
+
+/// What a render did, collected with `RendererConfig::statistics`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// Camera rays.
+    pub primary_rays: u64,
+    /// Other rays searching for the nearest surface, e.g. the bounces of paths and photons.
+    pub secondary_rays: u64,
+    /// Rays testing whether a point is visible from another one, e.g. from a light sample.
+    pub shadow_rays: u64,
+    /// Ray intersection tests per type of shape.
+    pub intersection_tests: BTreeMap<String, u64>,
+    /// Rays searching for the nearest surface that were traced for camera samples, camera rays included.
+    pub path_rays: u64,
+    /// Camera samples per traced pixel.
+    pub samples_per_pixel: f64,
+    /// Time spent preprocessing the scene, e.g. tracing photons or Markov chains.
+    pub preprocess_time: Duration,
+    /// Time spent tracing the tiles of passes.
+    pub trace_time: Duration,
+    /// Time spent updating the images after passes.
+    pub image_time: Duration,
+    /// Time spent writing checkpoints.
+    pub checkpoint_time: Duration,
+}
+
+impl RenderStats {
+    /// Rays searching for the nearest surface per camera sample.
+    pub fn average_path_length(&self) -> f64 {
+        if self.primary_rays == 0 {
+            return 0.0;
+        }
+        self.path_rays as f64 / self.primary_rays as f64
+    }
+
+    /// Adds the counts and times of `other`, e.g. those of another thread.
+    pub fn add(&mut self, other: &RenderStats) {
+        self.primary_rays += other.primary_rays;
+        self.secondary_rays += other.secondary_rays;
+        self.shadow_rays += other.shadow_rays;
+        for (shape, tests) in &other.intersection_tests {
+            *self.intersection_tests.entry(shape.clone()).or_insert(0) += tests;
+        }
+        self.path_rays += other.path_rays;
+        self.preprocess_time += other.preprocess_time;
+        self.trace_time += other.trace_time;
+        self.image_time += other.image_time;
+        self.checkpoint_time += other.checkpoint_time;
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Primary rays:        {:>14}", self.primary_rays)?;
+        writeln!(f, "Secondary rays:      {:>14}", self.secondary_rays)?;
+        writeln!(f, "Shadow rays:         {:>14}", self.shadow_rays)?;
+        writeln!(f, "Intersection tests:")?;
+        for (shape, tests) in &self.intersection_tests {
+            writeln!(f, "  {:<18}{:>14}", shape, tests)?;
+        }
+        writeln!(
+            f,
+            "Average path length: {:>14.2}",
+            self.average_path_length()
+        )?;
+        writeln!(f, "Samples per pixel:   {:>14.2}", self.samples_per_pixel)?;
+        writeln!(f, "Time per phase:")?;
+        for (phase, time) in [
+            ("preprocessing", self.preprocess_time),
+            ("tracing", self.trace_time),
+            ("images", self.image_time),
+            ("checkpoints", self.checkpoint_time),
+        ] {
+            writeln!(f, "  {:<18}{:>12.3} s", phase, time.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for RenderStats {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        [self.primary_rays, self.secondary_rays, self.shadow_rays].write_to(out)?;
+        let tests: Vec<(String, u64)> = self.intersection_tests.clone().into_iter().collect();
+        tests.write_to(out)?;
+        self.path_rays.write_to(out)?;
+        self.samples_per_pixel.write_to(out)?;
+        [
+            self.preprocess_time,
+            self.trace_time,
+            self.image_time,
+            self.checkpoint_time,
+        ]
+        .write_to(out)
+    }
+}
+
+impl Deserialize for RenderStats {
+    fn read_from(input: &mut InputStream) -> Result<RenderStats> {
+        let [primary_rays, secondary_rays, shadow_rays] = <[u64; 3]>::read_from(input)?;
+        let intersection_tests = Vec::<(String, u64)>::read_from(input)?;
+        let path_rays = u64::read_from(input)?;
+        let samples_per_pixel = f64::read_from(input)?;
+        let [preprocess_time, trace_time, image_time, checkpoint_time] =
+            <[Duration; 4]>::read_from(input)?;
+        Ok(RenderStats {
+            primary_rays,
+            secondary_rays,
+            shadow_rays,
+            intersection_tests: intersection_tests.into_iter().collect(),
+            path_rays,
+            samples_per_pixel,
+            preprocess_time,
+            trace_time,
+            image_time,
+            checkpoint_time,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    stats: RenderStats,
+    /// Set from a camera ray to the end of its sample.
+    in_sample: bool,
+    /// Whether the nearest surface along the camera ray of the sample is still to be searched.
+    camera_ray_pending: bool,
+}
+
+thread_local! {
+    /// The counters of this thread while it counts.
+    static COUNTERS: RefCell<Option<Counters>> = const { RefCell::new(None) };
+}
+
+/// Starts counting on this thread from zero, or stops counting.
+pub(crate) fn set_counting(counting: bool) {
+    COUNTERS.with(|counters| *counters.borrow_mut() = counting.then(Counters::default));
+}
+
+pub(crate) fn is_counting() -> bool {
+    COUNTERS.with(|counters| counters.borrow().is_some())
+}
+
+/// Stops counting on this thread and returns the counts, if it counted.
+pub(crate) fn stop_counting() -> Option<RenderStats> {
+    COUNTERS.with(|counters| counters.borrow_mut().take().map(|counters| counters.stats))
+}
+
+/// Returns the counts of this thread so far and counts on from zero.
+pub(crate) fn take_counts() -> Option<RenderStats> {
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        counters
+            .as_mut()
+            .map(|counters| std::mem::take(&mut counters.stats))
+    })
+}
+
+/// Lets `count` update the counts of this thread, if it counts.
+pub(crate) fn count<F: FnOnce(&mut RenderStats)>(count: F) {
+    COUNTERS.with(|counters| {
+        if let Some(counters) = counters.borrow_mut().as_mut() {
+            count(&mut counters.stats);
+        }
+    });
+}
+
+/// Counts a camera ray, which starts a camera sample.
+pub(crate) fn count_camera_ray() {
+    COUNTERS.with(|counters| {
+        if let Some(counters) = counters.borrow_mut().as_mut() {
+            counters.stats.primary_rays += 1;
+            counters.in_sample = true;
+            counters.camera_ray_pending = true;
+        }
+    });
+}
+
+pub(crate) fn end_camera_sample() {
+    COUNTERS.with(|counters| {
+        if let Some(counters) = counters.borrow_mut().as_mut() {
+            counters.in_sample = false;
+            counters.camera_ray_pending = false;
+        }
+    });
+}
+
+/// Counts a ray searching for the nearest surface. The first one of a camera sample is its camera ray.
+pub(crate) fn count_nearest_hit_ray() {
+    COUNTERS.with(|counters| {
+        if let Some(counters) = counters.borrow_mut().as_mut() {
+            if !counters.camera_ray_pending {
+                counters.stats.secondary_rays += 1;
+            }
+            if counters.in_sample {
+                counters.stats.path_rays += 1;
+            }
+            counters.camera_ray_pending = false;
+        }
+    });
+}
+
+pub(crate) fn count_shadow_ray() {
+    count(|stats| stats.shadow_rays += 1);
+}
+
+pub(crate) fn count_intersection_test(shape: &str) {
+    count(|stats| match stats.intersection_tests.get_mut(shape) {
+        Some(tests) => *tests += 1,
+        None => {
+            stats.intersection_tests.insert(shape.to_string(), 1);
+        }
+    });
+}
+
+/// Collects the counts of threads helping the thread that created it, e.g. the threads of a render pass.
+pub(crate) struct Helpers {
+    /// The counts of the helpers that are done, if the creating thread counts.
+    counts: Option<Mutex<RenderStats>>,
+}
+
+impl Helpers {
+    pub fn new() -> Helpers {
+        Helpers {
+            counts: is_counting().then(|| Mutex::new(RenderStats::default())),
+        }
+    }
+
+    /// Runs `f` on a helper thread, which counts if the creating thread does.
+    pub fn run<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let Some(counts) = &self.counts else {
+            return f();
+        };
+        set_counting(true);
+        let result = f();
+        if let Some(stats) = stop_counting() {
+            counts.lock().unwrap().add(&stats);
+        }
+        result
+    }
+
+    /// Adds counts made elsewhere, e.g. by another process.
+    pub fn add(&self, stats: &RenderStats) {
+        if let Some(counts) = &self.counts {
+            counts.lock().unwrap().add(stats);
+        }
+    }
+
+    /// Hands the counts of the helpers done so far to the creating thread.
+    pub fn finish(&self) {
+        if let Some(counts) = &self.counts {
+            let counts = std::mem::take(&mut *counts.lock().unwrap());
+            count(|stats| stats.add(&counts));
+        }
+    }
+}
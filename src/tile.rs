@@ -0,0 +1,196 @@
+//! Splitting of the image into tiles, which the render threads trace one at a time.
+
+use std::io::Result;
+
+use crate::io::{InputStream, OutputStream};
+use crate::serialize::{invalid_data, Deserialize, Serialize};
+
+/// A rectangle of pixels. Tiles at the right and bottom border of the image may be smaller than the rest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The order in which tiles are handed out to the render threads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    /// Row by row from the top left, like scanlines.
+    Scanline,
+    /// Along a Hilbert curve, so that consecutive tiles are neighbours and share most of the scene.
+    Hilbert,
+    /// Spiralling out from the centre, so that the centre of the image is finished first.
+    Spiral,
+}
+
+#[derive(Clone, Debug)]
+pub struct TileConfig {
+    /// Width and height of the tiles in pixels.
+    pub size: u32,
+    pub order: TileOrder,
+}
+
+impl Default for TileConfig {
+    fn default() -> TileConfig {
+        TileConfig {
+            size: 32,
+            order: TileOrder::Hilbert,
+        }
+    }
+}
+
+impl TileConfig {
+    /// Splits an image into tiles, in the order of the config.
+    pub fn tiles(&self, image_width: u32, image_height: u32) -> Vec<Tile> {
+        expect_neq!(self.size, 0);
+        let columns = image_width.div_ceil(self.size);
+        let rows = image_height.div_ceil(self.size);
+        let tile = |(column, row): (u32, u32)| {
+            let x = column * self.size;
+            let y = row * self.size;
+            Tile {
+                x,
+                y,
+                width: self.size.min(image_width - x),
+                height: self.size.min(image_height - y),
+            }
+        };
+
+        match self.order {
+            TileOrder::Scanline => (0..rows)
+                .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                .map(tile)
+                .collect(),
+            TileOrder::Hilbert => {
+                let n = columns.max(rows).next_power_of_two();
+                (0..n as u64 * n as u64)
+                    .map(|d| hilbert_point(n, d))
+                    .filter(|&(column, row)| column < columns && row < rows)
+                    .map(tile)
+                    .collect()
+            }
+            TileOrder::Spiral => spiral(columns, rows).into_iter().map(tile).collect(),
+        }
+    }
+}
+
+impl Serialize for TileConfig {
+    fn write_to(&self, out: &mut OutputStream) -> Result<()> {
+        self.size.write_to(out)?;
+        let order = match self.order {
+            TileOrder::Scanline => "scanline",
+            TileOrder::Hilbert => "hilbert",
+            TileOrder::Spiral => "spiral",
+        };
+        order.write_to(out)
+    }
+}
+
+impl Deserialize for TileConfig {
+    fn read_from(input: &mut InputStream) -> Result<TileConfig> {
+        let size = u32::read_from(input)?;
+        let order = match String::read_from(input)?.as_str() {
+            "scanline" => TileOrder::Scanline,
+            "hilbert" => TileOrder::Hilbert,
+            "spiral" => TileOrder::Spiral,
+            _ => return Err(invalid_data("Unknown tile order")),
+        };
+        if size == 0 {
+            return Err(invalid_data("Invalid tile size"));
+        }
+        Ok(TileConfig { size, order })
+    }
+}
+
+/// The point at distance `d` along the Hilbert curve through an `n` by `n` grid, with `n` a power of two.
+fn hilbert_point(n: u32, d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = (1 & (t / 2)) as u32;
+        let ry = (1 & (t ^ rx as u64)) as u32;
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/// All cells of a grid, spiralling out from its centre.
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let count = columns as usize * rows as usize;
+    let mut cells = Vec::with_capacity(count);
+    let (mut x, mut y) = ((columns as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut leg = 0;
+    while cells.len() < count {
+        // The legs of the spiral grow by one after every second turn.
+        let (dx, dy) = directions[leg % 4];
+        for _ in 0..leg / 2 + 1 {
+            if x >= 0 && y >= 0 && x < columns as i64 && y < rows as i64 {
+                cells.push((x as u32, y as u32));
+            }
+            x += dx;
+            y += dy;
+        }
+        leg += 1;
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_order_covers_the_image_once() {
+        for order in [TileOrder::Scanline, TileOrder::Hilbert, TileOrder::Spiral] {
+            let config = TileConfig { size: 16, order };
+            let tiles = config.tiles(100, 37);
+            assert_eq!(7 * 3, tiles.len());
+
+            let mut covered = vec![0; 100 * 37];
+            for tile in &tiles {
+                for y in tile.y..tile.y + tile.height {
+                    for x in tile.x..tile.x + tile.width {
+                        covered[(y * 100 + x) as usize] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|&count| count == 1), "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn hilbert_and_spiral_orders_keep_tiles_together() {
+        // Consecutive tiles of a Hilbert curve through a square grid are neighbours.
+        let hilbert = TileConfig {
+            size: 1,
+            order: TileOrder::Hilbert,
+        }
+        .tiles(8, 8);
+        for pair in hilbert.windows(2) {
+            let distance = pair[0].x.abs_diff(pair[1].x) + pair[0].y.abs_diff(pair[1].y);
+            assert_eq!(1, distance);
+        }
+
+        let spiral = TileConfig {
+            size: 10,
+            order: TileOrder::Spiral,
+        }
+        .tiles(50, 30);
+        assert_eq!((20, 10), (spiral[0].x, spiral[0].y));
+        assert_eq!((30, 10), (spiral[1].x, spiral[1].y));
+    }
+}
@@ -1,49 +1,234 @@
 use num_cpus;
-use rand::{self, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
 use std::option::Option;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::camera::Camera;
 use crate::color::Color;
-use crate::math::{Ray3, Vector2};
-use crate::sampler::UnitSquareSampler;
-use crate::shapes::{Hitable, Intersection};
+use crate::crop::{CropConfig, CropOutput};
+use crate::film::Film;
+use crate::filter::Filter;
+use crate::integrator::Integrator;
+use crate::io::{InputStream, OutputStream};
+use crate::light::{luminance, Light};
+use crate::math::Vector2;
+use crate::medium::Medium;
+use crate::progress::{CancellationToken, Progress, ProgressObserver};
+use crate::sampler::{stream_seed, Sampler, UnitSquareSampler};
+use crate::scene::Scene;
+use crate::serialize::{Deserialize, Serialize};
+use crate::shapes::Hitable;
+use crate::spectrum::SampledWavelengths;
+use crate::stats::{self, Helpers, RenderStats};
+use crate::tile::{Tile, TileConfig};
 use crate::{RenderBuffer, TraceContext};
 
+pub mod distributed;
+
 pub struct RendererConfig {
     pub image_width: u32,
     pub image_height: u32,
     pub pixel_size: f64,
     pub pixel_sampler: UnitSquareSampler,
+    /// Keeps adding batches of pixel samples to the pixels that are still noisy. Without it every pixel
+    /// receives one set of the pixel sampler.
+    pub adaptive_sampling: Option<AdaptiveSamplingConfig>,
+    /// Weights the contribution of every pixel sample to the pixels around it.
+    pub filter: Arc<dyn Filter>,
+    /// Number of passes over the image. Every pass adds one set of the pixel sampler to every pixel, or
+    /// with adaptive sampling an equal share of the maximum number of samples to the pixels that are still
+    /// noisy. Ignored with a render budget.
+    pub passes: u32,
+    /// Keeps adding passes until the budget is used up, instead of rendering a fixed number of them.
+    pub budget: Option<RenderBudget>,
+    /// Upper bound on the number of scattering events along a path.
     pub max_trace_depth: u32,
     pub ambient_color: Color,
+    /// A medium filling the whole scene, e.g. for haze or fog.
+    pub atmosphere: Option<Arc<dyn Medium>>,
+    /// Lights that are sampled directly at every scattering event. Lights at infinity also add their
+    /// radiance to rays leaving the scene, on top of `ambient_color`.
+    pub lights: Vec<Arc<dyn Light>>,
+    /// The algorithm computing the radiance of each camera ray.
+    pub integrator: Arc<dyn Integrator>,
+    /// Traces wavelengths instead of RGB, e.g. for dispersion. Camera rays sample hero wavelengths, paths carry
+    /// spectra at them and their radiance is accumulated through CIE XYZ. Integrators without spectral paths,
+    /// see `Integrator::spectral_radiance`, still render in RGB.
+    pub spectral: bool,
+    /// Seeds all random choices of a render. Renders with the same seed are identical, whatever the number
+    /// of threads.
+    pub seed: u64,
+    /// Counts rays, intersection tests and the time spent in every phase of renders, see
+    /// `Renderer::statistics`. Counting takes a little time.
+    pub statistics: bool,
+    /// Saves the state of the render between passes, so that an interrupted render can be resumed.
+    pub checkpoint: Option<CheckpointConfig>,
+    /// Receives the progress of renders after every tile.
+    pub progress: Option<Arc<dyn ProgressObserver>>,
+    /// Traces only the pixels in a window of the image.
+    pub crop: Option<CropConfig>,
+    /// How the image is split into the tiles that the render threads trace.
+    pub tiles: TileConfig,
     pub num_render_threads: Option<u32>,
 }
 
+#[derive(Clone, Debug)]
+pub struct AdaptiveSamplingConfig {
+    /// Relative standard error of the luminance of a pixel below which it receives no more samples.
+    pub target_error: f64,
+    /// Upper bound on the number of samples of a pixel. Pixels receive whole sets of the pixel sampler,
+    /// so the bound is rounded up to a multiple of the set size.
+    pub max_samples_per_pixel: u32,
+}
+
+impl Default for AdaptiveSamplingConfig {
+    fn default() -> AdaptiveSamplingConfig {
+        AdaptiveSamplingConfig {
+            target_error: 0.05,
+            max_samples_per_pixel: 256,
+        }
+    }
+}
+
+impl Serialize for AdaptiveSamplingConfig {
+    fn write_to(&self, out: &mut OutputStream) -> std::io::Result<()> {
+        self.target_error.write_to(out)?;
+        self.max_samples_per_pixel.write_to(out)
+    }
+}
+
+impl Deserialize for AdaptiveSamplingConfig {
+    fn read_from(input: &mut InputStream) -> std::io::Result<AdaptiveSamplingConfig> {
+        Ok(AdaptiveSamplingConfig {
+            target_error: f64::read_from(input)?,
+            max_samples_per_pixel: u32::read_from(input)?,
+        })
+    }
+}
+
+/// Ends a render once it has run out of time or reached a noise level, whichever comes first. Every pass adds
+/// one set of the pixel sampler to every pixel, or with adaptive sampling one batch to the pixels that are
+/// still noisy, until all of them have reached the maximum number of samples. Without any limit the render
+/// runs until it is cancelled or stopped after a pass.
+#[derive(Clone, Debug, Default)]
+pub struct RenderBudget {
+    /// Wall-clock time of the render, including the preprocessing of the integrator. A pass is only started
+    /// if it is expected to end in time, judging by the pass before it; the first pass always runs.
+    pub time: Option<Duration>,
+    /// Relative standard error of the luminance, averaged over all pixels, at which the render ends. Ignored
+    /// with integrators that only splat, because the error is estimated from the camera samples.
+    pub target_error: Option<f64>,
+}
+
+impl Serialize for RenderBudget {
+    fn write_to(&self, out: &mut OutputStream) -> std::io::Result<()> {
+        self.time.write_to(out)?;
+        self.target_error.write_to(out)
+    }
+}
+
+impl Deserialize for RenderBudget {
+    fn read_from(input: &mut InputStream) -> std::io::Result<RenderBudget> {
+        Ok(RenderBudget {
+            time: Deserialize::read_from(input)?,
+            target_error: Deserialize::read_from(input)?,
+        })
+    }
+}
+
+/// What a render achieved.
+#[derive(Clone, Debug)]
+pub struct RenderSummary {
+    /// Passes finished, including those before the render was resumed.
+    pub passes: u32,
+    /// Camera samples per pixel, averaged over the image.
+    pub samples_per_pixel: f64,
+    /// Relative standard error of the luminance, averaged over all pixels.
+    pub relative_error: f64,
+    /// Time since the render was started or resumed.
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckpointConfig {
+    /// The checkpoint file. It is replaced atomically, so that a crash while writing it keeps the
+    /// previous checkpoint.
+    pub path: PathBuf,
+    /// Minimum time between two checkpoints. Checkpoints are written after passes, and always after the
+    /// last pass of a render.
+    pub interval: Duration,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> CheckpointConfig {
+        CheckpointConfig {
+            path: PathBuf::from("render.checkpoint"),
+            interval: Duration::from_secs(600),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Renderer {
     image_width: u32,
     image_height: u32,
     pixel_size: f64,
     pixel_sampler: UnitSquareSampler,
+    adaptive_sampling: Option<AdaptiveSamplingConfig>,
+    filter: Arc<dyn Filter>,
+    passes: u32,
+    budget: Option<RenderBudget>,
     max_trace_depth: u32,
     ambient_color: Color,
+    atmosphere: Option<Arc<dyn Medium>>,
+    lights: Vec<Arc<dyn Light>>,
+    integrator: Arc<dyn Integrator>,
+    spectral: bool,
+    seed: u64,
+    checkpoint: Option<CheckpointConfig>,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    cancellation: CancellationToken,
+    crop: Option<CropConfig>,
+    tiles: TileConfig,
     num_render_threads: u32,
     image_buffer: Arc<Mutex<RenderBuffer>>,
+    sample_count_buffer: Option<Arc<Mutex<RenderBuffer>>>,
+    statistics: Option<Arc<Mutex<RenderStats>>>,
 }
 
 impl Renderer {
     pub fn new(config: &RendererConfig) -> Renderer {
+        expect_neq!(config.passes, 0);
         Renderer {
             image_width: config.image_width,
             image_height: config.image_height,
             pixel_size: config.pixel_size,
             pixel_sampler: config.pixel_sampler.clone(),
+            adaptive_sampling: config.adaptive_sampling.clone(),
+            filter: Arc::clone(&config.filter),
+            passes: config.passes,
+            budget: config.budget.clone(),
             max_trace_depth: config.max_trace_depth,
             ambient_color: config.ambient_color,
+            atmosphere: config.atmosphere.clone(),
+            lights: config.lights.clone(),
+            integrator: Arc::clone(&config.integrator),
+            spectral: config.spectral,
+            seed: config.seed,
+            checkpoint: config.checkpoint.clone(),
+            progress: config.progress.clone(),
+            cancellation: CancellationToken::new(),
+            crop: config.crop.clone(),
+            tiles: config.tiles.clone(),
             num_render_threads: config
                 .num_render_threads
                 .unwrap_or_else(|| num_cpus::get() as u32),
@@ -51,6 +236,15 @@ impl Renderer {
                 config.image_width,
                 config.image_height,
             ))),
+            sample_count_buffer: config.adaptive_sampling.as_ref().map(|_| {
+                Arc::new(Mutex::new(RenderBuffer::new(
+                    config.image_width,
+                    config.image_height,
+                )))
+            }),
+            statistics: config
+                .statistics
+                .then(|| Arc::new(Mutex::new(RenderStats::default()))),
         }
     }
 
@@ -58,32 +252,323 @@ impl Renderer {
         self.image_buffer.lock().unwrap().write_to_file(path)
     }
 
-    pub fn render(&mut self, camera: &Arc<dyn Camera>, objects: &Arc<Vec<Arc<dyn Hitable>>>) {
-        let mut handles = Vec::new();
-        let next_line = Arc::new(AtomicU32::new(0));
+    /// The token that cancels the renders of this renderer. Cancelled renders keep the samples traced so
+    /// far and return early, without calling back or writing a checkpoint for the unfinished pass.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// With adaptive sampling, the number of samples each pixel received as a gray image, in which white
+    /// stands for the maximum number of samples per pixel.
+    pub fn sample_counts(&self) -> Option<RenderBuffer> {
+        self.sample_count_buffer
+            .as_ref()
+            .map(|buffer| buffer.lock().unwrap().clone())
+    }
+
+    /// With statistics enabled, what the last render did.
+    pub fn statistics(&self) -> Option<RenderStats> {
+        self.statistics
+            .as_ref()
+            .map(|statistics| statistics.lock().unwrap().clone())
+    }
+
+    pub fn render(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+    ) -> std::io::Result<RenderSummary> {
+        self.render_progressive(camera, objects, |_, _| true)
+    }
+
+    /// Renders the configured number of passes, each adding one set of the pixel sampler to every pixel, or
+    /// as many as the budget allows. After every pass `on_pass` receives the index of the pass and the image
+    /// so far, and may stop the render early by returning false. The image of the renderer is always that of
+    /// the last finished pass. Fails if a checkpoint cannot be written, or the image to composite into read.
+    pub fn render_progressive<F>(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+        on_pass: F,
+    ) -> std::io::Result<RenderSummary>
+    where
+        F: FnMut(u32, &RenderBuffer) -> bool,
+    {
+        let started = Instant::now();
+        let tracer = self.start(camera, objects)?;
+        let trace_pass = |pass, progress: &_| self.trace_pass(&tracer, camera, pass, progress);
+        self.trace_passes(&tracer, 0, started, trace_pass, on_pass)
+    }
+
+    pub fn resume<P: AsRef<Path>>(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+        checkpoint: P,
+    ) -> std::io::Result<RenderSummary> {
+        self.resume_progressive(camera, objects, checkpoint, |_, _| true)
+    }
+
+    /// Continues the render saved in the file `checkpoint` with the remaining passes. The renderer and the
+    /// scene must be configured as for the interrupted render, which makes the result identical to that of
+    /// a render that was never interrupted.
+    pub fn resume_progressive<P, F>(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+        checkpoint: P,
+        on_pass: F,
+    ) -> std::io::Result<RenderSummary>
+    where
+        P: AsRef<Path>,
+        F: FnMut(u32, &RenderBuffer) -> bool,
+    {
+        let started = Instant::now();
+        let tracer = self.start(camera, objects)?;
+        let first_pass = tracer.read_checkpoint(checkpoint.as_ref())?;
+        self.update_images(&tracer);
+        let trace_pass = |pass, progress: &_| self.trace_pass(&tracer, camera, pass, progress);
+        self.trace_passes(&tracer, first_pass, started, trace_pass, on_pass)
+    }
+
+    /// Sets up the tracer of a new render and lets the integrator preprocess the scene. Fails if the image to
+    /// composite a crop window into cannot be read.
+    fn start(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+    ) -> std::io::Result<Tracer> {
+        let tracer = self.set_up(camera, objects)?;
+        let preprocess_started = Instant::now();
+        self.integrator.preprocess(&tracer.scene, &tracer.film);
+        stats::count(|stats| stats.preprocess_time += preprocess_started.elapsed());
+        self.update_images(&tracer);
+        Ok(tracer)
+    }
+
+    /// Sets up the tracer of a new render without preprocessing, see `start`.
+    fn set_up(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+    ) -> std::io::Result<Tracer> {
+        let mut film = Film::new(
+            self.image_width,
+            self.image_height,
+            self.pixel_size,
+            camera,
+        );
+        film.seed = self.seed;
+        film.num_threads = self.num_render_threads;
+        let window = match &self.crop {
+            Some(crop) => crop.window.pixels(self.image_width, self.image_height),
+            None => Tile {
+                x: 0,
+                y: 0,
+                width: self.image_width,
+                height: self.image_height,
+            },
+        };
+        let composite = match self.crop.as_ref().map(|crop| &crop.output) {
+            Some(CropOutput::Composite(path)) => {
+                let image = RenderBuffer::read_from_file(path)?;
+                if image.width() != self.image_width || image.height() != self.image_height {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Image to composite into differs in size",
+                    ));
+                }
+                Some(Arc::new(image))
+            }
+            _ => None,
+        };
+
+        // Samples just outside the window reach its pixels through the filter, so they are traced as well.
+        let margin = (self.filter.radius() + 0.5).ceil() as u32 - 1;
+        let x = window.x.saturating_sub(margin);
+        let y = window.y.saturating_sub(margin);
+        let traced = Tile {
+            x,
+            y,
+            width: (window.x + window.width + margin).min(self.image_width) - x,
+            height: (window.y + window.height + margin).min(self.image_height) - y,
+        };
+        let tiles: Vec<Tile> = self
+            .tiles
+            .tiles(traced.width, traced.height)
+            .into_iter()
+            .map(|tile| Tile {
+                x: traced.x + tile.x,
+                y: traced.y + tile.y,
+                ..tile
+            })
+            .collect();
         let tracer = Tracer {
             image_width: self.image_width,
             image_height: self.image_height,
             pixel_size: self.pixel_size,
             pixel_sampler: self.pixel_sampler.clone(),
-            max_trace_depth: self.max_trace_depth,
-            ambient_color: self.ambient_color,
-            image_buffer: Arc::clone(&self.image_buffer),
+            adaptive_sampling: self.adaptive_sampling.clone(),
+            filter: Arc::clone(&self.filter),
+            passes: match self.budget {
+                Some(_) => None,
+                None => Some(self.passes),
+            },
+            integrator: Arc::clone(&self.integrator),
+            spectral: self.spectral,
+            film: Arc::new(film),
+            scene: Scene {
+                objects: Arc::clone(objects),
+                lights: self.lights.clone(),
+                atmosphere: self.atmosphere.clone(),
+                ambient_color: self.ambient_color,
+                max_trace_depth: self.max_trace_depth,
+            },
+            accumulation_buffer: Arc::new(Mutex::new(RenderBuffer::new(
+                self.image_width,
+                self.image_height,
+            ))),
+            samples_traced: Arc::new(AtomicU64::new(0)),
+            pixels: Arc::new(
+                tiles
+                    .iter()
+                    .map(|tile| {
+                        let num_pixels = (tile.width * tile.height) as usize;
+                        Mutex::new(vec![PixelState::default(); num_pixels])
+                    })
+                    .collect(),
+            ),
+            tiles: Arc::new(tiles),
+            traced,
+            window,
+            composite,
+            pending_tiles: Arc::new(Mutex::new(PendingTiles::default())),
+            cancellation: self.cancellation.clone(),
         };
 
+        // The thread running the render collects the counts of all threads helping it.
+        stats::set_counting(self.statistics.is_some());
+        Ok(tracer)
+    }
+
+    /// Runs the passes of a render, tracing the tiles of each one with `trace_pass`.
+    fn trace_passes<T, F>(
+        &self,
+        tracer: &Tracer,
+        first_pass: u32,
+        started: Instant,
+        mut trace_pass: T,
+        mut on_pass: F,
+    ) -> std::io::Result<RenderSummary>
+    where
+        T: FnMut(u32, &Option<Arc<ProgressCounter>>),
+        F: FnMut(u32, &RenderBuffer) -> bool,
+    {
+        let mut last_checkpoint = Instant::now();
+        let progress = self.progress.as_ref().map(|observer| {
+            let tiles_done = first_pass as usize * tracer.tiles.len();
+            Arc::new(ProgressCounter {
+                observer: Arc::clone(observer),
+                started: Instant::now(),
+                tiles_at_start: tiles_done,
+                tiles_done: AtomicUsize::new(tiles_done),
+                tiles_total: AtomicUsize::new(self.passes as usize * tracer.tiles.len()),
+            })
+        });
+        let end_pass = tracer.passes.unwrap_or(u32::MAX);
+        let mut passes_done = first_pass;
+        for pass in first_pass..end_pass {
+            let pass_started = Instant::now();
+            let samples_before = tracer.samples_traced.load(Ordering::Relaxed);
+            if let (Some(progress), None) = (&progress, tracer.passes) {
+                // The number of passes is open, so the progress counts up to the end of this one.
+                let tiles_total = (pass as usize + 1) * tracer.tiles.len();
+                progress.tiles_total.store(tiles_total, Ordering::Relaxed);
+            }
+            *tracer.pending_tiles.lock().unwrap() = PendingTiles::default();
+            trace_pass(pass, &progress);
+            let images_started = Instant::now();
+            stats::count(|stats| stats.trace_time += images_started - pass_started);
+            // Tiles after one that was never traced wait in vain for their turn.
+            tracer.add_pending_tiles();
+            self.update_images(tracer);
+            stats::count(|stats| stats.image_time += images_started.elapsed());
+            if tracer.cancellation.is_cancelled() {
+                break;
+            }
+            passes_done = pass + 1;
+
+            let proceed = on_pass(pass, &self.image_buffer.lock().unwrap());
+            let budget_spent = self.budget.as_ref().is_some_and(|budget| {
+                let pass_time = pass_started.elapsed();
+                budget
+                    .time
+                    .is_some_and(|time| started.elapsed() + pass_time > time)
+                    || budget.target_error.is_some_and(|target_error| {
+                        !tracer.integrator.splats_only() && tracer.relative_error() <= target_error
+                    })
+                    || tracer.samples_traced.load(Ordering::Relaxed) == samples_before
+            });
+            if let Some(checkpoint) = &self.checkpoint {
+                let last_pass = !proceed || budget_spent || pass + 1 == end_pass;
+                if last_pass || last_checkpoint.elapsed() >= checkpoint.interval {
+                    let checkpoint_started = Instant::now();
+                    tracer.write_checkpoint(&checkpoint.path, pass + 1)?;
+                    last_checkpoint = Instant::now();
+                    stats::count(|stats| stats.checkpoint_time += last_checkpoint - checkpoint_started);
+                }
+            }
+            if !proceed || budget_spent {
+                break;
+            }
+        }
+
+        if let (Some(statistics), Some(mut counts)) = (&self.statistics, stats::stop_counting()) {
+            counts.samples_per_pixel = tracer.samples_per_pixel();
+            *statistics.lock().unwrap() = counts;
+        }
+        Ok(RenderSummary {
+            passes: passes_done,
+            samples_per_pixel: tracer.samples_per_pixel(),
+            relative_error: tracer.relative_error(),
+            elapsed: started.elapsed(),
+        })
+    }
+
+    fn trace_pass(
+        &self,
+        tracer: &Tracer,
+        camera: &Arc<dyn Camera>,
+        pass: u32,
+        progress: &Option<Arc<ProgressCounter>>,
+    ) {
+        let mut handles = Vec::new();
+        let next_tile = Arc::new(AtomicUsize::new(0));
+        let helpers = Arc::new(Helpers::new());
+
         for _ in 0..self.num_render_threads {
-            let next_line = Arc::clone(&next_line);
+            let next_tile = Arc::clone(&next_tile);
             let camera = Arc::clone(camera);
-            let objects = Arc::clone(objects);
             let tracer = tracer.clone();
-            let handle = thread::spawn(move || loop {
-                let y = next_line.fetch_add(1, Ordering::Relaxed);
+            let progress = progress.clone();
+            let helpers = Arc::clone(&helpers);
+            let handle = thread::spawn(move || {
+                helpers.run(|| loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
 
-                if y >= tracer.image_height {
-                    break;
-                }
+                    if index >= tracer.tiles.len() || tracer.cancellation.is_cancelled() {
+                        break;
+                    }
 
-                tracer.trace_line(&camera, &objects, y);
+                    let tile = tracer.trace_tile(&camera, index, pass);
+                    tracer.add_tile(index, tile);
+                    if tracer.cancellation.is_cancelled() {
+                        break;
+                    }
+                    if let Some(progress) = &progress {
+                        progress.report(pass, tracer.samples_traced.load(Ordering::Relaxed));
+                    }
+                })
             });
             handles.push(handle);
         }
@@ -91,6 +576,47 @@ impl Renderer {
         for handle in handles {
             handle.join().unwrap();
         }
+        helpers.finish();
+    }
+
+    /// Updates the image, and with adaptive sampling the sample counts, to all samples traced so far.
+    fn update_images(&self, tracer: &Tracer) {
+        // Every camera sample may splat anywhere on the film, so their splats are averaged over all of them as
+        // if they were spread over the whole film, also when only a crop window is traced.
+        let samples_traced = tracer.samples_traced.load(Ordering::Relaxed);
+        let scale = if samples_traced > 0 {
+            self.image_width as f64 * self.image_height as f64 / samples_traced as f64
+        } else {
+            0.0
+        };
+        let mut image = tracer.accumulation_buffer.lock().unwrap().clone();
+        tracer.film.add_splats_to(&mut image, scale);
+        *self.image_buffer.lock().unwrap() = match self.crop.as_ref().map(|crop| &crop.output) {
+            Some(CropOutput::Cropped) => tracer.crop(&image),
+            _ => tracer.composite(&image),
+        };
+
+        if let (Some(buffer), Some(adaptive)) = (&self.sample_count_buffer, &self.adaptive_sampling)
+        {
+            let samples_per_set = self.pixel_sampler.samples_per_set() as u32;
+            let max_samples = adaptive.max_samples_per_pixel.max(samples_per_set) as f64;
+            let mut counts = RenderBuffer::new(self.image_width, self.image_height);
+            for (i, pixel) in tracer.pixel_states().iter().enumerate() {
+                let gray = (pixel.estimate.count as f64 / max_samples).min(1.0);
+                let color = Color {
+                    r: gray,
+                    g: gray,
+                    b: gray,
+                    a: 1.0,
+                };
+                let (x, y) = (i as u32 % self.image_width, i as u32 / self.image_width);
+                counts.set_pixel(x, y, color);
+            }
+            *buffer.lock().unwrap() = match self.crop.as_ref().map(|crop| &crop.output) {
+                Some(CropOutput::Cropped) => tracer.crop(&counts),
+                _ => counts,
+            };
+        }
     }
 }
 
@@ -100,86 +626,1054 @@ struct Tracer {
     image_height: u32,
     pixel_size: f64,
     pixel_sampler: UnitSquareSampler,
-    max_trace_depth: u32,
-    ambient_color: Color,
-    image_buffer: Arc<Mutex<RenderBuffer>>,
+    adaptive_sampling: Option<AdaptiveSamplingConfig>,
+    filter: Arc<dyn Filter>,
+    /// The number of passes of the render, unless a budget decides when it ends.
+    passes: Option<u32>,
+    integrator: Arc<dyn Integrator>,
+    spectral: bool,
+    film: Arc<Film>,
+    scene: Scene,
+    /// The filtered samples of all passes so far, without splats.
+    accumulation_buffer: Arc<Mutex<RenderBuffer>>,
+    samples_traced: Arc<AtomicU64>,
+    /// The tiles in the order they are traced.
+    tiles: Arc<Vec<Tile>>,
+    /// The pixels covered by the tiles: the crop window and the pixels around it that reach it through
+    /// the filter.
+    traced: Tile,
+    /// The pixels of the image that are rendered.
+    window: Tile,
+    /// The image that the crop window is pasted into.
+    composite: Option<Arc<RenderBuffer>>,
+    /// The state of the pixels of every tile, row by row.
+    pixels: Arc<Vec<Mutex<Vec<PixelState>>>>,
+    pending_tiles: Arc<Mutex<PendingTiles>>,
+    cancellation: CancellationToken,
+}
+
+/// Counts the tiles traced by a render and reports its progress.
+struct ProgressCounter {
+    observer: Arc<dyn ProgressObserver>,
+    started: Instant,
+    /// Tiles that were traced before the render was resumed.
+    tiles_at_start: usize,
+    tiles_done: AtomicUsize,
+    tiles_total: AtomicUsize,
+}
+
+impl ProgressCounter {
+    /// Counts a tile of `pass` as done and reports the progress.
+    fn report(&self, pass: u32, camera_rays: u64) {
+        let tiles_done = self.tiles_done.fetch_add(1, Ordering::Relaxed) + 1;
+        let elapsed = self.started.elapsed();
+        let traced = (tiles_done - self.tiles_at_start) as u32;
+        let tiles_total = self.tiles_total.load(Ordering::Relaxed);
+        let remaining = tiles_total.saturating_sub(tiles_done) as u32;
+        self.observer.on_progress(&Progress {
+            pass,
+            tiles_done,
+            tiles_total,
+            camera_rays,
+            elapsed,
+            eta: elapsed / traced * remaining,
+        });
+    }
+}
+
+/// Identifies checkpoint files and their version.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"ARDCKPT5";
+
+/// What the passes over a pixel carry over to the next pass.
+#[derive(Clone, Default)]
+struct PixelState {
+    set_offset: usize,
+    sample_offset: usize,
+    estimate: PixelEstimate,
+}
+
+impl Serialize for PixelState {
+    fn write_to(&self, out: &mut OutputStream) -> std::io::Result<()> {
+        self.set_offset.write_to(out)?;
+        self.sample_offset.write_to(out)?;
+        self.estimate.write_to(out)
+    }
+}
+
+impl Deserialize for PixelState {
+    fn read_from(input: &mut InputStream) -> std::io::Result<PixelState> {
+        Ok(PixelState {
+            set_offset: usize::read_from(input)?,
+            sample_offset: usize::read_from(input)?,
+            estimate: PixelEstimate::read_from(input)?,
+        })
+    }
+}
+
+/// The filtered samples of one tile, which spread over the pixels within the filter radius around it.
+struct FilteredTile {
+    first_column: u32,
+    first_row: u32,
+    buffer: RenderBuffer,
+    /// The number of camera samples traced for the tile.
+    samples: u64,
+}
+
+impl FilteredTile {
+    fn add_to(&self, buffer: &mut RenderBuffer) {
+        for y in 0..self.buffer.height() {
+            for x in 0..self.buffer.width() {
+                buffer.add_weighted_sum(
+                    self.first_column + x,
+                    self.first_row + y,
+                    &self.buffer.get_weighted_sum(x, y),
+                    self.buffer.get_weight(x, y),
+                );
+            }
+        }
+    }
+}
+
+impl Serialize for FilteredTile {
+    fn write_to(&self, out: &mut OutputStream) -> std::io::Result<()> {
+        self.first_column.write_to(out)?;
+        self.first_row.write_to(out)?;
+        self.buffer.write_to(out)?;
+        self.samples.write_to(out)
+    }
+}
+
+impl Deserialize for FilteredTile {
+    fn read_from(input: &mut InputStream) -> std::io::Result<FilteredTile> {
+        Ok(FilteredTile {
+            first_column: u32::read_from(input)?,
+            first_row: u32::read_from(input)?,
+            buffer: RenderBuffer::read_from(input)?,
+            samples: u64::read_from(input)?,
+        })
+    }
+}
+
+/// Tiles that are traced but not yet added to the image. They are added in order, so that the floating
+/// point sums of overlapping tiles do not depend on which thread finishes first.
+#[derive(Default)]
+struct PendingTiles {
+    next_tile: usize,
+    tiles: BTreeMap<usize, FilteredTile>,
+}
+
+/// The variance of the luminance of the samples of a pixel.
+#[derive(Clone, Default)]
+struct PixelEstimate {
+    luminance_sum: f64,
+    luminance_sum_squared: f64,
+    count: u32,
+}
+
+impl PixelEstimate {
+    fn add(&mut self, sample: &Color) {
+        let l = luminance(sample);
+        self.luminance_sum += l;
+        self.luminance_sum_squared += l * l;
+        self.count += 1;
+    }
+
+    /// The standard error of the mean luminance relative to the mean. Dark pixels are compared against 1%
+    /// of white instead, because their noise stays invisible, so that they do not take samples forever.
+    fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.count as f64;
+        let mean = self.luminance_sum / n;
+        let variance = ((self.luminance_sum_squared - n * mean * mean) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() / mean.max(0.01)
+    }
+}
+
+impl Serialize for PixelEstimate {
+    fn write_to(&self, out: &mut OutputStream) -> std::io::Result<()> {
+        self.luminance_sum.write_to(out)?;
+        self.luminance_sum_squared.write_to(out)?;
+        self.count.write_to(out)
+    }
+}
+
+impl Deserialize for PixelEstimate {
+    fn read_from(input: &mut InputStream) -> std::io::Result<PixelEstimate> {
+        Ok(PixelEstimate {
+            luminance_sum: f64::read_from(input)?,
+            luminance_sum_squared: f64::read_from(input)?,
+            count: u32::read_from(input)?,
+        })
+    }
 }
 
 impl Tracer {
-    fn trace_line(&self, camera: &Arc<dyn Camera>, objects: &Arc<Vec<Arc<dyn Hitable>>>, y: u32) {
-        let image_dim = Vector2::new(self.image_width as f64, self.image_height as f64);
-        let half = Vector2::new(0.5, 0.5);
-        let num_pixel_sets = self.pixel_sampler.samples.len();
-        let mut rng = rand::thread_rng();
-        let mut out = Vec::with_capacity(self.image_width as usize);
-
-        let mut set_offset: usize = rng.gen();
-
-        for x in 0..self.image_width {
-            let mut color = Color::black();
-            let sample_offset: usize = rng.gen();
-            let pixel_corner = self.pixel_size
-                * (Vector2 {
-                    x: x as f64,
-                    y: y as f64,
-                } - 0.5 * image_dim
-                    - half);
-
-            set_offset += 1;
-
-            let pixel_set_index = set_offset % num_pixel_sets;
-
-            for (idx, &sample) in self.pixel_sampler.samples[pixel_set_index]
-                .iter()
-                .enumerate()
-            {
-                let trace_context = TraceContext {
-                    set_index: set_offset,
-                    sample_index: sample_offset + idx,
+    /// Traces the samples of the tile `index` in `pass` and returns them, to be added with `add_tile`.
+    fn trace_tile(&self, camera: &Arc<dyn Camera>, index: usize, pass: u32) -> FilteredTile {
+        let tile = self.tiles[index];
+        let samples_per_pixel = self.pixel_sampler.samples_per_set();
+        let reach = self.filter.radius().ceil() as u32;
+        let first_column = tile.x.saturating_sub(reach);
+        let first_row = tile.y.saturating_sub(reach);
+        let last_column = (tile.x + tile.width - 1 + reach).min(self.image_width - 1);
+        let last_row = (tile.y + tile.height - 1 + reach).min(self.image_height - 1);
+        let mut filtered = FilteredTile {
+            first_column,
+            first_row,
+            buffer: RenderBuffer::new(last_column - first_column + 1, last_row - first_row + 1),
+            samples: 0,
+        };
+
+        // With adaptive sampling every pass lets the pixels take up to their share of the maximum samples,
+        // or one more batch when the number of passes is open.
+        let pass_samples = self.adaptive_sampling.as_ref().map(|adaptive| {
+            let max_samples = adaptive.max_samples_per_pixel as u64;
+            let samples = match self.passes {
+                Some(passes) => (max_samples * (pass as u64 + 1)).div_ceil(passes as u64),
+                None => (samples_per_pixel as u64 * (pass as u64 + 1)).min(max_samples),
+            };
+            samples as u32
+        });
+        let mut pixels = self.pixels[index].lock().unwrap();
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            if self.cancellation.is_cancelled() {
+                break;
+            }
+            let x = tile.x + i as u32 % tile.width;
+            let y = tile.y + i as u32 / tile.width;
+            // Every pixel and pass has its own random numbers, so the image depends neither on the tiles nor
+            // on the threads tracing them.
+            let stream = ((pass as u64) << 32) | (y as u64 * self.image_width as u64 + x as u64);
+            let mut rng = StdRng::seed_from_u64(stream_seed(self.film.seed, stream));
+            if pass == 0 {
+                pixel.set_offset = rng.gen();
+                pixel.sample_offset = rng.gen();
+            }
+
+            let first_batch = pixel.estimate.count as usize / samples_per_pixel;
+            for batch in first_batch.. {
+                let converged = match (&self.adaptive_sampling, pass_samples) {
+                    (Some(adaptive), Some(pass_samples)) => {
+                        pixel.estimate.count >= pass_samples
+                            || pixel.estimate.relative_error() <= adaptive.target_error
+                    }
+                    _ => batch > first_batch,
+                };
+                if converged {
+                    break;
+                }
+
+                // All samples of a batch come from one set, so they are stratified in every dimension of the path.
+                for idx in 0..samples_per_pixel {
+                    let pixel_context = TraceContext {
+                        set_index: pixel.set_offset.wrapping_add(batch),
+                        sample_index: pixel.sample_offset.wrapping_add(idx),
+                        dimension: 0,
+                        wavelengths: if self.spectral {
+                            Some(SampledWavelengths::sample(rng.gen()))
+                        } else {
+                            None
+                        },
+                    };
+                    let raster = Vector2::new(x as f64, y as f64)
+                        + self.pixel_sampler.sample_for(&pixel_context);
+                    let mut color = self.trace_sample(camera, raster, &pixel_context, &mut rng);
+                    color.a = 1.0;
+                    pixel.estimate.add(&color);
+                    self.add_filtered_sample(&mut filtered, raster, &color);
+                }
+                filtered.samples += samples_per_pixel as u64;
+            }
+        }
+        filtered
+    }
+
+    /// Camera samples per traced pixel.
+    fn samples_per_pixel(&self) -> f64 {
+        let num_pixels = self.traced.width as f64 * self.traced.height as f64;
+        self.samples_traced.load(Ordering::Relaxed) as f64 / num_pixels
+    }
+
+    /// The relative standard error of the luminance, averaged over all traced pixels.
+    fn relative_error(&self) -> f64 {
+        let mut sum = 0.0;
+        for pixels in self.pixels.iter() {
+            for pixel in pixels.lock().unwrap().iter() {
+                sum += pixel.estimate.relative_error();
+            }
+        }
+        sum / (self.traced.width as f64 * self.traced.height as f64)
+    }
+
+    /// The crop window of a full-size image.
+    fn crop(&self, image: &RenderBuffer) -> RenderBuffer {
+        let window = self.window;
+        let mut cropped = RenderBuffer::new(window.width, window.height);
+        for y in 0..window.height {
+            for x in 0..window.width {
+                cropped.add_weighted_sum(
+                    x,
+                    y,
+                    &image.get_weighted_sum(window.x + x, window.y + y),
+                    image.get_weight(window.x + x, window.y + y),
+                );
+            }
+        }
+        cropped
+    }
+
+    /// The crop window of a full-size image pasted into the image to composite into, if there is one.
+    fn composite(&self, image: &RenderBuffer) -> RenderBuffer {
+        let Some(composite) = &self.composite else {
+            return image.clone();
+        };
+        let window = self.window;
+        let mut composite = composite.as_ref().clone();
+        for y in window.y..window.y + window.height {
+            for x in window.x..window.x + window.width {
+                composite.set_pixel(x, y, image.get_pixel(x, y));
+            }
+        }
+        composite
+    }
+
+    /// The states of all pixels, row by row over the whole image.
+    fn pixel_states(&self) -> Vec<PixelState> {
+        let mut states =
+            vec![PixelState::default(); (self.image_width * self.image_height) as usize];
+        for (tile, pixels) in self.tiles.iter().zip(self.pixels.iter()) {
+            for (i, pixel) in pixels.lock().unwrap().iter().enumerate() {
+                let x = tile.x + i as u32 % tile.width;
+                let y = tile.y + i as u32 / tile.width;
+                states[(y * self.image_width + x) as usize] = pixel.clone();
+            }
+        }
+        states
+    }
+
+    /// Replaces the states of all pixels by those returned by `pixel_states`.
+    fn set_pixel_states(&self, states: &[PixelState]) {
+        for (tile, pixels) in self.tiles.iter().zip(self.pixels.iter()) {
+            for (i, pixel) in pixels.lock().unwrap().iter_mut().enumerate() {
+                let x = tile.x + i as u32 % tile.width;
+                let y = tile.y + i as u32 / tile.width;
+                *pixel = states[(y * self.image_width + x) as usize].clone();
+            }
+        }
+    }
+
+    /// Saves everything the passes from `next_pass` on depend on: the accumulated samples, the splats, and
+    /// the sample offsets and estimates of all pixels. The random numbers of a pass are derived from the seed.
+    fn write_checkpoint(&self, path: &Path, next_pass: u32) -> std::io::Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut out = OutputStream::new(&temp_path)?;
+
+        out.write(CHECKPOINT_MAGIC)?;
+        out.write_u32_le(self.image_width)?;
+        out.write_u32_le(self.image_height)?;
+        out.write_u64_le(self.film.seed)?;
+        out.write_u32_le(self.pixel_sampler.samples_per_set() as u32)?;
+        for value in [
+            self.traced.x,
+            self.traced.y,
+            self.traced.width,
+            self.traced.height,
+        ] {
+            out.write_u32_le(value)?;
+        }
+        out.write_u32_le(next_pass)?;
+        out.write_u64_le(self.samples_traced.load(Ordering::Relaxed))?;
+
+        let accumulation_buffer = self.accumulation_buffer.lock().unwrap();
+        for y in 0..self.image_height {
+            for x in 0..self.image_width {
+                let sum = accumulation_buffer.get_weighted_sum(x, y);
+                let weight = accumulation_buffer.get_weight(x, y);
+                for value in [sum.r, sum.g, sum.b, sum.a, weight] {
+                    out.write_f64_le(value)?;
+                }
+            }
+        }
+        for sum in self.film.splat_sums() {
+            out.write_u64_le(sum)?;
+        }
+        for pixel in self.pixel_states() {
+            pixel.write_to(&mut out)?;
+        }
+        out.flush()?;
+        drop(out);
+
+        fs::rename(&temp_path, path)
+    }
+
+    /// Restores the state saved by `write_checkpoint` and returns the pass to continue with.
+    fn read_checkpoint(&self, path: &Path) -> std::io::Result<u32> {
+        let mut input = InputStream::new(path)?;
+
+        let mut magic = [0u8; 8];
+        input.read(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a checkpoint"));
+        }
+        let width = input.read_u32_le()?;
+        let height = input.read_u32_le()?;
+        let seed = input.read_u64_le()?;
+        let samples_per_set = input.read_u32_le()?;
+        let traced = Tile {
+            x: input.read_u32_le()?,
+            y: input.read_u32_le()?,
+            width: input.read_u32_le()?,
+            height: input.read_u32_le()?,
+        };
+        if width != self.image_width
+            || height != self.image_height
+            || seed != self.film.seed
+            || samples_per_set as usize != self.pixel_sampler.samples_per_set()
+            || traced != self.traced
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Checkpoint does not belong to this render",
+            ));
+        }
+        let next_pass = input.read_u32_le()?;
+        self.samples_traced
+            .store(input.read_u64_le()?, Ordering::Relaxed);
+
+        let mut accumulation_buffer = RenderBuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let sum = Color {
+                    r: input.read_f64_le()?,
+                    g: input.read_f64_le()?,
+                    b: input.read_f64_le()?,
+                    a: input.read_f64_le()?,
                 };
-                let sampled_pixel_pos = pixel_corner + self.pixel_size * sample;
-                let ray = camera.generate_ray(sampled_pixel_pos.x, -sampled_pixel_pos.y);
+                let weight = input.read_f64_le()?;
+                accumulation_buffer.add_weighted_sum(x, y, &sum, weight);
+            }
+        }
+        *self.accumulation_buffer.lock().unwrap() = accumulation_buffer;
+
+        // The splats of camera samples and the unscaled ones.
+        let num_splat_sums = 6 * width as usize * height as usize;
+        let mut splat_sums = Vec::with_capacity(num_splat_sums);
+        for _ in 0..num_splat_sums {
+            splat_sums.push(input.read_u64_le()?);
+        }
+        // Replaces the splats of the preprocessing, which the checkpoint contains already.
+        self.film.set_splat_sums(&splat_sums);
+
+        let num_pixels = width as usize * height as usize;
+        let mut pixels = Vec::with_capacity(num_pixels);
+        for _ in 0..num_pixels {
+            pixels.push(PixelState::read_from(&mut input)?);
+        }
+        self.set_pixel_states(&pixels);
+        Ok(next_pass)
+    }
+
+    /// Adds a sample at the raster position `raster` to all pixels of `tile` within the filter radius.
+    fn add_filtered_sample(&self, tile: &mut FilteredTile, raster: Vector2, color: &Color) {
+        let radius = self.filter.radius();
+        // Pixel centres lie at half-integer raster positions.
+        let first = |t: f64, start: u32| ((t - 0.5 - radius).ceil().max(0.0) as u32).max(start);
+        let last = |t: f64, end: u32| ((t - 0.5 + radius).floor().max(0.0) as u32).min(end - 1);
 
-                color += self.trace_ray(&trace_context, &ray, &objects, 0);
+        let end_column = tile.first_column + tile.buffer.width();
+        let end_row = tile.first_row + tile.buffer.height();
+        for py in first(raster.y, tile.first_row)..=last(raster.y, end_row) {
+            for px in first(raster.x, tile.first_column)..=last(raster.x, end_column) {
+                let weight = self
+                    .filter
+                    .evaluate(raster.x - (px as f64 + 0.5), raster.y - (py as f64 + 0.5));
+                if weight != 0.0 {
+                    let (x, y) = (px - tile.first_column, py - tile.first_row);
+                    tile.buffer.add_sample(x, y, color, weight);
+                }
             }
+        }
+    }
 
-            color /= self.pixel_sampler.samples[pixel_set_index].len() as f64;
+    /// Adds the filtered samples of the tile `index` and of all following tiles that are already traced to
+    /// the accumulated samples.
+    fn add_tile(&self, index: usize, tile: FilteredTile) {
+        self.samples_traced
+            .fetch_add(tile.samples, Ordering::Relaxed);
+        let mut pending = self.pending_tiles.lock().unwrap();
+        pending.tiles.insert(index, tile);
 
-            out.push(color);
+        let mut accumulation_buffer = self.accumulation_buffer.lock().unwrap();
+        loop {
+            let next_tile = pending.next_tile;
+            match pending.tiles.remove(&next_tile) {
+                Some(tile) => tile.add_to(&mut accumulation_buffer),
+                None => break,
+            }
+            pending.next_tile += 1;
         }
+    }
 
-        self.image_buffer.lock().unwrap().set_pixel_line(y, &out);
+    /// Adds the filtered samples of all tiles that are still waiting for a tile before them.
+    fn add_pending_tiles(&self) {
+        let mut pending = self.pending_tiles.lock().unwrap();
+        let mut accumulation_buffer = self.accumulation_buffer.lock().unwrap();
+        for tile in std::mem::take(&mut pending.tiles).into_values() {
+            tile.add_to(&mut accumulation_buffer);
+        }
     }
 
-    fn trace_ray(
+    /// Traces the camera ray through the raster position `raster` and returns its RGB radiance.
+    fn trace_sample(
         &self,
-        trace_context: &TraceContext,
-        ray: &Ray3,
-        objects: &Vec<Arc<dyn Hitable>>,
-        depth: u32,
+        camera: &Arc<dyn Camera>,
+        raster: Vector2,
+        pixel_context: &TraceContext,
+        rng: &mut StdRng,
     ) -> Color {
-        let have_hit = objects
-            .into_iter()
-            .filter_map(|o| (*o).intersect(&ray))
-            .min_by(|a: &Intersection, b: &Intersection| a.t.partial_cmp(&b.t).unwrap());
-
-        if let Some(intersection) = have_hit {
-            let mut scattered = Ray3::default();
-            let mut attenuation = Color::black();
-            if (*intersection.material).scatter(
-                trace_context,
-                ray,
-                &intersection,
-                &mut attenuation,
-                &mut scattered,
-            ) && depth < self.max_trace_depth
-            {
-                return self.trace_ray(trace_context, &scattered, objects, depth + 1) * attenuation;
-            } else {
-                return attenuation;
+        let image_dim = Vector2::new(self.image_width as f64, self.image_height as f64);
+        let half = Vector2::new(0.5, 0.5);
+        let sampled_pixel_pos = self.pixel_size * (raster - 0.5 * image_dim - half);
+        let ray = camera.generate_ray(sampled_pixel_pos.x, -sampled_pixel_pos.y);
+
+        let mut trace_context = pixel_context.next_dimension();
+        stats::count_camera_ray();
+        let spectral = match trace_context.wavelengths {
+            Some(_) => self.integrator.spectral_radiance(
+                &self.scene,
+                &self.film,
+                &mut trace_context,
+                &ray,
+                rng,
+            ),
+            None => None,
+        };
+        let radiance = match (spectral, &trace_context.wavelengths) {
+            (Some(radiance), Some(wavelengths)) => wavelengths.to_rgb(&radiance),
+            _ => {
+                // Integrators without spectral paths render in RGB.
+                trace_context.wavelengths = None;
+                self.integrator
+                    .radiance(&self.scene, &self.film, &trace_context, &ray, rng)
             }
-        } else {
-            self.ambient_color
+        };
+        stats::end_camera_sample();
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bdpt::BidirectionalPathTracer;
+    use crate::camera::PinholeCamera;
+    use crate::crop::CropWindow;
+    use crate::filter::{BoxFilter, MitchellFilter};
+    use crate::integrator::PathTracer;
+    use crate::light::SphereLight;
+    use crate::material::Lambertian;
+    use crate::math::Vector3;
+    use crate::mlt::{MetropolisConfig, MetropolisIntegrator};
+    use crate::sampler::HemiSphereSampler;
+    use crate::shapes::{Plane, Sphere};
+    use crate::tile::TileOrder;
+
+    pub(super) fn config(seed: u64, num_render_threads: u32) -> RendererConfig {
+        RendererConfig {
+            image_width: 16,
+            image_height: 12,
+            pixel_size: 0.1,
+            pixel_sampler: UnitSquareSampler::jittered_sampler(2),
+            adaptive_sampling: None,
+            filter: Arc::new(BoxFilter::default()),
+            passes: 1,
+            budget: None,
+            max_trace_depth: 4,
+            ambient_color: Color::black(),
+            atmosphere: None,
+            lights: vec![Arc::new(SphereLight {
+                center: Vector3::new(1.0, 2.0, 0.0),
+                radius: 0.3,
+                radiance: Color::white() * 20.0,
+            })],
+            integrator: Arc::new(BidirectionalPathTracer::new()),
+            spectral: false,
+            seed,
+            statistics: false,
+            checkpoint: None,
+            progress: None,
+            crop: None,
+            tiles: TileConfig::default(),
+            num_render_threads: Some(num_render_threads),
+        }
+    }
+
+    pub(super) fn render(config: &RendererConfig) -> Renderer {
+        render_with(config, |_, _| true)
+    }
+
+    fn render_with<F>(config: &RendererConfig, on_pass: F) -> Renderer
+    where
+        F: FnMut(u32, &RenderBuffer) -> bool,
+    {
+        let mut renderer = Renderer::new(config);
+        let (camera, objects) = scene();
+        renderer
+            .render_progressive(&camera, &objects, on_pass)
+            .unwrap();
+        renderer
+    }
+
+    pub(super) fn scene() -> (Arc<dyn Camera>, Arc<Vec<Arc<dyn Hitable>>>) {
+        let camera: Arc<dyn Camera> = Arc::new(PinholeCamera::new(
+            &Vector3::new(0.0, 1.0, 4.0),
+            &Vector3::zero(),
+            &Vector3::new(0.0, 1.0, 0.0),
+            2.0,
+        ));
+        let material = Arc::new(Lambertian::new(
+            &HemiSphereSampler::jittered_sampler(4, 1.0),
+            &(Color::white() * 0.5),
+        ));
+        let objects: Arc<Vec<Arc<dyn Hitable>>> = Arc::new(vec![
+            Arc::new(Sphere {
+                center: Vector3::new(0.0, 0.5, 0.0),
+                radius: 0.5,
+                material: material.clone(),
+            }),
+            Arc::new(Plane {
+                point: Vector3::zero(),
+                normal: Vector3::new(0.0, 1.0, 0.0),
+                material,
+            }),
+        ]);
+        (camera, objects)
+    }
+
+    pub(super) fn pixels(renderer: &Renderer) -> Vec<[u64; 3]> {
+        pixels_of(&renderer.image_buffer.lock().unwrap())
+    }
+
+    fn pixels_of(image: &RenderBuffer) -> Vec<[u64; 3]> {
+        let mut pixels = Vec::new();
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let c = image.get_pixel(x, y);
+                pixels.push([c.r.to_bits(), c.g.to_bits(), c.b.to_bits()]);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn renders_with_the_same_seed_are_identical() {
+        let image = pixels(&render(&config(7, 1)));
+        assert_eq!(image, pixels(&render(&config(7, 4))));
+        assert_ne!(image, pixels(&render(&config(8, 4))));
+    }
+
+    #[test]
+    fn tile_layouts_do_not_change_the_image() {
+        let image = pixels(&render(&config(7, 4)));
+        for (size, order) in [
+            (5, TileOrder::Scanline),
+            (3, TileOrder::Spiral),
+            (64, TileOrder::Hilbert),
+        ] {
+            let tiled = RendererConfig {
+                tiles: TileConfig { size, order },
+                ..config(7, 4)
+            };
+            assert_eq!(image, pixels(&render(&tiled)), "{} {:?}", size, order);
         }
     }
+
+    #[test]
+    fn wide_filters_blend_neighbouring_tiles_reproducibly() {
+        let mitchell = |num_render_threads| RendererConfig {
+            filter: Arc::new(MitchellFilter::default()),
+            tiles: TileConfig {
+                size: 4,
+                order: TileOrder::Hilbert,
+            },
+            ..config(7, num_render_threads)
+        };
+        let renderer = render(&mitchell(1));
+        let image = pixels(&renderer);
+        assert_eq!(image, pixels(&render(&mitchell(4))));
+        assert_ne!(image, pixels(&render(&config(7, 1))));
+
+        // Every pixel, including those in the corners, gathers a positive weight from the samples around it.
+        let buffer = renderer.image_buffer.lock().unwrap();
+        for y in 0..buffer.height() {
+            for x in 0..buffer.width() {
+                assert!(buffer.get_weight(x, y) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn crop_windows_keep_the_pixels_of_the_full_render() {
+        let cropped = |seed, output: Option<CropOutput>| RendererConfig {
+            integrator: Arc::new(PathTracer::new()),
+            filter: Arc::new(MitchellFilter::default()),
+            crop: output.map(|output| CropConfig {
+                window: CropWindow::Pixels {
+                    x: 5,
+                    y: 3,
+                    width: 6,
+                    height: 4,
+                },
+                output,
+            }),
+            ..config(seed, 4)
+        };
+        let full = render(&cropped(7, None));
+        let full_image = full.image_buffer.lock().unwrap().clone();
+        let in_window = |x, y| (5..11).contains(&x) && (3..7).contains(&y);
+        let assert_same = |a: Color, b: Color| {
+            for (a, b) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+                assert_close!(a, b);
+            }
+        };
+
+        // The samples around the window reach its pixels through the filter as in the full render.
+        let window = render(&cropped(7, Some(CropOutput::Cropped)));
+        let window_image = window.image_buffer.lock().unwrap().clone();
+        assert_eq!((6, 4), (window_image.width(), window_image.height()));
+        for y in 0..4 {
+            for x in 0..6 {
+                assert_same(
+                    full_image.get_pixel(x + 5, y + 3),
+                    window_image.get_pixel(x, y),
+                );
+            }
+        }
+
+        let path = std::env::temp_dir().join("ard_crop_windows_composite.bmp");
+        full.write_to_file(&path).unwrap();
+        let other_seed = render(&cropped(8, None));
+        let other_image = other_seed.image_buffer.lock().unwrap().clone();
+        let composite = render(&cropped(8, Some(CropOutput::Composite(path))));
+        let composite_image = composite.image_buffer.lock().unwrap().clone();
+        // Bitmaps keep no alpha.
+        let rgb = |color: Color| color.to_rgba32() & 0xffffff;
+        for y in 0..12 {
+            for x in 0..16 {
+                let pixel = composite_image.get_pixel(x, y);
+                if in_window(x, y) {
+                    assert_same(other_image.get_pixel(x, y), pixel);
+                } else {
+                    assert_eq!(rgb(full_image.get_pixel(x, y)), rgb(pixel));
+                }
+            }
+        }
+
+        // Light paths of bidirectional path tracing splat all over the film, whatever window is traced.
+        let bidirectional = |output: Option<CropOutput>| {
+            let renderer = render(&RendererConfig {
+                integrator: Arc::new(BidirectionalPathTracer::new()),
+                passes: 64,
+                ..cropped(7, output)
+            });
+            let image = renderer.image_buffer.lock().unwrap().clone();
+            let (x0, y0) = if image.width() == 6 { (0, 0) } else { (5, 3) };
+            let mut mean = 0.0;
+            for y in 0..4 {
+                for x in 0..6 {
+                    mean += image.get_pixel(x + x0, y + y0).g / 24.0;
+                }
+            }
+            mean
+        };
+        let full_mean = bidirectional(None);
+        let window_mean = bidirectional(Some(CropOutput::Cropped));
+        assert!(
+            (window_mean - full_mean).abs() < 0.02 * full_mean,
+            "{} vs {}",
+            window_mean,
+            full_mean
+        );
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_samples_on_noisy_pixels() {
+        let renderer = render(&RendererConfig {
+            adaptive_sampling: Some(AdaptiveSamplingConfig {
+                target_error: 0.05,
+                max_samples_per_pixel: 64,
+            }),
+            ..config(7, 4)
+        });
+        let counts = renderer.sample_counts().unwrap();
+        // The top row only sees the black sky and keeps the first set of 4 samples, while indirect light
+        // around the sphere needs all of them.
+        for x in 0..counts.width() {
+            assert_eq!(4.0 / 64.0, counts.get_pixel(x, 0).g);
+        }
+        assert!((0..counts.width()).any(|x| counts.get_pixel(x, 3).g == 1.0));
+        assert!(render(&config(7, 4)).sample_counts().is_none());
+    }
+
+    #[test]
+    fn budgets_add_passes_until_they_are_spent() {
+        let (camera, objects) = scene();
+        let budgeted = |budget, adaptive_sampling| {
+            let mut renderer = Renderer::new(&RendererConfig {
+                adaptive_sampling,
+                budget: Some(budget),
+                ..config(7, 4)
+            });
+            let summary = renderer.render(&camera, &objects).unwrap();
+            (summary, pixels(&renderer))
+        };
+
+        // Noisy renders take more passes, each adding a set of 4 samples, to the same image as fixed passes.
+        let (summary, image) = budgeted(
+            RenderBudget {
+                time: None,
+                target_error: Some(0.05),
+            },
+            None,
+        );
+        assert!(summary.passes > 1);
+        assert!(summary.relative_error <= 0.05);
+        assert_eq!(4.0 * summary.passes as f64, summary.samples_per_pixel);
+        let fixed = RendererConfig {
+            passes: summary.passes,
+            ..config(7, 4)
+        };
+        assert_eq!(image, pixels(&render(&fixed)));
+
+        let (summary, _) = budgeted(
+            RenderBudget {
+                time: Some(Duration::ZERO),
+                target_error: None,
+            },
+            None,
+        );
+        assert_eq!((1, 4.0), (summary.passes, summary.samples_per_pixel));
+
+        // Without limits, adaptive renders end once every pixel has converged or reached its maximum.
+        let (summary, _) = budgeted(
+            RenderBudget::default(),
+            Some(AdaptiveSamplingConfig {
+                target_error: 0.05,
+                max_samples_per_pixel: 16,
+            }),
+        );
+        assert_eq!(5, summary.passes);
+        assert!(summary.samples_per_pixel > 4.0 && summary.samples_per_pixel < 16.0);
+    }
+
+    #[test]
+    fn error_budgets_do_not_end_renders_that_only_splat() {
+        // The black camera samples of Metropolis light transport have no error, which says nothing about
+        // the image. The render goes on until it is stopped after the third pass.
+        let metropolis = RendererConfig {
+            integrator: Arc::new(MetropolisIntegrator::new(&MetropolisConfig {
+                bootstrap_samples: 1000,
+                chains: 8,
+                mutations_per_pixel: 4,
+                ..MetropolisConfig::default()
+            })),
+            budget: Some(RenderBudget {
+                time: None,
+                target_error: Some(0.05),
+            }),
+            ..config(7, 4)
+        };
+        let mut passes = 0;
+        render_with(&metropolis, |pass, _| {
+            passes += 1;
+            pass < 2
+        });
+        assert_eq!(3, passes);
+    }
+
+    #[test]
+    fn progressive_passes_refine_the_same_render() {
+        let progressive = RendererConfig {
+            passes: 4,
+            ..config(7, 4)
+        };
+        let mut passes = Vec::new();
+        let renderer = render_with(&progressive, |pass, image| {
+            passes.push((pass, image.clone()));
+            true
+        });
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            passes.iter().map(|p| p.0).collect::<Vec<_>>()
+        );
+        assert_eq!(pixels(&renderer), pixels_of(&passes[3].1));
+        assert_ne!(pixels_of(&passes[0].1), pixels_of(&passes[3].1));
+
+        // A render stopped after the second pass keeps the image of that pass.
+        let stopped = render_with(&progressive, |pass, _| pass < 1);
+        assert_eq!(pixels(&stopped), pixels_of(&passes[1].1));
+    }
+
+    #[test]
+    fn metropolis_snapshots_do_not_depend_on_the_passes_traced() {
+        let metropolis = |num_render_threads| RendererConfig {
+            integrator: Arc::new(MetropolisIntegrator::new(&MetropolisConfig {
+                bootstrap_samples: 1000,
+                chains: 8,
+                mutations_per_pixel: 4,
+                ..MetropolisConfig::default()
+            })),
+            passes: 4,
+            ..config(7, num_render_threads)
+        };
+        let mut passes = Vec::new();
+        let renderer = render_with(&metropolis(1), |_, image| {
+            passes.push(pixels_of(image));
+            true
+        });
+        // The camera samples carry no light, so every snapshot shows just the chains.
+        assert_eq!(4, passes.len());
+        assert!(passes.iter().all(|image| *image == passes[0]));
+        assert!(passes[0].iter().any(|pixel| f64::from_bits(pixel[1]) > 0.0));
+        assert_eq!(pixels(&renderer), pixels(&render(&metropolis(4))));
+    }
+
+    #[test]
+    fn resumed_renders_match_uninterrupted_ones() {
+        let path = std::env::temp_dir().join(format!("ard-{}.checkpoint", std::process::id()));
+        let adaptive = || RendererConfig {
+            adaptive_sampling: Some(AdaptiveSamplingConfig {
+                target_error: 0.05,
+                max_samples_per_pixel: 16,
+            }),
+            passes: 3,
+            ..config(7, 4)
+        };
+        let checkpointed = RendererConfig {
+            checkpoint: Some(CheckpointConfig {
+                path: path.clone(),
+                interval: Duration::from_secs(3600),
+            }),
+            ..adaptive()
+        };
+
+        // Stopping after the first pass writes a checkpoint, from which another renderer continues.
+        render_with(&checkpointed, |_, _| false);
+        let mut resumed = Renderer::new(&checkpointed);
+        let (camera, objects) = scene();
+        resumed.resume(&camera, &objects, &path).unwrap();
+
+        let uninterrupted = render(&adaptive());
+        assert_eq!(pixels(&uninterrupted), pixels(&resumed));
+        assert_eq!(
+            pixels_of(&uninterrupted.sample_counts().unwrap()),
+            pixels_of(&resumed.sample_counts().unwrap())
+        );
+
+        let mismatched = Renderer::new(&config(8, 4)).resume(&camera, &objects, &path);
+        assert!(mismatched.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn progress_is_reported_after_every_tile() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let observer = Arc::clone(&reports);
+        render(&RendererConfig {
+            passes: 2,
+            progress: Some(Arc::new(move |progress: &Progress| {
+                observer.lock().unwrap().push(progress.clone())
+            })),
+            tiles: TileConfig {
+                size: 4,
+                order: TileOrder::Spiral,
+            },
+            ..config(7, 4)
+        });
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(2 * 12, reports.len());
+        let last = reports.iter().max_by_key(|p| p.tiles_done).unwrap();
+        assert_eq!((24, 24, 1), (last.tiles_done, last.tiles_total, last.pass));
+        assert_eq!(Duration::ZERO, last.eta);
+        assert!(last.camera_rays > 0);
+    }
+
+    #[test]
+    fn cancelled_renders_keep_the_tiles_traced_so_far() {
+        // The observer cancels the render after the first tile. Path tracing does not splat, so only the
+        // pixels of that tile have samples.
+        let token = Arc::new(Mutex::new(None::<CancellationToken>));
+        let observer = Arc::clone(&token);
+        let mut renderer = Renderer::new(&RendererConfig {
+            integrator: Arc::new(PathTracer::new()),
+            passes: 2,
+            progress: Some(Arc::new(move |_: &Progress| {
+                observer.lock().unwrap().as_ref().unwrap().cancel()
+            })),
+            tiles: TileConfig {
+                size: 4,
+                order: TileOrder::Spiral,
+            },
+            ..config(7, 1)
+        });
+        *token.lock().unwrap() = Some(renderer.cancellation_token());
+
+        let (camera, objects) = scene();
+        let mut passes = 0;
+        renderer
+            .render_progressive(&camera, &objects, |_, _| {
+                passes += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(0, passes);
+
+        let image = renderer.image_buffer.lock().unwrap();
+        let mut traced = 0;
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                assert!(image.get_pixel(x, y).r.is_finite());
+                if image.get_weight(x, y) > 0.0 {
+                    traced += 1;
+                }
+            }
+        }
+        assert_eq!(4 * 4, traced);
+    }
+
+    #[test]
+    fn statistics_count_the_work_of_all_threads() {
+        let counted = |num_render_threads| {
+            render(&RendererConfig {
+                integrator: Arc::new(PathTracer::new()),
+                statistics: true,
+                ..config(7, num_render_threads)
+            })
+        };
+        let renderer = counted(1);
+        let stats = renderer.statistics().unwrap();
+        assert_eq!(16 * 12 * 4, stats.primary_rays);
+        assert_eq!(4.0, stats.samples_per_pixel);
+        assert!(stats.secondary_rays > 0 && stats.shadow_rays > 0);
+        assert!(stats.average_path_length() > 1.0);
+        assert!(stats.intersection_tests["sphere"] > stats.primary_rays);
+        assert!(stats.intersection_tests["plane"] > stats.primary_rays);
+
+        let merged = counted(4).statistics().unwrap();
+        assert_eq!(stats.secondary_rays, merged.secondary_rays);
+        assert_eq!(stats.shadow_rays, merged.shadow_rays);
+        assert_eq!(stats.intersection_tests, merged.intersection_tests);
+        assert_eq!(stats.path_rays, merged.path_rays);
+
+        let uncounted = render(&RendererConfig {
+            integrator: Arc::new(PathTracer::new()),
+            ..config(7, 1)
+        });
+        assert!(uncounted.statistics().is_none());
+        assert_eq!(pixels(&uncounted), pixels(&renderer));
+    }
 }
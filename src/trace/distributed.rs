@@ -0,0 +1,552 @@
+//! Rendering on several machines. A coordinator hands out the tiles of every pass to worker processes
+//! connected over TCP, which trace them and send back their filtered samples, splats and pixel states, and
+//! with statistics their counts. The scene travels to the workers when they connect, so they only need to
+//! know the coordinator.
+//!
+//! All messages are encoded with `serialize`. A worker opens with `WORKER_MAGIC` and its number of threads,
+//! and receives the scene. Then the coordinator keeps every thread busy with tile requests, each answered
+//! by the traced tile, until it sends `DONE`.
+
+use std::collections::VecDeque;
+use std::io::Result;
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{
+    FilteredTile, PixelState, ProgressCounter, RenderSummary, Renderer, RendererConfig, Tracer,
+};
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::crop::{CropConfig, CropOutput, CropWindow};
+use crate::io::{InputStream, OutputStream};
+use crate::sampler::UnitSquareSampler;
+use crate::serialize::{invalid_data, Deserialize, Serialize};
+use crate::shapes::Hitable;
+use crate::stats::{self, Helpers, RenderStats};
+use crate::tile::TileConfig;
+use crate::RenderBuffer;
+
+/// Identifies workers and the version of the protocol.
+const WORKER_MAGIC: &[u8; 8] = b"ARDWORK1";
+
+/// Requests from the coordinator to a worker.
+const TILE_REQUEST: u8 = 0;
+const DONE: u8 = 1;
+
+/// How often waiting threads check whether the render was cancelled, or whether a worker connected.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Time a worker has to introduce itself after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The pass, index and pixel states of a tile to trace.
+type TileRequest = (u32, usize, Vec<PixelState>);
+
+/// The config of the renderer, the camera and the objects sent to a worker.
+type Scene = (RendererConfig, Arc<dyn Camera>, Arc<Vec<Arc<dyn Hitable>>>);
+
+impl Renderer {
+    /// Renders like `render_progressive`, but has the tiles traced by the worker processes that connect to
+    /// `listener`, see `run_worker`. Workers may join and leave at any time, and the tiles of a worker that
+    /// disconnects go to the others, so passes wait for a worker to trace them. The image is identical to
+    /// that of a local render. Fails if the scene cannot be serialized, or like `render_progressive`.
+    pub fn render_distributed<F>(
+        &mut self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+        listener: &TcpListener,
+        on_pass: F,
+    ) -> Result<RenderSummary>
+    where
+        F: FnMut(u32, &RenderBuffer) -> bool,
+    {
+        // Finds shapes, lights and the like that cannot be serialized before the workers do.
+        self.write_scene(
+            camera,
+            objects,
+            &mut OutputStream::from_writer(std::io::sink()),
+        )?;
+
+        let started = Instant::now();
+        let tracer = self.start(camera, objects)?;
+        let coordinator = Coordinator {
+            renderer: self,
+            tracer: &tracer,
+            camera,
+            objects,
+            helpers: Helpers::new(),
+            dispatch: Mutex::new(Dispatch::default()),
+            changed: Condvar::new(),
+        };
+        listener.set_nonblocking(true)?;
+        let summary = thread::scope(|scope| {
+            let coordinator = &coordinator;
+            scope.spawn(move || coordinator.accept_workers(scope, listener));
+            let trace_pass = |pass, progress: &_| coordinator.trace_pass(pass, progress);
+            let summary = self.trace_passes(&tracer, 0, started, trace_pass, on_pass);
+            coordinator.dispatch.lock().unwrap().finished = true;
+            coordinator.changed.notify_all();
+            summary
+        });
+        listener.set_nonblocking(false)?;
+        summary
+    }
+
+    /// Writes what the workers need to set up the same tracer as the coordinator.
+    fn write_scene(
+        &self,
+        camera: &Arc<dyn Camera>,
+        objects: &Arc<Vec<Arc<dyn Hitable>>>,
+        out: &mut OutputStream,
+    ) -> Result<()> {
+        self.image_width.write_to(out)?;
+        self.image_height.write_to(out)?;
+        self.pixel_size.write_to(out)?;
+        self.pixel_sampler.write_to(out)?;
+        self.adaptive_sampling.write_to(out)?;
+        self.filter.write_to(out)?;
+        self.passes.write_to(out)?;
+        self.budget.write_to(out)?;
+        self.max_trace_depth.write_to(out)?;
+        self.ambient_color.write_to(out)?;
+        self.atmosphere.write_to(out)?;
+        self.lights.write_to(out)?;
+        self.integrator.write_to(out)?;
+        self.spectral.write_to(out)?;
+        self.seed.write_to(out)?;
+        self.statistics.is_some().write_to(out)?;
+        self.crop.as_ref().map(|crop| crop.window).write_to(out)?;
+        self.tiles.write_to(out)?;
+        camera.write_to(out)?;
+        objects.as_ref().write_to(out)
+    }
+}
+
+/// Reads the scene written by `Renderer::write_scene` into the config of a worker renderer.
+fn read_scene(input: &mut InputStream, num_threads: u32) -> Result<Scene> {
+    let config = RendererConfig {
+        image_width: u32::read_from(input)?,
+        image_height: u32::read_from(input)?,
+        pixel_size: f64::read_from(input)?,
+        pixel_sampler: UnitSquareSampler::read_from(input)?,
+        adaptive_sampling: Deserialize::read_from(input)?,
+        filter: Deserialize::read_from(input)?,
+        passes: u32::read_from(input)?,
+        budget: Deserialize::read_from(input)?,
+        max_trace_depth: u32::read_from(input)?,
+        ambient_color: Color::read_from(input)?,
+        atmosphere: Deserialize::read_from(input)?,
+        lights: Deserialize::read_from(input)?,
+        integrator: Deserialize::read_from(input)?,
+        spectral: bool::read_from(input)?,
+        seed: u64::read_from(input)?,
+        statistics: bool::read_from(input)?,
+        checkpoint: None,
+        progress: None,
+        // Workers leave the compositing to the coordinator.
+        crop: Option::<CropWindow>::read_from(input)?.map(|window| CropConfig {
+            window,
+            output: CropOutput::Cropped,
+        }),
+        tiles: TileConfig::read_from(input)?,
+        num_render_threads: Some(num_threads),
+    };
+    if config.image_width == 0 || config.image_height == 0 || config.passes == 0 {
+        return Err(invalid_data("Invalid render"));
+    }
+    let camera = Deserialize::read_from(input)?;
+    let objects = Vec::read_from(input)?;
+    Ok((config, camera, Arc::new(objects)))
+}
+
+/// The tiles of the current pass and who is tracing them.
+#[derive(Default)]
+struct Dispatch {
+    pass: u32,
+    /// Tiles waiting for a worker.
+    queue: VecDeque<usize>,
+    /// Number of tiles that workers are tracing.
+    in_flight: usize,
+    progress: Option<Arc<ProgressCounter>>,
+    /// Set once the render is over, which sends the workers home.
+    finished: bool,
+}
+
+/// The coordinator of a distributed render. Every connected worker is served by a thread of its own.
+struct Coordinator<'a> {
+    renderer: &'a Renderer,
+    tracer: &'a Tracer,
+    camera: &'a Arc<dyn Camera>,
+    objects: &'a Arc<Vec<Arc<dyn Hitable>>>,
+    /// Collects the counts of the workers.
+    helpers: Helpers,
+    dispatch: Mutex<Dispatch>,
+    /// Signals changes of the dispatch to the threads waiting for tiles or for the end of a pass.
+    changed: Condvar,
+}
+
+impl<'a> Coordinator<'a> {
+    /// Hands out the tiles of `pass` and returns once all of them are traced, or once the tiles that workers
+    /// are tracing are done after the render was cancelled.
+    fn trace_pass(&self, pass: u32, progress: &Option<Arc<ProgressCounter>>) {
+        let mut dispatch = self.dispatch.lock().unwrap();
+        dispatch.pass = pass;
+        dispatch.queue = (0..self.tracer.tiles.len()).collect();
+        dispatch.progress = progress.clone();
+        self.changed.notify_all();
+        while !dispatch.queue.is_empty() || dispatch.in_flight > 0 {
+            if self.tracer.cancellation.is_cancelled() {
+                dispatch.queue.clear();
+            }
+            dispatch = self
+                .changed
+                .wait_timeout(dispatch, POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        self.helpers.finish();
+    }
+
+    /// Serves the workers connecting to `listener` until the render is finished.
+    fn accept_workers<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        listener: &TcpListener,
+    ) {
+        while !self.dispatch.lock().unwrap().finished {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    scope.spawn(move || self.serve_worker(stream));
+                }
+                // Nobody is connecting, or a connection failed before it was accepted.
+                Err(_) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Sends tiles to a worker until the render is finished. The tiles of a worker that fails or disconnects
+    /// go back to the queue.
+    fn serve_worker(&self, stream: TcpStream) {
+        let mut in_flight = Vec::new();
+        let _ = self.serve(stream, &mut in_flight);
+
+        let mut dispatch = self.dispatch.lock().unwrap();
+        dispatch.in_flight -= in_flight.len();
+        for index in in_flight {
+            dispatch.queue.push_front(index);
+        }
+        self.changed.notify_all();
+    }
+
+    fn serve(&self, stream: TcpStream, in_flight: &mut Vec<usize>) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut input = InputStream::from_reader(stream.try_clone()?);
+        let mut out = OutputStream::from_writer(stream.try_clone()?);
+
+        let mut magic = [0u8; 8];
+        input.read(&mut magic)?;
+        if &magic != WORKER_MAGIC {
+            return Err(invalid_data("Not a render worker"));
+        }
+        let num_threads = u32::read_from(&mut input)?.max(1) as usize;
+        stream.set_read_timeout(None)?;
+        self.renderer
+            .write_scene(self.camera, self.objects, &mut out)?;
+        out.flush()?;
+
+        loop {
+            let (pass, requests) = {
+                let mut dispatch = self.dispatch.lock().unwrap();
+                while in_flight.is_empty() && dispatch.queue.is_empty() && !dispatch.finished {
+                    dispatch = self.changed.wait(dispatch).unwrap();
+                }
+                if in_flight.is_empty() && dispatch.finished {
+                    break;
+                }
+                // Keeps every thread of the worker busy.
+                let count = num_threads
+                    .saturating_sub(in_flight.len())
+                    .min(dispatch.queue.len());
+                let requests: Vec<usize> = dispatch.queue.drain(..count).collect();
+                dispatch.in_flight += requests.len();
+                in_flight.extend(&requests);
+                (dispatch.pass, requests)
+            };
+            for index in requests {
+                out.write(&[TILE_REQUEST])?;
+                pass.write_to(&mut out)?;
+                index.write_to(&mut out)?;
+                self.tracer.pixels[index]
+                    .lock()
+                    .unwrap()
+                    .write_to(&mut out)?;
+            }
+            out.flush()?;
+            self.receive_tile(&mut input, in_flight)?;
+        }
+
+        out.write(&[DONE])?;
+        out.flush()
+    }
+
+    /// Reads a traced tile and adds it to the render.
+    fn receive_tile(&self, input: &mut InputStream, in_flight: &mut Vec<usize>) -> Result<()> {
+        let index = usize::read_from(input)?;
+        let pixels = Vec::<PixelState>::read_from(input)?;
+        let tile = FilteredTile::read_from(input)?;
+        let splats = Vec::<(u32, u64)>::read_from(input)?;
+        let counts = Option::<RenderStats>::read_from(input)?;
+
+        let tracer = self.tracer;
+        let position = in_flight.iter().position(|&i| i == index);
+        let num_splat_sums = 3 * tracer.image_width as usize * tracer.image_height as usize;
+        if position.is_none()
+            || pixels.len() != tracer.pixels[index].lock().unwrap().len()
+            || tile.first_column + tile.buffer.width() > tracer.image_width
+            || tile.first_row + tile.buffer.height() > tracer.image_height
+            || splats.iter().any(|&(i, _)| i as usize >= num_splat_sums)
+        {
+            return Err(invalid_data("Tile does not belong to this render"));
+        }
+        in_flight.swap_remove(position.unwrap());
+
+        *tracer.pixels[index].lock().unwrap() = pixels;
+        for (i, sum) in splats {
+            tracer.film.add_splat_sum(i, sum);
+        }
+        tracer.add_tile(index, tile);
+        if let Some(counts) = &counts {
+            self.helpers.add(counts);
+        }
+
+        let mut dispatch = self.dispatch.lock().unwrap();
+        dispatch.in_flight -= 1;
+        let (pass, progress) = (dispatch.pass, dispatch.progress.clone());
+        self.changed.notify_all();
+        drop(dispatch);
+        if let Some(progress) = progress {
+            progress.report(pass, tracer.samples_traced.load(Ordering::Relaxed));
+        }
+        Ok(())
+    }
+}
+
+/// Connects to the coordinator of a distributed render at `address` and traces the tiles it sends with
+/// `num_threads` threads, or one per CPU, until the render is finished. The worker preprocesses the scene
+/// itself, e.g. to trace the photons of a photon mapper, unless the integrator only splats. Fails if the
+/// connection fails.
+pub fn run_worker<A: ToSocketAddrs>(address: A, num_threads: Option<u32>) -> Result<()> {
+    let num_threads = num_threads.unwrap_or_else(|| num_cpus::get() as u32).max(1);
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    let mut input = InputStream::from_reader(stream.try_clone()?);
+    let mut out = OutputStream::from_writer(stream.try_clone()?);
+    out.write(WORKER_MAGIC)?;
+    num_threads.write_to(&mut out)?;
+    out.flush()?;
+
+    let (config, camera, objects) = read_scene(&mut input, num_threads)?;
+    let mut renderer = Renderer::new(&config);
+    // Splats of the preprocessing, e.g. the Markov chains of Metropolis light transport, reach the image
+    // through the coordinator only.
+    let tracer = if renderer.integrator.splats_only() {
+        renderer.set_up(&camera, &objects)?
+    } else {
+        renderer.start(&camera, &objects)?
+    };
+    // The coordinator counts the preprocessing itself.
+    let counting = stats::stop_counting().is_some();
+
+    let out = Mutex::new(out);
+    let (sender, receiver) = mpsc::channel();
+    let receiver = Mutex::new(receiver);
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..num_threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let result = trace_tiles(&tracer, &camera, &receiver, &out, counting);
+                    if result.is_err() {
+                        // Ends the requests of the coordinator, which gives the tiles to other workers.
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    result
+                })
+            })
+            .collect();
+        let mut result = read_requests(&mut input, &tracer, sender);
+        for thread in threads {
+            result = result.and(thread.join().unwrap());
+        }
+        result
+    })
+}
+
+/// Passes the tile requests of the coordinator to the threads of the worker.
+fn read_requests(
+    input: &mut InputStream,
+    tracer: &Tracer,
+    requests: Sender<TileRequest>,
+) -> Result<()> {
+    loop {
+        match input.read_u8()? {
+            TILE_REQUEST => {
+                let pass = u32::read_from(input)?;
+                let index = usize::read_from(input)?;
+                let pixels = Vec::<PixelState>::read_from(input)?;
+                if index >= tracer.tiles.len()
+                    || pixels.len() != tracer.pixels[index].lock().unwrap().len()
+                {
+                    return Err(invalid_data("Tile does not belong to this render"));
+                }
+                // Threads only stop receiving after failing, which ends the connection as well.
+                let _ = requests.send((pass, index, pixels));
+            }
+            DONE => return Ok(()),
+            _ => return Err(invalid_data("Unknown request")),
+        }
+    }
+}
+
+/// Traces requested tiles and sends them back until there are no more requests.
+fn trace_tiles(
+    tracer: &Tracer,
+    camera: &Arc<dyn Camera>,
+    requests: &Mutex<Receiver<TileRequest>>,
+    out: &Mutex<OutputStream>,
+    counting: bool,
+) -> Result<()> {
+    stats::set_counting(counting);
+    // Every thread splats onto a film of its own, so that the splats of a tile can be sent along with it.
+    let tracer = Tracer {
+        film: Arc::new(tracer.film.cleared()),
+        ..tracer.clone()
+    };
+    loop {
+        let request = requests.lock().unwrap().recv();
+        let Ok((pass, index, pixels)) = request else {
+            return Ok(());
+        };
+        *tracer.pixels[index].lock().unwrap() = pixels;
+        let tile = tracer.trace_tile(camera, index, pass);
+        let splats = tracer.film.take_splats();
+        let counts = stats::take_counts();
+
+        let mut out = out.lock().unwrap();
+        index.write_to(&mut out)?;
+        tracer.pixels[index].lock().unwrap().write_to(&mut out)?;
+        tile.write_to(&mut out)?;
+        splats.write_to(&mut out)?;
+        counts.write_to(&mut out)?;
+        out.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mlt::{MetropolisConfig, MetropolisIntegrator};
+    use crate::tile::TileOrder;
+    use crate::trace::tests::{config, pixels, render, scene};
+    use crate::trace::AdaptiveSamplingConfig;
+
+    fn distributed_config() -> RendererConfig {
+        RendererConfig {
+            adaptive_sampling: Some(AdaptiveSamplingConfig {
+                target_error: 0.05,
+                max_samples_per_pixel: 16,
+            }),
+            passes: 2,
+            statistics: true,
+            tiles: TileConfig {
+                size: 4,
+                order: TileOrder::Spiral,
+            },
+            ..config(7, 2)
+        }
+    }
+
+    /// Renders `config` with the workers that `workers` starts for the address to connect to.
+    fn render_distributed<W>(config: &RendererConfig, workers: W) -> Renderer
+    where
+        W: FnOnce(String) -> Vec<thread::JoinHandle<Result<()>>>,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let workers = workers(listener.local_addr().unwrap().to_string());
+        let mut renderer = Renderer::new(config);
+        let (camera, objects) = scene();
+        renderer
+            .render_distributed(&camera, &objects, &listener, |_, _| true)
+            .unwrap();
+        for worker in workers {
+            worker.join().unwrap().unwrap();
+        }
+        renderer
+    }
+
+    #[test]
+    fn distributed_renders_match_local_ones() {
+        // Bidirectional path tracing splats, so the splats of the workers reach the image as well.
+        let renderer = render_distributed(&distributed_config(), |address| {
+            (0..2)
+                .map(|_| {
+                    let address = address.clone();
+                    thread::spawn(move || run_worker(address, Some(2)))
+                })
+                .collect()
+        });
+        let local = render(&distributed_config());
+        assert_eq!(pixels(&local), pixels(&renderer));
+
+        // The counts of the workers reach the coordinator.
+        let (stats, local_stats) = (renderer.statistics().unwrap(), local.statistics().unwrap());
+        assert_eq!(local_stats.primary_rays, stats.primary_rays);
+        assert_eq!(local_stats.shadow_rays, stats.shadow_rays);
+        assert_eq!(local_stats.intersection_tests, stats.intersection_tests);
+    }
+
+    #[test]
+    fn metropolis_renders_match_local_ones() {
+        // The Markov chains only run on the coordinator, the workers trace camera samples alone.
+        let config = RendererConfig {
+            integrator: Arc::new(MetropolisIntegrator::new(&MetropolisConfig {
+                bootstrap_samples: 1000,
+                chains: 8,
+                mutations_per_pixel: 4,
+                ..MetropolisConfig::default()
+            })),
+            ..distributed_config()
+        };
+        let renderer = render_distributed(&config, |address| {
+            vec![thread::spawn(move || run_worker(address, Some(2)))]
+        });
+        assert_eq!(pixels(&render(&config)), pixels(&renderer));
+    }
+
+    #[test]
+    fn tiles_of_workers_that_leave_are_traced_by_others() {
+        let renderer = render_distributed(&distributed_config(), |address| {
+            vec![thread::spawn(move || {
+                // A worker that takes a tile and disconnects before tracing it.
+                let stream = TcpStream::connect(&address)?;
+                let mut input = InputStream::from_reader(stream.try_clone()?);
+                let mut out = OutputStream::from_writer(stream);
+                out.write(WORKER_MAGIC)?;
+                1u32.write_to(&mut out)?;
+                out.flush()?;
+                read_scene(&mut input, 1)?;
+                assert_eq!(TILE_REQUEST, input.read_u8()?);
+                drop(input);
+                drop(out);
+
+                run_worker(address, Some(2))
+            })]
+        });
+        assert_eq!(pixels(&render(&distributed_config())), pixels(&renderer));
+    }
+}
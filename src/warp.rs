@@ -0,0 +1,219 @@
+//! Mappings of samples in the unit square to other domains, with the densities of the mapped points.
+//! Directions are returned in a local frame around the z axis; `to_world` rotates them around any axis.
+
+use std::f64::consts::PI;
+
+use crate::math::{Vector2, Vector3};
+
+/// Maps the unit square to the unit disk with Shirley and Chiu's concentric mapping, which keeps the
+/// relative areas and adjacency of strata.
+pub fn concentric_disk(u: Vector2) -> Vector2 {
+    let a = 2.0 * u.x - 1.0;
+    let b = 2.0 * u.y - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vector2::new(0.0, 0.0);
+    }
+
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    Vector2::new(r * phi.cos(), r * phi.sin())
+}
+
+/// Density with respect to area of the points of `concentric_disk`.
+pub fn concentric_disk_pdf() -> f64 {
+    1.0 / PI
+}
+
+/// Maps the unit square to directions distributed uniformly over the hemisphere around the z axis.
+pub fn uniform_hemisphere(u: Vector2) -> Vector3 {
+    let z = u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Density with respect to solid angle of the directions of `uniform_hemisphere`.
+pub fn uniform_hemisphere_pdf() -> f64 {
+    1.0 / (2.0 * PI)
+}
+
+/// Maps the unit square to directions around the z axis with density proportional to their cosine, by
+/// projecting the concentric disk up to the hemisphere.
+pub fn cosine_hemisphere(u: Vector2) -> Vector3 {
+    let d = concentric_disk(u);
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+    Vector3::new(d.x, d.y, z)
+}
+
+/// Density with respect to solid angle of a direction of `cosine_hemisphere` with the given cosine.
+pub fn cosine_hemisphere_pdf(cos_theta: f64) -> f64 {
+    cos_theta.max(0.0) / PI
+}
+
+/// Maps the unit square to directions around the z axis with density proportional to `cos(theta)^e`.
+pub fn cosine_power_hemisphere(u: Vector2, e: f64) -> Vector3 {
+    let cos_theta = (1.0 - u.y).powf(1.0 / (e + 1.0));
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.x;
+    Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Density with respect to solid angle of a direction of `cosine_power_hemisphere` with the given cosine.
+pub fn cosine_power_hemisphere_pdf(cos_theta: f64, e: f64) -> f64 {
+    if cos_theta <= 0.0 {
+        0.0
+    } else {
+        (e + 1.0) / (2.0 * PI) * cos_theta.powf(e)
+    }
+}
+
+/// Maps the unit square to points distributed uniformly over the surface of the unit sphere.
+pub fn uniform_sphere(u: Vector2) -> Vector3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Density with respect to solid angle of the directions of `uniform_sphere`.
+pub fn uniform_sphere_pdf() -> f64 {
+    1.0 / (4.0 * PI)
+}
+
+/// Maps the unit square to directions distributed uniformly in the cone around the z axis whose directions
+/// have a cosine of at least `cos_theta_max`.
+pub fn uniform_cone(u: Vector2, cos_theta_max: f64) -> Vector3 {
+    let cos_theta = 1.0 - u.x * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Density with respect to solid angle of the directions of `uniform_cone`. Cones without a solid angle,
+/// e.g. of very distant lights, have no density, so that their samples are skipped.
+pub fn uniform_cone_pdf(cos_theta_max: f64) -> f64 {
+    if cos_theta_max >= 1.0 {
+        return 0.0;
+    }
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+}
+
+/// Maps the unit square to barycentric coordinates of points distributed uniformly over a triangle.
+/// The third coordinate is one minus the sum of the two returned.
+pub fn uniform_triangle(u: Vector2) -> (f64, f64) {
+    let su = u.x.sqrt();
+    (1.0 - su, u.y * su)
+}
+
+/// Density with respect to area of the points of `uniform_triangle` on a triangle of the given area.
+pub fn uniform_triangle_pdf(area: f64) -> f64 {
+    1.0 / area
+}
+
+/// Rotates a direction in the local frame of the warps so that the z axis maps to the unit vector `w`.
+pub fn to_world(local: &Vector3, w: &Vector3) -> Vector3 {
+    let v = (w.cross(&Vector3::new(0.0072, 1.0, 0.0034))).normalized();
+    let u = v.cross(w);
+    (u * local.x + v * local.y + *w * local.z).normalized()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::UnitSquareSampler;
+
+    /// Estimates the integral of `f` over the points a warp makes of a stratified sample set.
+    fn integrate<T>(
+        warp: impl Fn(Vector2) -> T,
+        pdf: impl Fn(&T) -> f64,
+        f: impl Fn(&T) -> f64,
+    ) -> f64 {
+        let sampler = UnitSquareSampler::jittered_sampler(64);
+        let samples = &sampler.samples[0];
+        samples
+            .iter()
+            .map(|&u| {
+                let x = warp(u);
+                f(&x) / pdf(&x)
+            })
+            .sum::<f64>()
+            / samples.len() as f64
+    }
+
+    #[test]
+    fn warped_directions_integrate_cosine_squared() {
+        let cos_squared = |d: &Vector3| d.z * d.z;
+        let hemisphere = 2.0 * PI / 3.0;
+
+        let uniform = integrate(
+            uniform_hemisphere,
+            |_| uniform_hemisphere_pdf(),
+            cos_squared,
+        );
+        let cosine = integrate(
+            cosine_hemisphere,
+            |d| cosine_hemisphere_pdf(d.z),
+            cos_squared,
+        );
+        let power = integrate(
+            |u| cosine_power_hemisphere(u, 1.0),
+            |d| cosine_power_hemisphere_pdf(d.z, 1.0),
+            cos_squared,
+        );
+        let sphere = integrate(uniform_sphere, |_| uniform_sphere_pdf(), cos_squared);
+        let cone = integrate(
+            |u| uniform_cone(u, 0.5),
+            |_| uniform_cone_pdf(0.5),
+            cos_squared,
+        );
+
+        assert!((uniform - hemisphere).abs() < 0.01, "{}", uniform);
+        assert!((cosine - hemisphere).abs() < 0.01, "{}", cosine);
+        assert!((power - hemisphere).abs() < 0.01, "{}", power);
+        assert!((sphere - 2.0 * hemisphere).abs() < 0.01, "{}", sphere);
+        assert!((cone - 7.0 * PI / 12.0).abs() < 0.01, "{}", cone);
+    }
+
+    #[test]
+    fn cones_without_solid_angle_have_no_density() {
+        assert_eq!(0.0, uniform_cone_pdf(1.0));
+        let narrow = uniform_cone_pdf(1.0 - 1e-12);
+        assert!(narrow.is_finite() && narrow > 0.0);
+    }
+
+    #[test]
+    fn warps_cover_their_domains_uniformly() {
+        // Half of the disk lies within radius sqrt(1/2), and half of the triangle beyond the corner region
+        // of half its area.
+        let disk = integrate(
+            concentric_disk,
+            |_| concentric_disk_pdf(),
+            |p| {
+                let r_squared = p.x * p.x + p.y * p.y;
+                expect_lt!(r_squared, 1.0 + 1e-9);
+                if r_squared < 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            },
+        );
+        let triangle = integrate(
+            uniform_triangle,
+            |_| uniform_triangle_pdf(0.5),
+            |&(b0, b1)| {
+                expect_lt!(b0 + b1, 1.0 + 1e-9);
+                if b0 > 1.0 - 0.5f64.sqrt() {
+                    1.0
+                } else {
+                    0.0
+                }
+            },
+        );
+        assert!((disk - 0.5 * PI).abs() < 0.02, "{}", disk);
+        assert!((triangle - 0.25).abs() < 0.01, "{}", triangle);
+    }
+}